@@ -0,0 +1,656 @@
+// Shared by the rcomp and image-base64 binaries, which each use a different subset.
+#![allow(dead_code)]
+
+#[cfg(feature = "gzip")]
+use flate2::read::MultiGzDecoder;
+#[cfg(feature = "gzip")]
+use flate2::write::GzEncoder;
+#[cfg(feature = "gzip")]
+use flate2::Compression;
+#[cfg(feature = "lz4")]
+use lz4_flex::frame::{BlockMode, BlockSize, FrameDecoder, FrameEncoder, FrameInfo};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+#[cfg(feature = "xz")]
+use xz2::read::XzDecoder;
+#[cfg(feature = "xz")]
+use xz2::write::XzEncoder;
+#[cfg(feature = "gzip")]
+use zlib_rs::{Deflate, DeflateConfig, DeflateFlush, Method, Status, Strategy};
+
+#[cfg(not(any(
+    feature = "gzip",
+    feature = "zstd",
+    feature = "xz",
+    feature = "brotli",
+    feature = "lz4"
+)))]
+compile_error!("enable at least one compression algorithm feature: gzip, zstd, xz, brotli or lz4");
+
+/// Buffer size used by the brotli encoder and decoder.
+const BROTLI_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Window size (log2) used by the brotli encoder.
+const BROTLI_LGWIN: u32 = 22;
+
+/// Compression algorithms supported by the compressor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Gzip,
+    Zstd,
+    Xz,
+    Brotli,
+    /// Several times faster than gzip at both ends but with a noticeably
+    /// worse ratio; meant for scratch data and short-lived archives.
+    Lz4,
+}
+
+impl Algorithm {
+    /// Every algorithm the tools know about, whether compiled in or not.
+    pub const ALL: [Algorithm; 5] = [
+        Algorithm::Gzip,
+        Algorithm::Zstd,
+        Algorithm::Xz,
+        Algorithm::Brotli,
+        Algorithm::Lz4,
+    ];
+
+    /// Returns whether support for this algorithm was compiled into this
+    /// build. Each algorithm has a Cargo feature of the same name.
+    pub fn is_available(self) -> bool {
+        match self {
+            Algorithm::Gzip => cfg!(feature = "gzip"),
+            Algorithm::Zstd => cfg!(feature = "zstd"),
+            Algorithm::Xz => cfg!(feature = "xz"),
+            Algorithm::Brotli => cfg!(feature = "brotli"),
+            Algorithm::Lz4 => cfg!(feature = "lz4"),
+        }
+    }
+
+    /// Returns the algorithms compiled into this build.
+    pub fn available() -> impl Iterator<Item = Algorithm> {
+        Algorithm::ALL.into_iter().filter(|a| a.is_available())
+    }
+
+    /// Detects the algorithm from the magic bytes at the start of `data`.
+    ///
+    /// Brotli streams have no magic number and are never detected.
+    pub fn detect(data: &[u8]) -> Option<Algorithm> {
+        if data.starts_with(&[0x1f, 0x8b]) {
+            Some(Algorithm::Gzip)
+        } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Algorithm::Zstd)
+        } else if data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Algorithm::Xz)
+        } else if data.starts_with(&[0x04, 0x22, 0x4d, 0x18]) {
+            Some(Algorithm::Lz4)
+        } else {
+            None
+        }
+    }
+
+    /// Infers the algorithm from a file extension such as `.gz` or `.tar.zst`.
+    pub fn from_path(path: &Path) -> Option<Algorithm> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "gz" | "tgz" => Some(Algorithm::Gzip),
+            "zst" | "tzst" => Some(Algorithm::Zstd),
+            "xz" | "txz" => Some(Algorithm::Xz),
+            "br" => Some(Algorithm::Brotli),
+            "lz4" => Some(Algorithm::Lz4),
+            _ => None,
+        }
+    }
+
+    /// Returns the lowercase name of the algorithm as used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Gzip => "gzip",
+            Algorithm::Zstd => "zstd",
+            Algorithm::Xz => "xz",
+            Algorithm::Brotli => "brotli",
+            Algorithm::Lz4 => "lz4",
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Algorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name() == s.to_lowercase())
+            .ok_or_else(|| format!("Unsupported compression algorithm: {}", s))
+    }
+}
+
+/// A compression level given on the command line: either a number in the
+/// algorithm's native scale or a named preset resolved per algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Numeric(u32),
+    Fast,
+    Balanced,
+    Best,
+}
+
+impl Level {
+    /// Returns the native level of `algorithm` for this level.
+    pub fn resolve(self, algorithm: Algorithm) -> u32 {
+        match (self, algorithm) {
+            (Level::Numeric(level), _) => level,
+            (Level::Fast, Algorithm::Zstd) => 3,
+            (Level::Balanced, Algorithm::Zstd) => 12,
+            (Level::Best, Algorithm::Zstd) => 19,
+            (Level::Best, Algorithm::Brotli) => 11,
+            (Level::Fast, _) => 1,
+            (Level::Balanced, _) => 6,
+            (Level::Best, _) => 9,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Numeric(level) => write!(f, "{}", level),
+            Level::Fast => f.write_str("fast"),
+            Level::Balanced => f.write_str("balanced"),
+            Level::Best => f.write_str("best"),
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fast" => Ok(Level::Fast),
+            "balanced" => Ok(Level::Balanced),
+            "best" => Ok(Level::Best),
+            _ => match s.parse::<u32>() {
+                Ok(level @ 1..=9) => Ok(Level::Numeric(level)),
+                _ => Err(format!(
+                    "Invalid compression level '{}': expected 1-9, fast, balanced or best",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Match-finding strategy of the gzip (deflate) encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GzipStrategy {
+    /// Regular LZ77 matching followed by Huffman coding
+    #[default]
+    Default,
+    /// Prefer Huffman coding over short matches; suits data made of small
+    /// values with a somewhat random distribution, such as filtered images
+    Filtered,
+    /// No matching at all, only Huffman coding of single bytes; fastest, and
+    /// sometimes smaller for data without repeated strings
+    HuffmanOnly,
+    /// Only match runs of the previous byte; nearly as fast as huffman-only
+    /// but much better on long runs of identical bytes
+    Rle,
+}
+
+impl GzipStrategy {
+    /// Names accepted by `--gzip-strategy`.
+    pub const NAMES: [&'static str; 4] = ["default", "filtered", "huffman-only", "rle"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GzipStrategy::Default => "default",
+            GzipStrategy::Filtered => "filtered",
+            GzipStrategy::HuffmanOnly => "huffman-only",
+            GzipStrategy::Rle => "rle",
+        }
+    }
+}
+
+impl fmt::Display for GzipStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for GzipStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(GzipStrategy::Default),
+            "filtered" => Ok(GzipStrategy::Filtered),
+            "huffman-only" => Ok(GzipStrategy::HuffmanOnly),
+            "rle" => Ok(GzipStrategy::Rle),
+            _ => Err(format!(
+                "Invalid gzip strategy '{}': expected {}",
+                s,
+                GzipStrategy::NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl From<GzipStrategy> for Strategy {
+    fn from(strategy: GzipStrategy) -> Self {
+        match strategy {
+            GzipStrategy::Default => Strategy::Default,
+            GzipStrategy::Filtered => Strategy::Filtered,
+            GzipStrategy::HuffmanOnly => Strategy::HuffmanOnly,
+            GzipStrategy::Rle => Strategy::Rle,
+        }
+    }
+}
+
+/// Deflate parameters of gzip output besides the level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GzipTuning {
+    /// Memory used for the match-finding hash table, 1-9: each step doubles
+    /// it (256 KiB at the default 8); lower levels use less memory but find
+    /// fewer matches
+    pub mem_level: u8,
+    pub strategy: GzipStrategy,
+}
+
+impl GzipTuning {
+    /// zlib's default memory level.
+    pub const DEFAULT_MEM_LEVEL: u8 = 8;
+}
+
+impl Default for GzipTuning {
+    fn default() -> Self {
+        GzipTuning {
+            mem_level: Self::DEFAULT_MEM_LEVEL,
+            strategy: GzipStrategy::Default,
+        }
+    }
+}
+
+/// Output buffer size of [`TunedGzEncoder`].
+#[cfg(feature = "gzip")]
+const TUNED_GZIP_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Gzip writer with an explicit memory level and strategy, which flate2
+/// does not expose; built on zlib-rs, whose output any gzip reader accepts.
+#[cfg(feature = "gzip")]
+pub struct TunedGzEncoder<W: Write> {
+    deflate: Deflate,
+    writer: W,
+    buffer: Vec<u8>,
+}
+
+#[cfg(feature = "gzip")]
+impl<W: Write> TunedGzEncoder<W> {
+    pub fn new(writer: W, level: u32, tuning: GzipTuning) -> Self {
+        let config = DeflateConfig {
+            level: level.min(9) as i32,
+            method: Method::Deflated,
+            // 15-bit (32 KiB) window; adding 16 selects the gzip wrapper
+            window_bits: 15 + 16,
+            mem_level: i32::from(tuning.mem_level.clamp(1, 9)),
+            strategy: tuning.strategy.into(),
+        };
+        TunedGzEncoder {
+            deflate: Deflate::new_with_config(config),
+            writer,
+            buffer: vec![0; TUNED_GZIP_BUFFER_SIZE],
+        }
+    }
+
+    /// Runs the compressor once over `input`, writes whatever it produced
+    /// and returns the number of input bytes consumed, the number of bytes
+    /// produced and the compressor status.
+    fn step(&mut self, input: &[u8], flush: DeflateFlush) -> io::Result<(usize, usize, Status)> {
+        let (before_in, before_out) = (self.deflate.total_in(), self.deflate.total_out());
+        let status = self
+            .deflate
+            .compress(input, &mut self.buffer, flush)
+            .map_err(|e| io::Error::other(e.as_str()))?;
+        let consumed = (self.deflate.total_in() - before_in) as usize;
+        let produced = (self.deflate.total_out() - before_out) as usize;
+        self.writer.write_all(&self.buffer[..produced])?;
+        Ok((consumed, produced, status))
+    }
+
+    /// Finishes the gzip stream, writing its trailer, and returns the
+    /// underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        while self.step(&[], DeflateFlush::Finish)?.2 != Status::StreamEnd {}
+        Ok(self.writer)
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl<W: Write> Write for TunedGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut input = buf;
+        while !input.is_empty() {
+            let (consumed, _, _) = self.step(input, DeflateFlush::NoFlush)?;
+            input = &input[consumed..];
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // A full buffer means more output may be pending
+        while self.step(&[], DeflateFlush::SyncFlush)?.1 == self.buffer.len() {}
+        self.writer.flush()
+    }
+}
+
+/// A compressing writer for one of the supported algorithms.
+///
+/// Unlike a boxed `Write`, this can be explicitly finished so trailers are
+/// written and errors are reported instead of being swallowed on drop.
+pub enum Encoder<W: Write> {
+    #[cfg(feature = "gzip")]
+    Gzip(GzEncoder<W>),
+    #[cfg(feature = "gzip")]
+    TunedGzip(Box<TunedGzEncoder<W>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, W>),
+    #[cfg(feature = "xz")]
+    Xz(XzEncoder<W>),
+    #[cfg(feature = "brotli")]
+    Brotli(Box<brotli::CompressorWriter<W>>),
+    #[cfg(feature = "lz4")]
+    Lz4(FrameEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Finishes the compressed stream and returns the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(e) => e.finish(),
+            #[cfg(feature = "gzip")]
+            Encoder::TunedGzip(e) => e.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(e) => e.finish(),
+            #[cfg(feature = "xz")]
+            Encoder::Xz(e) => e.finish(),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(mut e) => {
+                // into_inner drops write errors, so surface them here first
+                e.flush()?;
+                Ok(e.into_inner())
+            }
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(e) => Ok(e.finish()?),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(e) => e.write(buf),
+            #[cfg(feature = "gzip")]
+            Encoder::TunedGzip(e) => e.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(e) => e.write(buf),
+            #[cfg(feature = "xz")]
+            Encoder::Xz(e) => e.write(buf),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(e) => e.write(buf),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(e) => e.flush(),
+            #[cfg(feature = "gzip")]
+            Encoder::TunedGzip(e) => e.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(e) => e.flush(),
+            #[cfg(feature = "xz")]
+            Encoder::Xz(e) => e.flush(),
+            #[cfg(feature = "brotli")]
+            Encoder::Brotli(e) => e.flush(),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(e) => e.flush(),
+        }
+    }
+}
+
+/// Error returned when an algorithm left out of this build is requested.
+fn not_compiled_in(algorithm: Algorithm) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} support is not compiled in; rebuild with --features {}",
+            algorithm,
+            algorithm.name()
+        ),
+    )
+}
+
+/// Wraps `writer` in an encoder for the given algorithm.
+///
+/// Fails for algorithms that were not compiled in.
+///
+/// # Arguments
+/// * `algorithm` - Compression algorithm to use
+/// * `writer` - Destination for the compressed bytes
+/// * `level` - Compression level in the algorithm's native scale
+pub fn make_encoder<W: Write>(
+    algorithm: Algorithm,
+    writer: W,
+    level: u32,
+) -> io::Result<Encoder<W>> {
+    Ok(match algorithm {
+        #[cfg(feature = "gzip")]
+        Algorithm::Gzip => Encoder::Gzip(GzEncoder::new(writer, Compression::new(level))),
+        #[cfg(feature = "zstd")]
+        Algorithm::Zstd => Encoder::Zstd(zstd::Encoder::new(writer, level as i32)?),
+        #[cfg(feature = "xz")]
+        Algorithm::Xz => Encoder::Xz(XzEncoder::new(writer, level)),
+        #[cfg(feature = "brotli")]
+        Algorithm::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+            writer,
+            BROTLI_BUFFER_SIZE,
+            level,
+            BROTLI_LGWIN,
+        ))),
+        #[cfg(feature = "lz4")]
+        Algorithm::Lz4 => {
+            Encoder::Lz4(FrameEncoder::with_frame_info(lz4_frame_info(level), writer))
+        }
+        #[allow(unreachable_patterns)]
+        unavailable => return Err(not_compiled_in(unavailable)),
+    })
+}
+
+/// Wraps `writer` in a gzip encoder, using flate2 unless `tuning` asks for
+/// a memory level or strategy other than the defaults.
+///
+/// # Arguments
+/// * `writer` - Destination for the compressed bytes
+/// * `level` - Compression level, 0-9
+/// * `tuning` - Memory level and strategy of the deflate encoder
+#[cfg(feature = "gzip")]
+pub fn make_gzip_encoder<W: Write>(
+    writer: W,
+    level: u32,
+    tuning: GzipTuning,
+) -> io::Result<Encoder<W>> {
+    Ok(if tuning == GzipTuning::default() {
+        Encoder::Gzip(GzEncoder::new(writer, Compression::new(level)))
+    } else {
+        Encoder::TunedGzip(Box::new(TunedGzEncoder::new(writer, level, tuning)))
+    })
+}
+
+#[cfg(not(feature = "gzip"))]
+pub fn make_gzip_encoder<W: Write>(
+    _writer: W,
+    _level: u32,
+    _tuning: GzipTuning,
+) -> io::Result<Encoder<W>> {
+    Err(not_compiled_in(Algorithm::Gzip))
+}
+
+/// Frame settings used for an lz4 compression level.
+///
+/// lz4_flex has a single compression speed and no acceleration factor, so
+/// the level picks the block layout instead: higher levels use larger blocks
+/// that may reference earlier ones, which finds more matches at a small cost
+/// in speed and memory.
+#[cfg(feature = "lz4")]
+fn lz4_frame_info(level: u32) -> FrameInfo {
+    let (block_size, block_mode) = match level {
+        0..=3 => (BlockSize::Max64KB, BlockMode::Independent),
+        4..=6 => (BlockSize::Max256KB, BlockMode::Linked),
+        _ => (BlockSize::Max4MB, BlockMode::Linked),
+    };
+    FrameInfo::new()
+        .block_size(block_size)
+        .block_mode(block_mode)
+        .content_checksum(true)
+}
+
+/// Wraps `reader` in a decoder for the given algorithm.
+///
+/// Fails for algorithms that were not compiled in.
+///
+/// # Arguments
+/// * `algorithm` - Compression algorithm the data was written with
+/// * `reader` - Source of the compressed bytes
+pub fn make_decoder<'a, R: Read + 'a>(
+    algorithm: Algorithm,
+    reader: R,
+) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match algorithm {
+        // Multi-member streams are read to the end, not just the first member
+        #[cfg(feature = "gzip")]
+        Algorithm::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        #[cfg(feature = "zstd")]
+        Algorithm::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        #[cfg(feature = "xz")]
+        Algorithm::Xz => Box::new(XzDecoder::new(reader)),
+        #[cfg(feature = "brotli")]
+        Algorithm::Brotli => Box::new(brotli::Decompressor::new(reader, BROTLI_BUFFER_SIZE)),
+        #[cfg(feature = "lz4")]
+        Algorithm::Lz4 => Box::new(FrameDecoder::new(reader)),
+        #[allow(unreachable_patterns)]
+        unavailable => return Err(not_compiled_in(unavailable)),
+    })
+}
+
+/// Compresses an in-memory buffer with the given algorithm and level.
+pub fn compress_bytes(algorithm: Algorithm, data: &[u8], level: u32) -> io::Result<Vec<u8>> {
+    let mut encoder = make_encoder(algorithm, Vec::new(), level)?;
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Decompresses an in-memory buffer that was compressed with the given algorithm.
+pub fn decompress_bytes(algorithm: Algorithm, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    make_decoder(algorithm, data)?.read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+/// Wraps `writer` in a zstd encoder primed with a trained dictionary.
+///
+/// The dictionary ID is recorded in the frame header, so a decoder can tell
+/// which dictionary the data needs.
+///
+/// # Arguments
+/// * `writer` - Destination for the compressed bytes
+/// * `level` - Zstd compression level
+/// * `dictionary` - Dictionary produced by [`train_dict`]
+#[cfg(feature = "zstd")]
+pub fn make_dict_encoder<W: Write>(
+    writer: W,
+    level: u32,
+    dictionary: &[u8],
+) -> io::Result<Encoder<W>> {
+    Ok(Encoder::Zstd(zstd::Encoder::with_dictionary(
+        writer,
+        level as i32,
+        dictionary,
+    )?))
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn make_dict_encoder<W: Write>(
+    _writer: W,
+    _level: u32,
+    _dictionary: &[u8],
+) -> io::Result<Encoder<W>> {
+    Err(not_compiled_in(Algorithm::Zstd))
+}
+
+/// Wraps `reader` in a zstd decoder primed with a trained dictionary.
+#[cfg(feature = "zstd")]
+pub fn make_dict_decoder<'a, R: Read + 'a>(
+    reader: R,
+    dictionary: &[u8],
+) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(Box::new(zstd::Decoder::with_dictionary(
+        io::BufReader::new(reader),
+        dictionary,
+    )?))
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn make_dict_decoder<'a, R: Read + 'a>(
+    _reader: R,
+    _dictionary: &[u8],
+) -> io::Result<Box<dyn Read + 'a>> {
+    Err(not_compiled_in(Algorithm::Zstd))
+}
+
+/// Trains a zstd dictionary of at most `max_size` bytes from sample data.
+#[cfg(feature = "zstd")]
+pub fn train_dict(samples: &[Vec<u8>], max_size: usize) -> io::Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size)
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn train_dict(_samples: &[Vec<u8>], _max_size: usize) -> io::Result<Vec<u8>> {
+    Err(not_compiled_in(Algorithm::Zstd))
+}
+
+/// Returns the ID of a trained zstd dictionary, or `None` for a raw
+/// content dictionary that carries no ID.
+#[cfg(feature = "zstd")]
+pub fn dict_id(dictionary: &[u8]) -> Option<u32> {
+    zstd::zstd_safe::get_dict_id_from_dict(dictionary).map(|id| id.get())
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn dict_id(_dictionary: &[u8]) -> Option<u32> {
+    None
+}
+
+/// Returns the dictionary ID recorded in the header of a zstd frame, or
+/// `None` if the frame was compressed without one.
+#[cfg(feature = "zstd")]
+pub fn frame_dict_id(header: &[u8]) -> Option<u32> {
+    zstd::zstd_safe::get_dict_id_from_frame(header).map(|id| id.get())
+}
+
+#[cfg(not(feature = "zstd"))]
+pub fn frame_dict_id(_header: &[u8]) -> Option<u32> {
+    None
+}
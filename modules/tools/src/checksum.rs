@@ -0,0 +1,108 @@
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+/// Hash algorithms that can be used for checksums.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha1,
+    Blake3,
+    Crc32,
+}
+
+impl HashAlgorithm {
+    /// Names accepted on the command line, in the order they are listed in help output.
+    pub const NAMES: [&'static str; 4] = ["sha256", "sha1", "blake3", "crc32"];
+
+    /// Returns the lowercase name of the algorithm, as used in sidecar extensions.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Crc32 => "crc32",
+        }
+    }
+
+    /// Creates a fresh incremental hasher for this algorithm.
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+
+    /// Hashes everything readable from `reader` and returns the hex digest.
+    pub fn digest_reader<R: Read>(self, reader: &mut R) -> io::Result<String> {
+        let mut hasher = self.hasher();
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        Ok(hasher.finalize_hex())
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "sha1" => Ok(HashAlgorithm::Sha1),
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "crc32" => Ok(HashAlgorithm::Crc32),
+            other => Err(format!("Unsupported hash algorithm: {}", other)),
+        }
+    }
+}
+
+/// Incremental hasher dispatching to the selected algorithm's implementation.
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha1(sha1::Sha1),
+    Blake3(Box<blake3::Hasher>),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    /// Feeds more data into the hasher.
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+            Hasher::Crc32(h) => h.update(data),
+        }
+    }
+
+    /// Consumes the hasher and returns the lowercase hex digest.
+    pub fn finalize_hex(self) -> String {
+        match self {
+            Hasher::Sha256(h) => to_hex(&h.finalize()),
+            Hasher::Sha1(h) => to_hex(&h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+            Hasher::Crc32(h) => format!("{:08x}", h.finalize()),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -1,13 +1,27 @@
-use clap::{Arg, Command};
-use flate2::read::GzDecoder;
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, Command};
+use globset::{Glob, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use indicatif::{ProgressBar, ProgressStyle};
-use std::fs::{self, File};
-use std::io::{self, Write};
-use std::path::Path;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use walkdir::WalkDir;
 
+mod algorithm;
+mod checksum;
+mod units;
+
+use algorithm::{Algorithm, Encoder, GzipStrategy, GzipTuning, Level};
+use checksum::{HashAlgorithm, Hasher};
+use units::format_size;
+
 /// Main entry point for the compression tool.
 /// Handles command line argument parsing and dispatches to appropriate functions.
 fn main() {
@@ -15,28 +29,273 @@ fn main() {
         .version("1.0")
         .author("Your Name <youremail@example.com>")
         .about("File compression and decompression tool")
+        .arg(
+            Arg::new("bytes")
+                .long("bytes")
+                .help("Print sizes as plain byte counts instead of KiB/MiB/GiB")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
         .subcommand(
             Command::new("compress")
                 .about("Compress a file or directory")
                 .arg(
                     Arg::new("INPUT")
-                        .help("Input file/directory to compress")
+                        .help("Input file/directory to compress, or '-' to read stdin; directories are always archived as tar")
                         .required(true)
                         .index(1),
                 )
                 .arg(
                     Arg::new("OUTPUT")
-                        .help("Output compressed file")
+                        .help("Output compressed file, or the target directory with --name-by-hash")
                         .required(true)
                         .index(2),
                 )
+                .arg(
+                    Arg::new("always-tar")
+                        .long("always-tar")
+                        .help("Archive a single file as tar too, so extraction restores its file name")
+                        .conflicts_with("single-file")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("single-file")
+                        .long("single-file")
+                        .help("Fail unless the input is compressed as a plain file rather than a tar archive")
+                        .conflicts_with("stdin-name")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("exact-output")
+                        .long("exact-output")
+                        .help("Write to OUTPUT as given instead of appending a missing .tar.gz, .gz, ... extension")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("name-by-hash")
+                        .long("name-by-hash")
+                        .help("Name the output <sha256 of compressed bytes>.<ext> inside the OUTPUT directory")
+                        .action(ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("level")
                         .short('l')
                         .long("level")
-                        .help("Compression level (1-9)")
-                        .value_parser(1..=9)
+                        .help("Compression level (1-9, or fast, balanced or best)")
+                        .value_parser(|s: &str| s.parse::<Level>())
                         .default_value("6"),
+                )
+                .arg(
+                    Arg::new("algo")
+                        .short('a')
+                        .long("algo")
+                        .help("Compression algorithm; lz4 is several times faster than gzip but compresses less")
+                        .value_parser(Algorithm::available().map(Algorithm::name).collect::<Vec<_>>())
+                        .conflicts_with("dict")
+                        // gzip when compiled in, as it comes first
+                        .default_value(Algorithm::available().next().map(Algorithm::name)),
+                )
+                .arg(
+                    Arg::new("dict")
+                        .long("dict")
+                        .value_name("PATH")
+                        .help("Compress with zstd using this dictionary (see train-dict)"),
+                )
+                .arg(
+                    Arg::new("gzip-mem-level")
+                        .long("gzip-mem-level")
+                        .value_name("N")
+                        .help("Gzip match-finding memory (1-9, default 8); each step doubles it, lower saves memory at some cost in ratio")
+                        .value_parser(clap::value_parser!(u8).range(1..=9)),
+                )
+                .arg(
+                    Arg::new("gzip-strategy")
+                        .long("gzip-strategy")
+                        .help("Gzip match-finding strategy: filtered favors Huffman coding over short matches, huffman-only skips matching (fastest), rle only matches runs of repeated bytes")
+                        .value_parser(GzipStrategy::NAMES),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .value_name("PATTERN")
+                        .help("Skip directory entries matching this gitignore-style pattern (repeatable)")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("ignore-file")
+                        .long("ignore-file")
+                        .value_name("PATH")
+                        .help("Read exclude patterns from this file instead of <INPUT>/.rcompignore"),
+                )
+                .arg(
+                    Arg::new("verbose")
+                        .short('v')
+                        .long("verbose")
+                        .help("Report the effective exclude patterns, sampled entropy and gzip parameters")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("keep-tar")
+                        .long("keep-tar")
+                        .value_name("PATH")
+                        .help("Also write the uncompressed tar when compressing a directory"),
+                )
+                .arg(
+                    Arg::new("self-extracting")
+                        .long("self-extracting")
+                        .help("Prefix the gzip output with a shell script that extracts it when run (Unix only); OUTPUT is used as given, e.g. archive.sh")
+                        .long_help(
+                            "Prefix the gzip output with a POSIX shell script, so that running \
+                             'sh OUTPUT [DIRECTORY]' extracts it into DIRECTORY (default: the \
+                             current directory) without rcomp. The script skips its own bytes with \
+                             tail and pipes the rest through gzip, and through tar for archives, \
+                             so it needs a Unix-like system with those tools; it does not run on \
+                             plain Windows. OUTPUT is used as given and made executable. rcomp \
+                             itself cannot decompress the result, because of the script in front.",
+                        )
+                        .conflicts_with_all(["name-by-hash", "dict"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("tee")
+                        .long("tee")
+                        .value_name("PATH")
+                        .help("Also write the compressed stream to PATH as it is produced (repeatable); all outputs are removed if any write fails")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .conflicts_with("name-by-hash")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("base-dir")
+                        .long("base-dir")
+                        .value_name("PATH")
+                        .help("Store directory entries relative to this ancestor of INPUT instead of INPUT itself"),
+                )
+                .arg(
+                    Arg::new("prefix")
+                        .long("prefix")
+                        .value_name("DIR")
+                        .help("Prepend this relative directory to every tar entry name (the inverse of --strip-components)")
+                        .value_parser(parse_entry_prefix),
+                )
+                .arg(
+                    Arg::new("block-size")
+                        .long("block-size")
+                        .value_name("N")
+                        .help("Pad the tar stream to a multiple of N 512-byte records (the tar blocking factor)")
+                        .value_parser(clap::value_parser!(u64).range(1..=u64::from(u32::MAX))),
+                )
+                .arg(
+                    Arg::new("stdin-name")
+                        .long("stdin-name")
+                        .value_name("NAME")
+                        .help("Store stdin as a tar entry with this relative path when OUTPUT is a tar archive"),
+                )
+                .arg(
+                    Arg::new("tar-format")
+                        .long("tar-format")
+                        .help("Tar header format used for directory input")
+                        .value_parser(["gnu", "pax", "ustar"])
+                        .default_value("gnu"),
+                )
+                .arg(
+                    Arg::new("skip-compressed")
+                        .long("skip-compressed")
+                        .visible_alias("skip-already-compressed")
+                        .help("Store already-compressed files in gzip archives without recompressing them")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("no-skip-compressed")
+                        .long("no-skip-compressed")
+                        .help("Compress every file, overriding skip-compressed from --profile")
+                        .overrides_with("skip-compressed")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("entropy-skip")
+                        .long("entropy-skip")
+                        .help("Sample the start of each file and store data too random to shrink without compressing it")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("no-entropy-skip")
+                        .long("no-entropy-skip")
+                        .help("Do not sample file entropy, overriding entropy-skip from --profile")
+                        .overrides_with("entropy-skip")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("rate-limit")
+                        .long("rate-limit")
+                        .value_name("BYTES_PER_SEC")
+                        .help("Throttle reading the input to at most this many bytes per second")
+                        .value_parser(clap::value_parser!(u64).range(1..)),
+                )
+                .arg(
+                    Arg::new("skip-special")
+                        .long("skip-special")
+                        .help("Skip FIFOs, sockets and device nodes with a warning (default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("no-skip-special")
+                        .long("no-skip-special")
+                        .help("Record FIFOs and device nodes as tar entries; sockets are still skipped")
+                        .overrides_with("skip-special")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("checksum")
+                        .long("checksum")
+                        .help("Write a checksum sidecar file next to the output")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("no-checksum")
+                        .long("no-checksum")
+                        .help("Write no checksum sidecar, overriding checksum from --profile")
+                        .overrides_with("checksum")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("hash")
+                        .long("hash")
+                        .help("Hash algorithm used for the checksum")
+                        .value_parser(HashAlgorithm::NAMES)
+                        .default_value("sha256"),
+                )
+                .arg(
+                    Arg::new("progress-json")
+                        .long("progress-json")
+                        .help("Write progress as JSON lines ({\"done\":..,\"total\":..,\"file\":..}) to stderr")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("progress-fd")
+                        .long("progress-fd")
+                        .value_name("FD")
+                        .help("Write the JSON progress lines to this inherited file descriptor instead (Unix)")
+                        .value_parser(clap::value_parser!(i32).range(1..)),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("TIME")
+                        .help("Only archive files modified after TIME (RFC 3339 or @epoch-seconds) and write OUTPUT.manifest.json")
+                        .value_parser(parse_since),
+                )
+                .arg(
+                    Arg::new("with-index")
+                        .long("with-index")
+                        .help("Write the names, sizes and offsets of archive entries to OUTPUT.idx so 'list --use-index' need not scan the archive")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .long("profile")
+                        .value_name("NAME")
+                        .help("Apply the [profiles.NAME] settings from ./rcomp.toml; flags given on the command line take precedence"),
                 ),
         )
         .subcommand(
@@ -53,149 +312,3975 @@ fn main() {
                         .help("Output directory")
                         .required(true)
                         .index(2),
+                )
+                .arg(
+                    Arg::new("only")
+                        .long("only")
+                        .help("Extract only entries matching this glob (repeatable)")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("strip-components")
+                        .long("strip-components")
+                        .value_name("N")
+                        .help("Remove the first N path components from each tar entry")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("min-size")
+                        .long("min-size")
+                        .value_name("BYTES")
+                        .help("Extract only regular files of at least BYTES bytes")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("max-size")
+                        .long("max-size")
+                        .value_name("BYTES")
+                        .help("Extract only regular files of at most BYTES bytes")
+                        .value_parser(clap::value_parser!(u64)),
+                )
+                .arg(
+                    Arg::new("newer-than")
+                        .long("newer-than")
+                        .value_name("TIME")
+                        .help("Extract only regular files modified after TIME (RFC 3339 or @epoch-seconds)")
+                        .value_parser(parse_since),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .short('j')
+                        .long("jobs")
+                        .value_name("N")
+                        .help("Write extracted files on N threads; directories are still created in archive order")
+                        .value_parser(clap::value_parser!(u64).range(1..)),
+                )
+                .arg(
+                    Arg::new("salvage")
+                        .long("salvage")
+                        .help("Extract what precedes corrupt or truncated data and list the recovered entries instead of failing")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("List each entry's target path, mode and owner, flagging unsafe paths, without writing anything")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("dict")
+                        .long("dict")
+                        .value_name("PATH")
+                        .help("Dictionary the zstd input was compressed with"),
+                ),
+        )
+        .subcommand(
+            Command::new("compare")
+                .about("Compare all available compression algorithms on an input")
+                .arg(
+                    Arg::new("INPUT")
+                        .help("Input file/directory to compress")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("level")
+                        .short('l')
+                        .long("level")
+                        .help("Compression level used for every algorithm (1-9, or fast, balanced or best)")
+                        .value_parser(|s: &str| s.parse::<Level>())
+                        .default_value("6"),
+                )
+                .arg(
+                    Arg::new("winner")
+                        .long("winner")
+                        .help("Recommend the algorithm with the best ratio per second")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("recompress")
+                .about("Convert a compressed file to a different algorithm")
+                .arg(
+                    Arg::new("INPUT")
+                        .help("Compressed input file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("OUTPUT")
+                        .help("Output file")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::new("algo")
+                        .short('a')
+                        .long("algo")
+                        .help("Target compression algorithm")
+                        .value_parser(Algorithm::available().map(Algorithm::name).collect::<Vec<_>>())
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("level")
+                        .short('l')
+                        .long("level")
+                        .help("Compression level (1-9, or fast, balanced or best)")
+                        .value_parser(|s: &str| s.parse::<Level>())
+                        .default_value("6"),
+                ),
+        )
+        .subcommand(
+            Command::new("merge")
+                .about("Merge several tar archives into one compressed archive")
+                .arg(
+                    Arg::new("OUTPUT")
+                        .help("Output archive; its extension selects the algorithm")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("INPUT")
+                        .help("Compressed tar archives to merge, in order")
+                        .required(true)
+                        .num_args(1..)
+                        .index(2),
+                )
+                .arg(
+                    Arg::new("on-collision")
+                        .long("on-collision")
+                        .help("What to do when two archives contain the same path")
+                        .value_parser(["error", "skip", "rename"])
+                        .default_value("error"),
+                )
+                .arg(
+                    Arg::new("level")
+                        .short('l')
+                        .long("level")
+                        .help("Compression level (1-9, or fast, balanced or best)")
+                        .value_parser(|s: &str| s.parse::<Level>())
+                        .default_value("6"),
+                ),
+        )
+        .subcommand(
+            Command::new("stat")
+                .about("Report the algorithm, sizes and entry count of a compressed file")
+                .arg(
+                    Arg::new("INPUT")
+                        .help("Compressed file or archive")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            Command::new("list")
+                .about("List the entries of a compressed tar archive")
+                .arg(
+                    Arg::new("INPUT")
+                        .help("Compressed tar archive")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("use-index")
+                        .long("use-index")
+                        .help("Read the INPUT.idx index written by 'compress --with-index' instead of scanning the archive")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("train-dict")
+                .about("Train a zstd dictionary from sample files")
+                .arg(
+                    Arg::new("INPUT")
+                        .help("Sample files or directories of samples")
+                        .required(true)
+                        .num_args(1..)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("PATH")
+                        .help("Where to write the dictionary")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("max-size")
+                        .long("max-size")
+                        .value_name("BYTES")
+                        .help("Maximum dictionary size in bytes")
+                        .value_parser(clap::value_parser!(u64).range(256..))
+                        .default_value("112640"),
                 ),
         )
         .get_matches();
 
     println!("Starting rcomp utility...");
 
+    units::set_raw_bytes(matches.get_flag("bytes"));
+
     if let Some(matches) = matches.subcommand_matches("compress") {
         let input = matches.get_one::<String>("INPUT").unwrap();
         let output = matches.get_one::<String>("OUTPUT").unwrap();
-        let level = matches.get_one::<u32>("level").unwrap();
+        // Checked before anything is opened, so the number cannot refer to
+        // one of rcomp's own files yet
+        let progress_fd = match matches.get_one::<i32>("progress-fd") {
+            Some(&fd) => match inherited_fd(fd) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    eprintln!("Compression failed: --progress-fd {}: {}", fd, e);
+                    return;
+                }
+            },
+            None => None,
+        };
+        let profile = match matches.get_one::<String>("profile") {
+            Some(name) => match load_profile(Path::new(PROFILE_FILE_NAME), name) {
+                Ok(profile) => {
+                    println!("Using profile '{}' from {}", name, PROFILE_FILE_NAME);
+                    profile
+                }
+                Err(e) => {
+                    eprintln!("Compression failed: {}", e);
+                    return;
+                }
+            },
+            None => Profile::default(),
+        };
+        // Profile values only replace flags left at their defaults
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        // A profile boolean applies unless the flag or its --no- form is given
+        let switch = |on: &str, off: &str, profile: Option<bool>, default: bool| {
+            if from_cli(on) || from_cli(off) {
+                !matches.get_flag(off)
+            } else {
+                profile.unwrap_or(default)
+            }
+        };
+        let dict = matches.get_one::<String>("dict").map(PathBuf::from);
+        let algorithm: Algorithm = match (profile.algo, matches.get_one::<String>("algo")) {
+            (Some(algo), _) if !from_cli("algo") => algo,
+            (_, Some(algo)) => algo.parse().unwrap(),
+            (_, None) => {
+                eprintln!("Compression failed: no compression algorithm is compiled in");
+                return;
+            }
+        };
+        // A dictionary switches compression to zstd, so presets follow it
+        let level = match profile.level {
+            Some(level) if !from_cli("level") => level,
+            _ => *matches.get_one::<Level>("level").unwrap(),
+        }
+        .resolve(match dict {
+            Some(_) => Algorithm::Zstd,
+            None => algorithm,
+        });
+        let name_by_hash = matches.get_flag("name-by-hash");
+        let self_extracting = matches.get_flag("self-extracting");
+        // Directory and named stdin input become tar archives
+        let archived = Path::new(input).is_dir()
+            || (input == STDIN_INPUT && matches.contains_id("stdin-name"))
+            || matches.get_flag("always-tar");
+        let corrected = (!name_by_hash && !self_extracting && !matches.get_flag("exact-output"))
+            .then(|| {
+                let algorithm = match dict {
+                    Some(_) => Algorithm::Zstd,
+                    None => algorithm,
+                };
+                corrected_output(output, algorithm, archived)
+            })
+            .flatten();
+        if let Some(corrected) = &corrected {
+            println!(
+                "Output '{}' lacks a matching extension; writing '{}' instead (use --exact-output to keep the name)",
+                output, corrected
+            );
+        }
+        let output = corrected.as_ref().unwrap_or(output);
+        let hash: HashAlgorithm = match profile.hash {
+            Some(hash) if !from_cli("hash") => hash,
+            _ => matches.get_one::<String>("hash").unwrap().parse().unwrap(),
+        };
+        // Plain compressed output has nowhere to record an entry name
+        let stdin_name = matches
+            .get_one::<String>("stdin-name")
+            .map(PathBuf::from)
+            .filter(|_| {
+                let archived = input == STDIN_INPUT
+                    && (name_by_hash || self_extracting || is_tar_output(output));
+                if !archived {
+                    eprintln!("Warning: --stdin-name is ignored unless stdin is compressed into a tar archive");
+                }
+                archived
+            });
+        let mut options = CompressOptions {
+            algorithm,
+            level,
+            keep_tar: matches.get_one::<String>("keep-tar").map(PathBuf::from),
+            base_dir: matches.get_one::<String>("base-dir").map(PathBuf::from),
+            prefix: matches.get_one::<PathBuf>("prefix").cloned(),
+            block_size: matches
+                .get_one::<u64>("block-size")
+                .copied()
+                .or(profile.block_size),
+            progress: match progress_fd {
+                Some(file) => Some(ProgressTarget::Fd(Arc::new(file))),
+                None => matches
+                    .get_flag("progress-json")
+                    .then_some(ProgressTarget::Stderr),
+            },
+            stdin_name,
+            always_tar: matches.get_flag("always-tar"),
+            single_file: matches.get_flag("single-file"),
+            exclude: profile
+                .exclude
+                .iter()
+                .chain(matches.get_many::<String>("exclude").into_iter().flatten())
+                .cloned()
+                .collect(),
+            ignore_file: matches
+                .get_one::<String>("ignore-file")
+                .map(PathBuf::from)
+                .or(profile.ignore_file),
+            verbose: matches.get_flag("verbose"),
+            skip_compressed: switch(
+                "skip-compressed",
+                "no-skip-compressed",
+                profile.skip_compressed,
+                false,
+            ),
+            entropy_skip: switch(
+                "entropy-skip",
+                "no-entropy-skip",
+                profile.entropy_skip,
+                false,
+            ),
+            skip_special: switch(
+                "skip-special",
+                "no-skip-special",
+                profile.skip_special,
+                true,
+            ),
+            rate_limit: matches
+                .get_one::<u64>("rate-limit")
+                .copied()
+                .or(profile.rate_limit),
+            since: matches.get_one::<SystemTime>("since").copied(),
+            // A hash-named archive gets its name only once it is written
+            manifest: matches
+                .contains_id("since")
+                .then(|| PathBuf::from(format!("{}.manifest.json", output)))
+                .filter(|_| {
+                    if name_by_hash {
+                        eprintln!("Warning: no --since manifest is written with --name-by-hash");
+                    }
+                    !name_by_hash
+                }),
+            index: matches
+                .get_flag("with-index")
+                .then(|| PathBuf::from(format!("{}.idx", output)))
+                .filter(|_| {
+                    if name_by_hash {
+                        eprintln!("Warning: no --with-index index is written with --name-by-hash");
+                    }
+                    !name_by_hash
+                }),
+            checksum: switch("checksum", "no-checksum", profile.checksum, false).then_some(hash),
+            // Set below, once the input is known to suit a script
+            stub: None,
+            gzip: GzipTuning {
+                mem_level: matches
+                    .get_one::<u8>("gzip-mem-level")
+                    .copied()
+                    .unwrap_or(GzipTuning::DEFAULT_MEM_LEVEL),
+                strategy: matches
+                    .get_one::<String>("gzip-strategy")
+                    .map_or(GzipStrategy::Default, |s| s.parse().unwrap()),
+            },
+            tee: matches
+                .get_many::<PathBuf>("tee")
+                .map(|paths| paths.cloned().collect())
+                .unwrap_or_default(),
+            dict,
+            tar_format: match profile.tar_format {
+                Some(format) if !from_cli("tar-format") => format,
+                _ => matches
+                    .get_one::<String>("tar-format")
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+            },
+            opened: Arc::default(),
+        };
 
+        if matches.contains_id("gzip-mem-level") || matches.contains_id("gzip-strategy") {
+            if options.algorithm != Algorithm::Gzip || options.dict.is_some() {
+                eprintln!("Warning: --gzip-mem-level and --gzip-strategy only apply to gzip output; ignoring them");
+            } else if options.verbose {
+                println!(
+                    "Gzip parameters: level {}, memory level {}, strategy {}",
+                    level, options.gzip.mem_level, options.gzip.strategy
+                );
+            }
+        }
+        if self_extracting {
+            let problem = if options.algorithm != Algorithm::Gzip {
+                Some("--self-extracting needs gzip output")
+            } else if input == STDIN_INPUT && options.stdin_name.is_none() {
+                Some("--self-extracting cannot name a file extracted from plain stdin; use --stdin-name")
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                eprintln!("Compression failed: {}", problem);
+                return;
+            }
+            // The script names the extracted file, so the name must survive as is
+            let file_name = match Path::new(input).file_name().filter(|_| !archived) {
+                Some(name) => match name.to_str() {
+                    Some(name) => Some(name),
+                    None => {
+                        eprintln!(
+                            "Compression failed: --self-extracting needs a UTF-8 file name, not {}",
+                            name.to_string_lossy()
+                        );
+                        return;
+                    }
+                },
+                None => None,
+            };
+            options.stub = Some(self_extracting_stub(file_name));
+        }
+        let mut seen = HashSet::from([output_identity(Path::new(output))]);
+        if let Some(duplicate) = options
+            .tee
+            .iter()
+            .find(|path| !seen.insert(output_identity(path)))
+        {
+            eprintln!(
+                "Compression failed: {} is given as more than one output",
+                duplicate.display()
+            );
+            return;
+        }
         println!(
             "Compressing '{}' to '{}' with level {}",
             input, output, level
         );
-        if let Err(e) = compress_path(input, output, *level) {
-            eprintln!("Compression failed: {}", e);
+        let result = if name_by_hash {
+            compress_to_hash_name(input, output, &options)
+                .map(|(path, digest)| (path.to_string_lossy().into_owned(), digest))
+        } else {
+            compress_path(input, output, &options).map(|digest| (output.clone(), digest))
+        };
+        if !options.tee.is_empty() {
+            let outputs = std::iter::once(Path::new(output.as_str()))
+                .chain(options.tee.iter().map(PathBuf::as_path));
+            if result.is_err() {
+                // A copy missing some of the stream is worse than none; only
+                // files this run opened are removed, and only regular ones,
+                // never devices or pipes
+                let opened = std::mem::take(&mut *options.opened.lock().unwrap());
+                for path in &opened {
+                    let regular = fs::symlink_metadata(path).is_ok_and(|m| m.is_file());
+                    if regular && fs::remove_file(path).is_ok() {
+                        eprintln!("Removed partial output {}", path.display());
+                    }
+                }
+            } else {
+                println!(
+                    "Wrote {} copies of the compressed output:",
+                    options.tee.len() + 1
+                );
+                for path in outputs {
+                    match fs::metadata(path) {
+                        Ok(metadata) => {
+                            println!("  {}: {}", path.display(), format_size(metadata.len()))
+                        }
+                        Err(e) => {
+                            eprintln!("Warning: cannot read the size of {}: {}", path.display(), e)
+                        }
+                    }
+                }
+            }
+        }
+        if result.is_ok() && self_extracting {
+            let outputs = std::iter::once(Path::new(output.as_str()))
+                .chain(options.tee.iter().map(PathBuf::as_path));
+            for path in outputs {
+                if let Err(e) = make_executable(path) {
+                    eprintln!("Warning: cannot make {} executable: {}", path.display(), e);
+                }
+            }
+            println!(
+                "Self-extracting archive written; run 'sh {} [DIRECTORY]' to extract it",
+                output
+            );
+        }
+        match result {
+            Err(e) => eprintln!("Compression failed: {}", e),
+            Ok((output, Some(digest))) => match write_checksum_sidecar(&output, hash, &digest) {
+                Ok(sidecar) => println!("Checksum written to {}", sidecar.display()),
+                Err(e) => eprintln!("Failed to write checksum: {}", e),
+            },
+            Ok((_, None)) => {}
         }
     } else if let Some(matches) = matches.subcommand_matches("decompress") {
         let input = matches.get_one::<String>("INPUT").unwrap();
         let output = matches.get_one::<String>("OUTPUT").unwrap();
+        let options = ExtractOptions {
+            only: matches
+                .get_many::<String>("only")
+                .map(|vals| vals.cloned().collect())
+                .unwrap_or_default(),
+            strip_components: *matches.get_one::<usize>("strip-components").unwrap(),
+            min_size: matches.get_one::<u64>("min-size").copied(),
+            max_size: matches.get_one::<u64>("max-size").copied(),
+            newer_than: matches.get_one::<SystemTime>("newer-than").copied(),
+            dict: matches.get_one::<String>("dict").map(PathBuf::from),
+            dry_run: matches.get_flag("dry-run"),
+            jobs: matches.get_one::<u64>("jobs").map(|&jobs| jobs as usize),
+            salvage: matches.get_flag("salvage"),
+        };
 
         println!("Decompressing '{}' to '{}'", input, output);
-        if let Err(e) = decompress_file(input, output) {
+        if let Err(e) = decompress_file(input, output, &options) {
             eprintln!("Decompression failed: {}", e);
         }
+    } else if let Some(matches) = matches.subcommand_matches("compare") {
+        let input = matches.get_one::<String>("INPUT").unwrap();
+        let level = matches.get_one::<Level>("level").unwrap();
+
+        println!("Comparing algorithms on '{}' at level {}", input, level);
+        if let Err(e) = compare_algorithms(input, *level, matches.get_flag("winner")) {
+            eprintln!("Comparison failed: {}", e);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("recompress") {
+        let input = matches.get_one::<String>("INPUT").unwrap();
+        let output = matches.get_one::<String>("OUTPUT").unwrap();
+        let algorithm: Algorithm = matches.get_one::<String>("algo").unwrap().parse().unwrap();
+        let level = matches
+            .get_one::<Level>("level")
+            .unwrap()
+            .resolve(algorithm);
+
+        println!(
+            "Recompressing '{}' to '{}' with {} level {}",
+            input, output, algorithm, level
+        );
+        if let Err(e) = recompress_file(input, output, algorithm, level) {
+            eprintln!("Recompression failed: {}", e);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("merge") {
+        let output = matches.get_one::<String>("OUTPUT").unwrap();
+        let inputs: Vec<&String> = matches.get_many::<String>("INPUT").unwrap().collect();
+        let collision = match matches.get_one::<String>("on-collision").unwrap().as_str() {
+            "error" => MergeCollision::Error,
+            "skip" => MergeCollision::Skip,
+            "rename" => MergeCollision::Rename,
+            _ => unreachable!(),
+        };
+        let level = *matches.get_one::<Level>("level").unwrap();
+
+        println!("Merging {} archives into '{}'", inputs.len(), output);
+        if let Err(e) = merge_archives(&inputs, output, collision, level) {
+            eprintln!("Merge failed: {}", e);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("stat") {
+        let input = matches.get_one::<String>("INPUT").unwrap();
+
+        if let Err(e) = stat_file(input) {
+            eprintln!("Stat failed: {}", e);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("list") {
+        let input = matches.get_one::<String>("INPUT").unwrap();
+
+        if let Err(e) = list_archive(input, matches.get_flag("use-index")) {
+            eprintln!("List failed: {}", e);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("train-dict") {
+        let inputs: Vec<&String> = matches.get_many::<String>("INPUT").unwrap().collect();
+        let output = matches.get_one::<String>("output").unwrap();
+        let max_size = *matches.get_one::<u64>("max-size").unwrap() as usize;
+
+        println!(
+            "Training dictionary '{}' (max {})",
+            output,
+            format_size(max_size as u64)
+        );
+        if let Err(e) = train_dictionary(&inputs, output, max_size) {
+            eprintln!("Dictionary training failed: {}", e);
+        }
     }
 }
 
-/// Compresses a file or directory based on the input path.
-///
-/// # Arguments
-/// * `input` - Path to the input file or directory
-/// * `output` - Path where the compressed file will be saved
-/// * `level` - Compression level (1-9)
-fn compress_path(input: &str, output: &str, level: u32) -> io::Result<()> {
-    let input_path = Path::new(input);
-    println!("Analyzing input path: {}", input);
+/// Options controlling how a file or directory is compressed.
+#[derive(Debug, Clone)]
+struct CompressOptions {
+    /// Algorithm used when no zstd dictionary is given
+    algorithm: Algorithm,
+    /// Compression level in the algorithm's native scale
+    level: u32,
+    /// Where to also write the uncompressed tar of a directory input
+    keep_tar: Option<PathBuf>,
+    /// Directory that entry names are made relative to instead of the input
+    base_dir: Option<PathBuf>,
+    /// Directory prepended to every tar entry name
+    prefix: Option<PathBuf>,
+    /// Tar blocking factor: the stream is padded to a multiple of this many records
+    block_size: Option<u64>,
+    /// Where to write machine-readable progress lines
+    progress: Option<ProgressTarget>,
+    /// Tar entry name for stdin input; plain compression when unset
+    stdin_name: Option<PathBuf>,
+    /// Archive single-file input as tar too; directories always are
+    always_tar: bool,
+    /// Reject input that would be archived as tar
+    single_file: bool,
+    /// Gitignore-style patterns excluded from directory input
+    exclude: Vec<String>,
+    /// Pattern file used instead of `<input>/.rcompignore`
+    ignore_file: Option<PathBuf>,
+    /// Report the effective exclude patterns
+    verbose: bool,
+    /// Store already-compressed files without recompressing them
+    skip_compressed: bool,
+    /// Sample file entropy and store files that look incompressible
+    entropy_skip: bool,
+    /// Skip FIFOs, sockets and device nodes instead of recording them
+    skip_special: bool,
+    /// Maximum input throughput in bytes per second
+    rate_limit: Option<u64>,
+    /// Only files modified after this time are archived
+    since: Option<SystemTime>,
+    /// Where to record the entries of an incremental archive
+    manifest: Option<PathBuf>,
+    /// Where to write the entry index of an archive
+    index: Option<PathBuf>,
+    /// Hash the output while it is written
+    checksum: Option<HashAlgorithm>,
+    /// Header format used for tar entries
+    tar_format: TarFormat,
+    /// Zstd dictionary used instead of plain gzip
+    dict: Option<PathBuf>,
+    /// Deflate memory level and strategy of gzip output
+    gzip: GzipTuning,
+    /// Extra files that receive a copy of the compressed output
+    tee: Vec<PathBuf>,
+    /// Shell script written ahead of the compressed stream
+    stub: Option<String>,
+    /// Output files opened so far, the ones to remove if compression fails
+    opened: Arc<Mutex<Vec<PathBuf>>>,
+}
 
-    if input_path.is_dir() {
-        println!("Input is a directory, using tar+gz compression");
-        compress_dir(input, output, level)
-    } else {
-        println!("Input is a file, using gz compression");
-        compress_file(input, output, level)
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions {
+            algorithm: Algorithm::Gzip,
+            level: 6,
+            keep_tar: None,
+            base_dir: None,
+            prefix: None,
+            block_size: None,
+            progress: None,
+            stdin_name: None,
+            always_tar: false,
+            single_file: false,
+            exclude: Vec::new(),
+            ignore_file: None,
+            verbose: false,
+            skip_compressed: false,
+            entropy_skip: false,
+            skip_special: true,
+            rate_limit: None,
+            since: None,
+            manifest: None,
+            index: None,
+            checksum: None,
+            tar_format: TarFormat::Gnu,
+            dict: None,
+            gzip: GzipTuning::default(),
+            tee: Vec::new(),
+            stub: None,
+            opened: Arc::default(),
+        }
+    }
+}
+
+impl CompressOptions {
+    /// Extension of the compressed output, without the `tar.` of archives.
+    fn extension(&self) -> &'static str {
+        compressed_extension(self.algorithm, self.dict.is_some())
     }
 }
 
-/// Compresses a single file using gzip compression.
+/// Extension of output compressed with `algorithm`, or with zstd when a
+/// dictionary is used, without the `tar.` of archives.
+fn compressed_extension(algorithm: Algorithm, dict: bool) -> &'static str {
+    match (dict, algorithm) {
+        (true, _) | (false, Algorithm::Zstd) => "zst",
+        (false, Algorithm::Gzip) => "gz",
+        (false, Algorithm::Xz) => "xz",
+        (false, Algorithm::Brotli) => "br",
+        (false, Algorithm::Lz4) => "lz4",
+    }
+}
+
+/// Suffixes that mark a tar archive compressed with `algorithm`; the first
+/// one is the preferred spelling.
+fn tar_suffixes(algorithm: Algorithm) -> &'static [&'static str] {
+    match algorithm {
+        Algorithm::Gzip => &[".tar.gz", ".tgz"],
+        Algorithm::Zstd => &[".tar.zst", ".tzst"],
+        Algorithm::Xz => &[".tar.xz", ".txz"],
+        Algorithm::Brotli => &[".tar.br"],
+        Algorithm::Lz4 => &[".tar.lz4"],
+    }
+}
+
+/// Appends the expected extension to a compress OUTPUT that has none of the
+/// suffixes decompress recognizes for its format, so the output can be
+/// detected again. Returns `None` when the name is already fine.
 ///
 /// # Arguments
-/// * `input` - Path to the input file
-/// * `output` - Path where the compressed file will be saved
-/// * `level` - Compression level (1-9)
-fn compress_file(input: &str, output: &str, level: u32) -> io::Result<()> {
-    println!("Opening input file: {}", input);
-    let input_file = File::open(input)?;
-    let input_size = input_file.metadata()?.len();
+/// * `output` - Output path given on the command line
+/// * `algorithm` - Algorithm of the output, zstd when a dictionary is used
+/// * `archived` - Whether the output is a tar archive
+fn corrected_output(output: &str, algorithm: Algorithm, archived: bool) -> Option<String> {
+    let single = format!(".{}", compressed_extension(algorithm, false));
+    let suffixes = match archived {
+        true => tar_suffixes(algorithm).to_vec(),
+        false => vec![single.as_str()],
+    };
+    let lower = output.to_lowercase();
+    if suffixes.iter().any(|suffix| lower.ends_with(suffix)) {
+        None
+    } else {
+        Some(format!("{}{}", output, suffixes[0]))
+    }
+}
 
-    let pb = ProgressBar::new(input_size);
-    // 修复模板错误处理
-    let style = ProgressStyle::default_bar()
-        .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap();
-    pb.set_style(style);
+/// Parses a `--since` time: an RFC 3339 timestamp such as
+/// `2024-05-01T12:00:00Z`, or `@` followed by seconds since the Unix epoch.
+fn parse_since(s: &str) -> Result<SystemTime, String> {
+    match s.strip_prefix('@') {
+        Some(secs) => secs
+            .parse::<u64>()
+            .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .map_err(|_| format!("invalid epoch seconds '{}'", secs)),
+        None => humantime::parse_rfc3339_weak(s)
+            .map_err(|e| format!("invalid timestamp '{}': {}", s, e)),
+    }
+}
 
-    println!("Creating output file: {}", output);
-    let output_file = File::create(output)?;
-    let mut encoder = GzEncoder::new(output_file, Compression::new(level));
+/// Name of the config file holding `--profile` tables, looked up in the
+/// current directory.
+const PROFILE_FILE_NAME: &str = "rcomp.toml";
 
-    println!("Starting compression process...");
-    let mut reader = io::BufReader::new(input_file);
-    io::copy(&mut reader, &mut encoder)?;
+/// Top level of `rcomp.toml`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    /// Kept as raw tables so only the selected profile has to be valid
+    #[serde(default)]
+    profiles: HashMap<String, toml::Table>,
+}
 
-    let output_size = fs::metadata(output)?.len();
-    pb.finish_with_message(format!(
-        "Compression complete! Original: {} bytes, Compressed: {} bytes, Ratio: {:.1}%",
-        input_size,
-        output_size,
-        (1.0 - output_size as f64 / input_size as f64) * 100.0
-    ));
+/// Compress settings from one `[profiles.<name>]` table. Every field is
+/// optional and named after its command-line flag; a flag given on the
+/// command line wins over the profile value.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Profile {
+    #[serde(deserialize_with = "deserialize_parsed")]
+    algo: Option<Algorithm>,
+    #[serde(deserialize_with = "deserialize_parsed")]
+    level: Option<Level>,
+    /// Added before any `--exclude` patterns, so those can still re-include
+    /// entries with `!pattern`
+    exclude: Vec<String>,
+    ignore_file: Option<PathBuf>,
+    #[serde(deserialize_with = "deserialize_parsed")]
+    tar_format: Option<TarFormat>,
+    block_size: Option<u64>,
+    skip_compressed: Option<bool>,
+    entropy_skip: Option<bool>,
+    skip_special: Option<bool>,
+    rate_limit: Option<u64>,
+    checksum: Option<bool>,
+    #[serde(deserialize_with = "deserialize_parsed")]
+    hash: Option<HashAlgorithm>,
+}
 
-    Ok(())
+/// Deserializes a profile value with its `FromStr` implementation, so the
+/// file accepts the same spellings as the command line. Numbers are parsed
+/// from their decimal form, which lets `level = 9` be written unquoted.
+fn deserialize_parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Number(u64),
+        Text(String),
+    }
+
+    let text = match Raw::deserialize(deserializer)? {
+        Raw::Number(n) => n.to_string(),
+        Raw::Text(s) => s,
+    };
+    text.parse().map(Some).map_err(serde::de::Error::custom)
 }
 
-/// Compresses a directory using tar+gzip compression.
+/// Loads the named compress profile from a TOML config file.
 ///
 /// # Arguments
-/// * `input` - Path to the input directory
-/// * `output` - Path where the compressed file will be saved
-/// * `level` - Compression level (1-9)
-fn compress_dir(input: &str, output: &str, level: u32) -> io::Result<()> {
-    println!("Creating tar archive from directory: {}", input);
-    let mut archive = tar::Builder::new(Vec::new());
+/// * `path` - Config file with `[profiles.<name>]` tables
+/// * `name` - Profile to load
+///
+/// # Returns
+/// The profile, or an error if the file is missing or invalid, the profile
+/// does not exist, or one of its values is out of range
+fn load_profile(path: &Path, name: &str) -> io::Result<Profile> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidInput, msg);
+    let text = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("cannot read profile file {}: {}", path.display(), e),
+        )
+    })?;
+    let mut file: ProfileFile = toml::from_str(&text)
+        .map_err(|e| invalid(format!("invalid profile file {}: {}", path.display(), e)))?;
+    let table = file.profiles.remove(name).ok_or_else(|| {
+        let mut names: Vec<_> = file.profiles.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        invalid(format!(
+            "no profile '{}' in {} (defined: {})",
+            name,
+            path.display(),
+            if names.is_empty() {
+                "none".to_string()
+            } else {
+                names.join(", ")
+            }
+        ))
+    })?;
 
-    for entry in WalkDir::new(input).into_iter().filter_map(|e| e.ok()) {
-        let path = entry.path();
-        if path.is_file() {
-            println!("Adding file to archive: {}", path.display());
-            archive.append_file(path.strip_prefix(input).unwrap(), &mut File::open(path)?)?;
+    let profile: Profile = toml::Value::Table(table)
+        .try_into()
+        .map_err(|e| invalid(format!("profile '{}': {}", name, e.to_string().trim_end())))?;
+
+    // Same restrictions as the corresponding flags
+    if let Some(algo) = profile.algo {
+        if !algo.is_available() {
+            return Err(invalid(format!(
+                "profile '{}': algo '{}' is not available for compress",
+                name, algo
+            )));
         }
     }
+    if profile.block_size == Some(0) || profile.block_size > Some(u64::from(u32::MAX)) {
+        return Err(invalid(format!(
+            "profile '{}': block-size must be between 1 and {}",
+            name,
+            u32::MAX
+        )));
+    }
+    if profile.rate_limit == Some(0) {
+        return Err(invalid(format!(
+            "profile '{}': rate-limit must be at least 1",
+            name
+        )));
+    }
+    Ok(profile)
+}
 
-    println!("Compressing tar archive...");
-    let tar_bytes = archive.into_inner()?;
-    let output_file = File::create(output)?;
-    let mut encoder = GzEncoder::new(output_file, Compression::new(level));
-    encoder.write_all(&tar_bytes)?;
+/// Progress bar style for byte transfers; shows raw counts with `--bytes`.
+fn byte_progress_style() -> ProgressStyle {
+    let counts = if units::raw_bytes() {
+        "{pos}/{len} bytes"
+    } else {
+        "{bytes}/{total_bytes}"
+    };
+    ProgressStyle::default_bar()
+        .template(&format!(
+            "[{{elapsed_precise}}] [{{bar:40.cyan/blue}}] {} ({{eta}})",
+            counts
+        ))
+        .unwrap()
+}
 
-    println!("Directory compression complete: {}", output);
-    Ok(())
+/// Where `--progress-json` lines are written.
+#[derive(Debug, Clone)]
+enum ProgressTarget {
+    Stderr,
+    /// Duplicate of a descriptor inherited from the parent process, such as
+    /// a pipe
+    Fd(Arc<File>),
+}
+
+/// Minimum time between two JSON progress lines.
+const PROGRESS_JSON_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Reports progress as JSON lines (`{"done":..,"total":..,"file":..}`) for a
+/// parent process, independent of the human progress bar. `done` and
+/// `total` count input bytes; `total` is `null` when the size is unknown,
+/// as for stdin.
+struct JsonProgress {
+    out: Box<dyn Write>,
+    done: u64,
+    total: Option<u64>,
+    last: Option<Instant>,
+}
+
+impl JsonProgress {
+    fn open(target: &ProgressTarget, total: Option<u64>) -> io::Result<Self> {
+        let out: Box<dyn Write> = match target {
+            ProgressTarget::Stderr => Box::new(io::stderr()),
+            ProgressTarget::Fd(file) => Box::new(file.try_clone()?),
+        };
+        Ok(JsonProgress {
+            out,
+            done: 0,
+            total,
+            last: None,
+        })
+    }
+
+    /// Records `n` more processed bytes and writes a line unless the last
+    /// one was written less than [`PROGRESS_JSON_INTERVAL`] ago.
+    fn advance(&mut self, n: u64, file: &str) -> io::Result<()> {
+        self.done += n;
+        if self
+            .last
+            .is_none_or(|last| last.elapsed() >= PROGRESS_JSON_INTERVAL)
+        {
+            self.emit(file)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a progress line now; used for the final line.
+    fn emit(&mut self, file: &str) -> io::Result<()> {
+        self.last = Some(Instant::now());
+        let line = serde_json::json!({
+            "done": self.done,
+            "total": self.total,
+            "file": file,
+        });
+        writeln!(self.out, "{}", line)?;
+        self.out.flush()
+    }
+}
+
+/// Reader that reports the bytes read through it as JSON progress.
+struct ProgressReader<R: Read> {
+    inner: R,
+    progress: JsonProgress,
+    file: String,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 {
+            self.progress.emit(&self.file)?;
+        } else {
+            self.progress.advance(n as u64, &self.file)?;
+        }
+        Ok(n)
+    }
+}
+
+/// Duplicates a descriptor inherited from the parent process for
+/// `--progress-fd`. Progress lines are written through the duplicate, and
+/// the original descriptor is never closed, as rcomp does not own it.
+/// Must run before rcomp opens any file, so that a number the parent did
+/// not pass fails here instead of naming one of rcomp's own files.
+#[cfg(unix)]
+fn inherited_fd(fd: i32) -> io::Result<File> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    // SAFETY: F_GETFD only reads the descriptor flags, failing with EBADF
+    // when the descriptor is not open
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: as above; F_DUPFD_CLOEXEC creates a new descriptor
+    let duplicate = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3) };
+    if duplicate == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the duplicate was just created and nothing else refers to it
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(duplicate) }))
+}
+
+#[cfg(not(unix))]
+fn inherited_fd(_fd: i32) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--progress-fd is only supported on Unix",
+    ))
+}
+
+/// INPUT argument that reads the data to compress from stdin.
+const STDIN_INPUT: &str = "-";
+
+/// Header format used when archiving a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TarFormat {
+    /// GNU headers with long-name extensions
+    Gnu,
+    /// POSIX ustar headers plus pax extended headers for long paths and large files
+    Pax,
+    /// Plain POSIX ustar; paths over 255 bytes and files over 8 GiB are rejected
+    Ustar,
+}
+
+impl FromStr for TarFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gnu" => Ok(TarFormat::Gnu),
+            "pax" => Ok(TarFormat::Pax),
+            "ustar" => Ok(TarFormat::Ustar),
+            _ => Err(format!(
+                "Invalid tar format '{}': expected gnu, pax or ustar",
+                s
+            )),
+        }
+    }
+}
+
+/// Largest file size representable in a ustar header (11 octal digits).
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+
+/// Size of one tar record; `--block-size` counts in these.
+const TAR_RECORD_SIZE: u64 = 512;
+
+/// Writer that counts the bytes written through it, so a tar stream can be
+/// padded to its blocking factor.
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+
+    /// Appends zeros until the byte count is a multiple of `records` tar
+    /// records, then returns the underlying writer.
+    fn pad_to_records(mut self, records: Option<u64>) -> io::Result<W> {
+        if let Some(records) = records {
+            let block = records * TAR_RECORD_SIZE;
+            let padding = (block - self.count % block) % block;
+            io::copy(&mut io::repeat(0).take(padding), &mut self)?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writer that duplicates every byte written into two underlying writers.
+struct TeeWriter<A: Write, B: Write> {
+    first: A,
+    second: B,
+}
+
+impl<A: Write, B: Write> TeeWriter<A, B> {
+    fn new(first: A, second: B) -> Self {
+        TeeWriter { first, second }
+    }
+
+    fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A: Write, B: Write> Write for TeeWriter<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.first.write_all(buf)?;
+        self.second.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
+
+/// Extra destinations that receive every byte of the compressed output.
+///
+/// A failed write names the destination it happened on.
+struct TeeTargets {
+    files: Vec<(PathBuf, File)>,
+}
+
+/// Resolves an output path for comparison with the others, so that `a`,
+/// `./a` and `dir/../a` are recognized as the same file. Only the parent
+/// directory is canonicalized, as the file itself may not exist yet.
+fn output_identity(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let parent = absolute
+        .parent()
+        .and_then(|parent| fs::canonicalize(parent).ok());
+    match (parent, absolute.file_name()) {
+        (Some(parent), Some(name)) => parent.join(name),
+        _ => absolute,
+    }
+}
+
+/// Adds the path of the tee destination a write failed on to `err`.
+fn tee_error(path: &Path, err: io::Error) -> io::Error {
+    io::Error::new(err.kind(), format!("tee {}: {}", path.display(), err))
+}
+
+impl Write for TeeTargets {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for (path, file) in &mut self.files {
+            file.write_all(buf).map_err(|e| tee_error(path, e))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for (path, file) in &mut self.files {
+            file.flush().map_err(|e| tee_error(path, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates the compressed output file and every `--tee` copy of it,
+/// recording each one opened in `options.opened`.
+///
+/// # Arguments
+/// * `output` - Path of the primary output file
+/// * `options` - Compression options
+fn create_output(
+    output: &str,
+    options: &CompressOptions,
+) -> io::Result<HashingWriter<TeeWriter<File, TeeTargets>>> {
+    let mut opened = options.opened.lock().unwrap();
+    let file = File::create(output)?;
+    opened.push(PathBuf::from(output));
+    let mut files = Vec::with_capacity(options.tee.len());
+    for path in &options.tee {
+        println!("Creating tee output file: {}", path.display());
+        let tee = File::create(path).map_err(|e| tee_error(path, e))?;
+        opened.push(path.clone());
+        files.push((path.clone(), tee));
+    }
+    drop(opened);
+    let targets = TeeTargets { files };
+    let mut writer = HashingWriter::new(TeeWriter::new(file, targets), options.checksum);
+    if let Some(stub) = &options.stub {
+        writer.write_all(stub.as_bytes())?;
+    }
+    Ok(writer)
+}
+
+/// Builds the shell script `--self-extracting` puts in front of gzip
+/// output. It skips its own bytes with `tail` and pipes the rest through
+/// `gzip -dc`, then through `tar` for archives, into the directory given as
+/// its first argument or the current one. It needs a POSIX shell with tail,
+/// gzip and tar, so it only runs on Unix-like systems.
+///
+/// # Arguments
+/// * `file_name` - Name of the extracted file for plain gzip output, or
+///   `None` for a tar archive
+fn self_extracting_stub(file_name: Option<&str>) -> String {
+    let extract = match file_name {
+        // Single quotes keep the name literal; embedded ones are spliced in
+        Some(name) => format!("gzip -dc > \"$dest\"/'{}'", name.replace('\'', "'\\''")),
+        None => "gzip -dc | tar -xf - -C \"$dest\"".to_string(),
+    };
+    let lines = vec![
+        "#!/bin/sh".to_string(),
+        "# Self-extracting archive written by rcomp; needs a Unix shell with tail, gzip and tar"
+            .to_string(),
+        "# Usage: sh THIS_FILE [DIRECTORY]  (extracts into DIRECTORY, default: here)".to_string(),
+        "set -e".to_string(),
+        "dest=\"${1:-.}\"".to_string(),
+        "mkdir -p \"$dest\"".to_string(),
+        "exit 0".to_string(),
+    ];
+    // The compressed stream starts right after the script. Counting bytes
+    // rather than lines keeps the offset right for names with newlines; the
+    // offset is part of the script, so repeat until its width settles.
+    let mut payload_offset = 1;
+    loop {
+        let mut script = lines.clone();
+        script.insert(
+            script.len() - 1,
+            format!("tail -c +{} \"$0\" | {}", payload_offset, extract),
+        );
+        let script = script.join("\n") + "\n";
+        if script.len() + 1 == payload_offset {
+            return script;
+        }
+        payload_offset = script.len() + 1;
+    }
+}
+
+/// Reader that counts the bytes read through it.
+struct CountingReader<R: Read> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> CountingReader<R> {
+    fn new(inner: R) -> Self {
+        CountingReader { inner, count: 0 }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Writer that feeds every byte written through it into a hasher, so the
+/// checksum of the output is known without reading the file back.
+struct HashingWriter<W: Write> {
+    inner: W,
+    /// `None` when no checksum was requested
+    hasher: Option<Hasher>,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W, algorithm: Option<HashAlgorithm>) -> Self {
+        HashingWriter {
+            inner,
+            hasher: algorithm.map(HashAlgorithm::hasher),
+        }
+    }
+
+    /// Flushes the underlying writer and returns the hex digest of
+    /// everything written, if a checksum was requested.
+    fn finish(mut self) -> io::Result<Option<String>> {
+        self.inner.flush()?;
+        Ok(self.hasher.map(Hasher::finalize_hex))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader or writer adapter that sleeps as needed to keep the average
+/// throughput at or below `bytes_per_sec`.
+///
+/// Transfers are capped at one second's worth of bytes, so bursts never
+/// exceed the limit by more than a single chunk.
+struct RateLimited<T> {
+    inner: T,
+    bytes_per_sec: u64,
+    transferred: u64,
+    started: Instant,
+}
+
+impl<T> RateLimited<T> {
+    fn new(inner: T, bytes_per_sec: u64) -> Self {
+        RateLimited {
+            inner,
+            bytes_per_sec,
+            transferred: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Largest transfer allowed in one call.
+    fn chunk_len(&self, requested: usize) -> usize {
+        requested.min(self.bytes_per_sec.try_into().unwrap_or(usize::MAX))
+    }
+
+    /// Records `n` transferred bytes and sleeps until the average rate is
+    /// back within the limit.
+    fn throttle(&mut self, n: usize) {
+        self.transferred += n as u64;
+        let due = Duration::from_secs_f64(self.transferred as f64 / self.bytes_per_sec as f64);
+        if let Some(wait) = due.checked_sub(self.started.elapsed()) {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Prints the average throughput achieved so far.
+    fn report(&self) {
+        let secs = self.started.elapsed().as_secs_f64();
+        if secs > 0.0 {
+            println!(
+                "Average input rate: {}/s (limit {}/s)",
+                format_size((self.transferred as f64 / secs) as u64),
+                format_size(self.bytes_per_sec)
+            );
+        }
+    }
+}
+
+impl<R: Read> Read for RateLimited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.chunk_len(buf.len());
+        let n = self.inner.read(&mut buf[..len])?;
+        self.throttle(n);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for RateLimited<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.chunk_len(buf.len());
+        let n = self.inner.write(&buf[..len])?;
+        self.throttle(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Compresses a file or directory based on the input path.
+///
+/// # Arguments
+/// * `input` - Path to the input file or directory
+/// * `output` - Path where the compressed file will be saved
+/// * `options` - Compression options
+///
+/// # Returns
+/// The digest of the compressed output when `options.checksum` is set
+fn compress_path(
+    input: &str,
+    output: &str,
+    options: &CompressOptions,
+) -> io::Result<Option<String>> {
+    let input_path = Path::new(input);
+    println!("Analyzing input path: {}", input);
+
+    let dict = match &options.dict {
+        Some(dict_path) => {
+            let dict = fs::read(dict_path)?;
+            match algorithm::dict_id(&dict) {
+                Some(id) => println!("Using dictionary {} (ID {})", dict_path.display(), id),
+                None => eprintln!(
+                    "Warning: {} has no dictionary ID; decompress cannot validate it",
+                    dict_path.display()
+                ),
+            }
+            Some(dict)
+        }
+        None => None,
+    };
+    let format = options.extension();
+
+    if input == STDIN_INPUT {
+        match &options.stdin_name {
+            Some(name) => {
+                println!("Input is stdin, using tar+{} compression", format);
+                return compress_stdin_tar(output, name, options, dict.as_deref());
+            }
+            None if is_tar_output(output) || options.always_tar => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "archiving stdin needs --stdin-name to name its tar entry",
+                ))
+            }
+            None => {}
+        }
+    }
+
+    if input_path.is_dir() && options.single_file {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "--single-file was given, but {} is a directory, which is always archived as tar",
+                input
+            ),
+        ));
+    }
+    if input_path.is_file() && options.always_tar {
+        println!("Archiving single file as tar+{} (--always-tar)", format);
+        compress_dir(input, output, options, dict.as_deref())
+    } else if input_path.is_dir() {
+        println!("Input is a directory, using tar+{} compression", format);
+        compress_dir(input, output, options, dict.as_deref())
+    } else {
+        if options.keep_tar.is_some() {
+            eprintln!("Warning: --keep-tar is ignored for single-file input");
+        }
+        if options.base_dir.is_some() {
+            eprintln!("Warning: --base-dir is ignored for single-file input");
+        }
+        if options.prefix.is_some() {
+            eprintln!("Warning: --prefix is ignored for single-file input");
+        }
+        if options.block_size.is_some() {
+            eprintln!("Warning: --block-size is ignored for single-file input");
+        }
+        if options.since.is_some() {
+            eprintln!("Warning: --since is ignored for single-file input");
+        }
+        if options.index.is_some() {
+            eprintln!("Warning: --with-index is ignored for single-file input");
+        }
+        let entropy = match input {
+            STDIN_INPUT => None,
+            _ => high_entropy(input_path, options)?,
+        };
+        if let Some(entropy) = entropy {
+            eprintln!(
+                "Warning: {} looks incompressible ({:.2} bits/byte); compressing to {} anyway",
+                input, entropy, format
+            );
+        }
+        println!("Input is a file, using {} compression", format);
+        compress_file(input, output, options, dict.as_deref())
+    }
+}
+
+/// Compresses `input` into `output_dir` under a content-addressed name: the
+/// SHA-256 of the compressed bytes plus the extension of the format used.
+/// The archive is written to a temporary file first and renamed once hashed,
+/// so a partially written file never carries a hash name.
+///
+/// # Arguments
+/// * `input` - Path to the input file or directory
+/// * `output_dir` - Directory where the compressed file will be stored
+/// * `options` - Compression options
+///
+/// # Returns
+/// The path of the stored file and, when `options.checksum` is set, its digest
+fn compress_to_hash_name(
+    input: &str,
+    output_dir: &str,
+    options: &CompressOptions,
+) -> io::Result<(PathBuf, Option<String>)> {
+    let output_dir = Path::new(output_dir);
+    fs::create_dir_all(output_dir)?;
+    let temp_path = output_dir.join(format!(".rcomp-{}.tmp", std::process::id()));
+    let temp_str = temp_path.to_string_lossy();
+
+    let digests = compress_path(input, &temp_str, options).and_then(|checksum| {
+        let name = match (options.checksum, &checksum) {
+            // The checksum already is the SHA-256 of the output
+            (Some(HashAlgorithm::Sha256), Some(digest)) => digest.clone(),
+            _ => HashAlgorithm::Sha256
+                .digest_reader(&mut io::BufReader::new(File::open(&temp_path)?))?,
+        };
+        Ok((name, checksum))
+    });
+    let (digest, checksum) = match digests {
+        Ok(digests) => digests,
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+    };
+
+    let archived = Path::new(input).is_dir() || options.stdin_name.is_some() || options.always_tar;
+    let final_path = match archived {
+        true => output_dir.join(format!("{}.tar.{}", digest, options.extension())),
+        false => output_dir.join(format!("{}.{}", digest, options.extension())),
+    };
+    if final_path.exists() {
+        // Same name means same bytes, so the stored copy is kept as is
+        fs::remove_file(&temp_path)?;
+        println!(
+            "Identical archive already stored at {}",
+            final_path.display()
+        );
+    } else {
+        fs::rename(&temp_path, &final_path)?;
+        println!("Stored as {}", final_path.display());
+    }
+    Ok((final_path, checksum))
+}
+
+/// Wraps `writer` in a zstd encoder when a dictionary is given, or an
+/// encoder for `options.algorithm` otherwise.
+///
+/// # Arguments
+/// * `writer` - Destination for the compressed bytes
+/// * `options` - Compression options
+/// * `dict` - Zstd dictionary contents
+fn create_encoder<W: Write>(
+    writer: W,
+    options: &CompressOptions,
+    dict: Option<&[u8]>,
+) -> io::Result<Encoder<W>> {
+    match (dict, options.algorithm) {
+        (Some(dict), _) => algorithm::make_dict_encoder(writer, options.level, dict),
+        (None, Algorithm::Gzip) => {
+            algorithm::make_gzip_encoder(writer, options.level, options.gzip)
+        }
+        (None, algorithm) => algorithm::make_encoder(algorithm, writer, options.level),
+    }
+}
+
+/// Compresses a single file using `options.algorithm`, or zstd when a
+/// dictionary is given.
+///
+/// # Arguments
+/// * `input` - Path to the input file, or `-` for stdin
+/// * `output` - Path where the compressed file will be saved
+/// * `options` - Compression options
+/// * `dict` - Zstd dictionary contents
+fn compress_file(
+    input: &str,
+    output: &str,
+    options: &CompressOptions,
+    dict: Option<&[u8]>,
+) -> io::Result<Option<String>> {
+    let (input_file, input_size): (Box<dyn Read>, u64) = if input == STDIN_INPUT {
+        println!("Reading input from stdin");
+        (Box::new(io::stdin().lock()), 0)
+    } else {
+        println!("Opening input file: {}", input);
+        let input_file = File::open(input)?;
+        let input_size = input_file.metadata()?.len();
+        (Box::new(input_file), input_size)
+    };
+    let input_file: Box<dyn Read> = match &options.progress {
+        Some(target) => Box::new(ProgressReader {
+            inner: input_file,
+            progress: JsonProgress::open(target, (input != STDIN_INPUT).then_some(input_size))?,
+            file: input.to_string(),
+        }),
+        None => input_file,
+    };
+
+    let pb = ProgressBar::new(input_size);
+    pb.set_style(byte_progress_style());
+
+    println!("Creating output file: {}", output);
+    let output_file = create_output(output, options)?;
+    let mut encoder = create_encoder(output_file, options, dict)?;
+
+    println!("Starting compression process...");
+    let mut reader = io::BufReader::new(input_file);
+    // The size of stdin is only known once it has been read
+    let input_size = match options.rate_limit {
+        Some(bytes_per_sec) => {
+            println!("Limiting input rate to {}/s", format_size(bytes_per_sec));
+            let mut limited = RateLimited::new(reader, bytes_per_sec);
+            let copied = io::copy(&mut limited, &mut encoder)?;
+            limited.report();
+            copied
+        }
+        None => io::copy(&mut reader, &mut encoder)?,
+    };
+    let digest = encoder.finish()?.finish()?;
+
+    let output_size = fs::metadata(output)?.len();
+    pb.finish();
+    println!(
+        "Compression complete! Original: {}, Compressed: {}, Ratio: {:.1}%",
+        format_size(input_size),
+        format_size(output_size),
+        (1.0 - output_size as f64 / input_size as f64) * 100.0
+    );
+
+    Ok(digest)
+}
+
+/// Compresses stdin into a tar archive holding a single entry named `name`.
+/// A tar header records the entry size up front, so the stream is read
+/// into memory before it is archived.
+///
+/// # Arguments
+/// * `output` - Path where the compressed file will be saved
+/// * `name` - Relative path of the tar entry
+/// * `options` - Compression options
+/// * `dict` - Zstd dictionary contents
+fn compress_stdin_tar(
+    output: &str,
+    name: &Path,
+    options: &CompressOptions,
+    dict: Option<&[u8]>,
+) -> io::Result<Option<String>> {
+    let unsafe_name = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "--stdin-name must be a non-empty relative path without '..': {}",
+                name.display()
+            ),
+        )
+    };
+    let entry_name = safe_entry_path(name, 0)
+        .map_err(|_| unsafe_name())?
+        .ok_or_else(unsafe_name)?;
+    if options.keep_tar.is_some() || options.base_dir.is_some() {
+        eprintln!("Warning: --keep-tar and --base-dir are ignored for stdin input");
+    }
+
+    let mut data = Vec::new();
+    io::stdin().lock().read_to_end(&mut data)?;
+    println!("Read {} from stdin", format_size(data.len() as u64));
+
+    let mut header = match options.tar_format {
+        TarFormat::Gnu => tar::Header::new_gnu(),
+        TarFormat::Pax | TarFormat::Ustar => tar::Header::new_ustar(),
+    };
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    );
+
+    let output_file = create_output(output, options)?;
+    let mut archive = tar::Builder::new(CountingWriter::new(create_encoder(
+        output_file,
+        options,
+        dict,
+    )?));
+    let entry_name = match &options.prefix {
+        Some(prefix) => prefix.join(entry_name),
+        None => entry_name,
+    };
+    println!("Adding stdin to archive as: {}", entry_name.display());
+    archive.append_data(&mut header, &entry_name, data.as_slice())?;
+    let digest = archive
+        .into_inner()?
+        .pad_to_records(options.block_size)?
+        .finish()?
+        .finish()?;
+    if let Some(index) = &options.index {
+        let entry = IndexEntry {
+            name: entry_name.to_string_lossy().into_owned(),
+            size: data.len() as u64,
+            offset: 0,
+        };
+        write_archive_index(index, output, vec![entry])?;
+    }
+
+    println!("Stdin compression complete: {}", output);
+    Ok(digest)
+}
+
+/// Returns true if `output` names a compressed tar archive such as
+/// `.tar.gz` or `.tzst`.
+fn is_tar_output(output: &str) -> bool {
+    let output = output.to_lowercase();
+    Algorithm::ALL
+        .into_iter()
+        .flat_map(tar_suffixes)
+        .any(|ext| output.ends_with(ext))
+}
+
+/// Compresses a directory into a tar archive compressed with
+/// `options.algorithm`, or with zstd when a dictionary is given.
+/// The tar stream is written straight into the encoder without being
+/// buffered in memory.
+///
+/// # Arguments
+/// * `input` - Path to the input directory
+/// * `output` - Path where the compressed file will be saved
+/// * `options` - Compression options
+/// * `dict` - Zstd dictionary contents
+fn compress_dir(
+    input: &str,
+    output: &str,
+    options: &CompressOptions,
+    dict: Option<&[u8]>,
+) -> io::Result<Option<String>> {
+    let output_file = create_output(output, options)?;
+    let mut index = options.index.as_ref().map(|_| Vec::new());
+    let output_file = match (dict, options.algorithm) {
+        (None, Algorithm::Gzip) => {
+            let encoder = GzipMembers::new(output_file, options.level, options.gzip)?;
+            write_archive(input, encoder, options, index.as_mut())?.finish()?
+        }
+        _ => {
+            if options.skip_compressed || options.entropy_skip {
+                eprintln!(
+                    "Warning: {} output cannot store entries; all files are compressed",
+                    options.extension()
+                );
+            }
+            let encoder = create_encoder(output_file, options, dict)?;
+            write_archive(input, encoder, options, index.as_mut())?.finish()?
+        }
+    };
+    let digest = output_file.finish()?;
+    // Written once the archive is complete so the index can record its size
+    if let (Some(path), Some(entries)) = (&options.index, index) {
+        write_archive_index(path, output, entries)?;
+    }
+
+    println!("Directory compression complete: {}", output);
+    Ok(digest)
+}
+
+/// Writes the tar archive of `input` into `encoder`, also saving the
+/// uncompressed tar when `options.keep_tar` is set.
+///
+/// # Arguments
+/// * `input` - Path to the input directory
+/// * `encoder` - Compressing writer for the output file
+/// * `options` - Compression options
+/// * `index` - Receives the name, size and offset of every entry written
+fn write_archive<E: Write + EntryStorage>(
+    input: &str,
+    encoder: E,
+    options: &CompressOptions,
+    index: Option<&mut Vec<IndexEntry>>,
+) -> io::Result<E> {
+    match &options.keep_tar {
+        Some(tar_path) => {
+            println!("Keeping uncompressed tar at: {}", tar_path.display());
+            let tee = TeeWriter::new(encoder, File::create(tar_path)?);
+            let (encoder, mut tar_file) =
+                write_tar_throttled(input, tee, options, index)?.into_inner();
+            tar_file.flush()?;
+            Ok(encoder)
+        }
+        None => write_tar_throttled(input, encoder, options, index),
+    }
+}
+
+/// Destination of a tar stream that can leave some entries uncompressed.
+trait EntryStorage {
+    /// Switches between storing the following bytes as is and compressing
+    /// them. Returns whether the bytes will be stored as is.
+    fn store_uncompressed(&mut self, stored: bool) -> io::Result<bool>;
+}
+
+/// Gzip writer that can finish the current member and start the next one
+/// at a different level. Concatenated members form one valid gzip stream,
+/// so already-compressed entries can be stored at level 0 while the rest of
+/// the archive is compressed.
+struct GzipMembers<W: Write> {
+    /// Encoder of the current member; only `None` while switching members
+    encoder: Option<Encoder<W>>,
+    level: u32,
+    tuning: GzipTuning,
+    stored: bool,
+}
+
+impl<W: Write> GzipMembers<W> {
+    fn new(writer: W, level: u32, tuning: GzipTuning) -> io::Result<Self> {
+        Ok(GzipMembers {
+            encoder: Some(algorithm::make_gzip_encoder(writer, level, tuning)?),
+            level,
+            tuning,
+            stored: false,
+        })
+    }
+
+    /// Finishes the last member and returns the underlying writer.
+    fn finish(mut self) -> io::Result<W> {
+        self.encoder.take().unwrap().finish()
+    }
+}
+
+impl<W: Write> EntryStorage for GzipMembers<W> {
+    fn store_uncompressed(&mut self, stored: bool) -> io::Result<bool> {
+        if stored != self.stored {
+            let writer = self.encoder.take().unwrap().finish()?;
+            let level = if stored { 0 } else { self.level };
+            self.encoder = Some(algorithm::make_gzip_encoder(writer, level, self.tuning)?);
+            self.stored = stored;
+        }
+        Ok(stored)
+    }
+}
+
+impl<W: Write> Write for GzipMembers<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.as_mut().unwrap().flush()
+    }
+}
+
+// The level of a zstd stream cannot change between entries.
+impl<W: Write> EntryStorage for Encoder<W> {
+    fn store_uncompressed(&mut self, _stored: bool) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+// An in-memory tar buffer is never compressed.
+impl EntryStorage for Vec<u8> {
+    fn store_uncompressed(&mut self, _stored: bool) -> io::Result<bool> {
+        Ok(true)
+    }
+}
+
+impl<A: Write + EntryStorage, B: Write> EntryStorage for TeeWriter<A, B> {
+    fn store_uncompressed(&mut self, stored: bool) -> io::Result<bool> {
+        self.first.store_uncompressed(stored)
+    }
+}
+
+impl<T: EntryStorage> EntryStorage for RateLimited<T> {
+    fn store_uncompressed(&mut self, stored: bool) -> io::Result<bool> {
+        self.inner.store_uncompressed(stored)
+    }
+}
+
+impl<W: Write + EntryStorage> EntryStorage for CountingWriter<W> {
+    fn store_uncompressed(&mut self, stored: bool) -> io::Result<bool> {
+        self.inner.store_uncompressed(stored)
+    }
+}
+
+/// Writes a tar archive like [`write_tar`], throttling the tar stream to
+/// `options.rate_limit` bytes per second when a limit is set.
+///
+/// # Arguments
+/// * `input` - Path to the input directory
+/// * `writer` - Destination for the tar stream
+/// * `options` - Compression options
+/// * `index` - Receives the name, size and offset of every entry written
+fn write_tar_throttled<W: Write + EntryStorage>(
+    input: &str,
+    writer: W,
+    options: &CompressOptions,
+    index: Option<&mut Vec<IndexEntry>>,
+) -> io::Result<W> {
+    match options.rate_limit {
+        Some(bytes_per_sec) => {
+            println!("Limiting archive rate to {}/s", format_size(bytes_per_sec));
+            let limited = write_tar(
+                input,
+                RateLimited::new(writer, bytes_per_sec),
+                options,
+                index,
+            )?;
+            limited.report();
+            Ok(limited.into_inner())
+        }
+        None => write_tar(input, writer, options, index),
+    }
+}
+
+/// Builds an in-memory tar archive of every file below a directory.
+///
+/// # Arguments
+/// * `input` - Path to the input directory
+fn build_tar(input: &str) -> io::Result<Vec<u8>> {
+    write_tar(input, Vec::new(), &CompressOptions::default(), None)
+}
+
+/// Writes a tar archive of every file below a directory into `writer`
+/// and returns the writer once the archive is complete.
+///
+/// # Arguments
+/// * `input` - Path to the input directory
+/// * `writer` - Destination for the tar stream
+/// * `options` - Compression options
+/// * `index` - Receives the name, size and offset of every entry written
+fn write_tar<W: Write + EntryStorage>(
+    input: &str,
+    writer: W,
+    options: &CompressOptions,
+    mut index: Option<&mut Vec<IndexEntry>>,
+) -> io::Result<W> {
+    println!("Creating tar archive from: {}", input);
+    let mut archive = tar::Builder::new(CountingWriter::new(writer));
+    // A single file (--always-tar) is stored under its own name
+    let root = match Path::new(input) {
+        path if path.is_file() => path.parent().unwrap_or(Path::new("")),
+        path => path,
+    };
+    let mut precompressed_files = 0u64;
+    let mut precompressed_bytes = 0u64;
+    let mut stored_files = 0u64;
+    let mut compressed_files = 0u64;
+    let mut high_entropy_files = 0u64;
+    let ignore = build_ignore_matcher(Path::new(input), options)?;
+    let name_prefix = match root.as_os_str().is_empty() {
+        true => entry_name_prefix(Path::new("."), options.base_dir.as_deref())?,
+        false => entry_name_prefix(root, options.base_dir.as_deref())?,
+    };
+    let name_prefix = match &options.prefix {
+        Some(prefix) => prefix.join(name_prefix),
+        None => name_prefix,
+    };
+
+    let walker = WalkDir::new(input).into_iter().filter_entry(|entry| {
+        // The root itself is never excluded; pruning a directory skips its contents
+        entry.depth() == 0
+            || !ignore
+                .matched(entry.path(), entry.file_type().is_dir())
+                .is_ignore()
+    });
+    let mut entries: Vec<_> = walker.filter_map(|e| e.ok()).collect();
+    let entry_name = |path: &Path| name_prefix.join(path.strip_prefix(root).unwrap());
+    if let Some(since) = options.since {
+        let files: Vec<PathBuf> = entries
+            .iter()
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| entry_name(entry.path()))
+            .collect();
+        // Files whose mtime cannot be read are kept, erring toward a full copy
+        entries.retain(|entry| {
+            !entry.path().is_file()
+                || entry
+                    .metadata()
+                    .ok()
+                    .and_then(|metadata| metadata.modified().ok())
+                    .is_none_or(|modified| modified > since)
+        });
+        let included: Vec<PathBuf> = entries
+            .iter()
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| entry_name(entry.path()))
+            .collect();
+        println!(
+            "{} of {} files modified since {}",
+            included.len(),
+            files.len(),
+            humantime::format_rfc3339_seconds(since)
+        );
+        if let Some(manifest) = &options.manifest {
+            write_incremental_manifest(manifest, since, &files, &included)?;
+        }
+    }
+    let total = entries
+        .iter()
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| fs::metadata(entry.path()).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let mut progress = match &options.progress {
+        Some(target) => Some(JsonProgress::open(target, Some(total))?),
+        None => None,
+    };
+    // Measured in bytes rather than files, so a few large files neither stall
+    // the bar nor skew the ETA, which follows the running byte rate
+    let pb = match &options.progress {
+        Some(ProgressTarget::Stderr) => ProgressBar::hidden(),
+        _ => ProgressBar::new(total),
+    };
+    pb.set_style(byte_progress_style());
+    // Log lines are printed with the bar cleared, so it is redrawn below them
+    let say = |line: String| pb.suspend(|| println!("{}", line));
+    let mut record = |name: &Path, size: u64, offset: u64| {
+        if let Some(index) = index.as_deref_mut() {
+            index.push(IndexEntry {
+                name: name.to_string_lossy().into_owned(),
+                size,
+                offset,
+            });
+        }
+    };
+    for entry in entries {
+        let path = entry.path();
+        // Where the entry's first header starts in the uncompressed stream
+        let offset = archive.get_ref().count;
+        if path.is_file() {
+            let size = entry.metadata()?.len();
+            let precompressed = options.skip_compressed && is_already_compressed(path)?;
+            let entropy = match precompressed {
+                true => None,
+                false => high_entropy(path, options)?,
+            };
+            if precompressed {
+                say(format!("Already compressed: {}", path.display()));
+                precompressed_files += 1;
+                precompressed_bytes += size;
+            } else if let Some(entropy) = entropy {
+                say(format!(
+                    "High entropy ({:.2} bits/byte), will not shrink: {}",
+                    entropy,
+                    path.display()
+                ));
+                high_entropy_files += 1;
+            }
+            say(format!("Adding file to archive: {}", path.display()));
+            let store = precompressed || entropy.is_some();
+            if archive.get_mut().store_uncompressed(store)? {
+                stored_files += 1;
+            } else {
+                compressed_files += 1;
+            }
+            append_tar_file(&mut archive, path, &entry_name(path), options.tar_format)?;
+            record(&entry_name(path), size, offset);
+            pb.inc(size);
+            if let Some(progress) = &mut progress {
+                progress.advance(size, &path.to_string_lossy())?;
+            }
+        } else if let Some(kind) = special_file_kind(entry.file_type()) {
+            let name = entry_name(path);
+            if options.skip_special || kind == "socket" {
+                pb.suspend(|| eprintln!("Warning: skipping {} {}", kind, path.display()));
+            } else {
+                say(format!("Adding {} to archive: {}", kind, path.display()));
+                archive.append_path_with_name(path, &name)?;
+                record(&name, 0, offset);
+            }
+        }
+    }
+    pb.finish_and_clear();
+
+    if options.skip_compressed {
+        println!(
+            "{} already-compressed files ({}) found",
+            precompressed_files,
+            format_size(precompressed_bytes)
+        );
+    }
+    if options.entropy_skip {
+        println!("{} high-entropy files found", high_entropy_files);
+    }
+    if options.skip_compressed || options.entropy_skip {
+        println!(
+            "{} entries stored, {} compressed",
+            stored_files, compressed_files
+        );
+    }
+    if let Some(progress) = &mut progress {
+        progress.emit(input)?;
+    }
+    archive.into_inner()?.pad_to_records(options.block_size)
+}
+
+/// Records what an incremental archive contains. `included` lists the
+/// entries in the archive; `files` lists every file present at backup time,
+/// so a restore that layers incrementals over a full archive can also
+/// remove files deleted since.
+///
+/// # Arguments
+/// * `path` - Where to write the JSON manifest
+/// * `since` - Cutoff time given with `--since`
+/// * `files` - Entry names of all files in the input
+/// * `included` - Entry names of the files modified after `since`
+fn write_incremental_manifest(
+    path: &Path,
+    since: SystemTime,
+    files: &[PathBuf],
+    included: &[PathBuf],
+) -> io::Result<()> {
+    let names = |paths: &[PathBuf]| -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect()
+    };
+    let manifest = serde_json::json!({
+        "since": humantime::format_rfc3339_seconds(since).to_string(),
+        "created": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        "included": names(included),
+        "files": names(files),
+    });
+    fs::write(path, serde_json::to_string_pretty(&manifest)? + "\n")?;
+    println!("Manifest written to {}", path.display());
+    Ok(())
+}
+
+/// Entry index written next to an archive by `compress --with-index`.
+#[derive(Debug, Serialize, Deserialize)]
+struct ArchiveIndex {
+    /// Size of the compressed archive the index describes; a different size
+    /// means the archive was replaced and the index is stale
+    archive_size: u64,
+    entries: Vec<IndexEntry>,
+}
+
+/// One archive entry as recorded in an [`ArchiveIndex`].
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    name: String,
+    /// Size of the entry data in bytes
+    size: u64,
+    /// Offset of the entry's first header in the uncompressed tar stream,
+    /// counting any long-name or PAX header in front of it
+    offset: u64,
+}
+
+/// Writes the entry index of a finished archive as compact JSON.
+///
+/// # Arguments
+/// * `path` - Where to write the index
+/// * `archive` - Path to the compressed archive
+/// * `entries` - Entries in the order they were written
+fn write_archive_index(path: &Path, archive: &str, entries: Vec<IndexEntry>) -> io::Result<()> {
+    let index = ArchiveIndex {
+        archive_size: fs::metadata(archive)?.len(),
+        entries,
+    };
+    fs::write(path, serde_json::to_string(&index)? + "\n")?;
+    println!(
+        "Index of {} entries written to {}",
+        index.entries.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Reads the `<input>.idx` index of an archive, failing if it is missing,
+/// malformed or does not match the archive's current size.
+///
+/// # Arguments
+/// * `input` - Path to the compressed archive
+fn read_archive_index(input: &str) -> io::Result<ArchiveIndex> {
+    let path = format!("{}.idx", input);
+    let index: ArchiveIndex = serde_json::from_slice(&fs::read(&path)?)?;
+    let archive_size = fs::metadata(input)?.len();
+    if index.archive_size != archive_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} describes a {}-byte archive, but {} is {} bytes",
+                path, index.archive_size, input, archive_size
+            ),
+        ));
+    }
+    Ok(index)
+}
+
+/// Lists the entries of a compressed tar archive with their sizes.
+///
+/// With `use_index`, entries come from the `<input>.idx` index written by
+/// `compress --with-index`, so the archive is not decompressed at all. A
+/// missing or stale index falls back to scanning the archive.
+///
+/// # Arguments
+/// * `input` - Path to the compressed archive
+/// * `use_index` - Read the index instead of the archive when possible
+fn list_archive(input: &str, use_index: bool) -> io::Result<()> {
+    let indexed = match use_index {
+        true => read_archive_index(input)
+            .inspect_err(|e| eprintln!("Warning: not using index ({}); scanning the archive", e))
+            .ok(),
+        false => None,
+    };
+    let entries = match indexed {
+        Some(index) => index.entries,
+        None => scan_archive_entries(input)?,
+    };
+
+    let mut total = 0u64;
+    for entry in &entries {
+        println!("{:>12}  {}", entry.size, entry.name);
+        total += entry.size;
+    }
+    println!("{} entries, {}", entries.len(), format_size(total));
+    Ok(())
+}
+
+/// Reads the names, sizes and offsets of every entry by decompressing the
+/// whole archive.
+///
+/// # Arguments
+/// * `input` - Path to the compressed archive
+fn scan_archive_entries(input: &str) -> io::Result<Vec<IndexEntry>> {
+    check_dictionary(input, None)?;
+    let algorithm = detect_input_algorithm(input)?;
+    let input_file = io::BufReader::new(File::open(input)?);
+    let mut decoder = algorithm::make_decoder(algorithm, input_file)?;
+    let mut header = Vec::with_capacity(512);
+    decoder.by_ref().take(512).read_to_end(&mut header)?;
+    if header.len() < 512 || header[257..262] != *b"ustar" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a tar archive", input),
+        ));
+    }
+
+    let mut archive = tar::Archive::new(io::Cursor::new(header).chain(decoder));
+    let mut entries = Vec::new();
+    // Each entry's headers start where the data of the one before ends
+    let mut offset = 0;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let size = entry.size();
+        entries.push(IndexEntry {
+            name: entry.path()?.to_string_lossy().into_owned(),
+            size,
+            offset,
+        });
+        offset =
+            entry.raw_header_position() + TAR_RECORD_SIZE * (1 + size.div_ceil(TAR_RECORD_SIZE));
+    }
+    Ok(entries)
+}
+
+/// Returns the path of `input` relative to `base_dir`, which is prepended to
+/// every entry name. Without a base directory entries are relative to the
+/// input itself and the prefix is empty.
+///
+/// # Arguments
+/// * `input` - Path to the input directory
+/// * `base_dir` - Ancestor of `input` that entry names are relative to
+fn entry_name_prefix(input: &Path, base_dir: Option<&Path>) -> io::Result<PathBuf> {
+    let Some(base_dir) = base_dir else {
+        return Ok(PathBuf::new());
+    };
+    let input_abs = input.canonicalize()?;
+    let base_abs = base_dir.canonicalize()?;
+    let prefix = input_abs.strip_prefix(&base_abs).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is not inside the base directory {}",
+                input.display(),
+                base_dir.display()
+            ),
+        )
+    })?;
+    println!(
+        "Archive entries are relative to {} (prefix '{}')",
+        base_dir.display(),
+        prefix.display()
+    );
+    Ok(prefix.to_path_buf())
+}
+
+/// Parses `--prefix`, which must be a non-empty relative path without `..`
+/// so that extraction stays below the output directory.
+fn parse_entry_prefix(value: &str) -> Result<PathBuf, String> {
+    match safe_entry_path(Path::new(value), 0) {
+        Ok(Some(prefix)) => Ok(prefix),
+        _ => Err(format!(
+            "expected a non-empty relative path without '..', got '{}'",
+            value
+        )),
+    }
+}
+
+/// Returns a description of a FIFO, socket or device node, or `None` for
+/// regular files, directories and symlinks.
+///
+/// Tar can record FIFOs and device nodes but has no entry type for sockets.
+#[cfg(unix)]
+fn special_file_kind(file_type: fs::FileType) -> Option<&'static str> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_fifo() {
+        Some("fifo")
+    } else if file_type.is_char_device() {
+        Some("character device")
+    } else if file_type.is_block_device() {
+        Some("block device")
+    } else if file_type.is_socket() {
+        Some("socket")
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_file_kind(_file_type: fs::FileType) -> Option<&'static str> {
+    None
+}
+
+/// Name of the pattern file read from the root of a directory input.
+const IGNORE_FILE_NAME: &str = ".rcompignore";
+
+/// Builds the exclude matcher for a directory input from the union of the
+/// ignore file and the `--exclude` patterns, using gitignore syntax.
+///
+/// The ignore file is `options.ignore_file` when given (and must exist),
+/// otherwise `<input>/.rcompignore` if present.
+///
+/// # Arguments
+/// * `input` - Root of the directory input; patterns are relative to it
+/// * `options` - Compression options
+fn build_ignore_matcher(input: &Path, options: &CompressOptions) -> io::Result<Gitignore> {
+    let to_io = |e: ignore::Error| io::Error::new(io::ErrorKind::InvalidInput, e.to_string());
+    let mut builder = GitignoreBuilder::new(input);
+    let mut patterns = Vec::new();
+
+    let ignore_file = match &options.ignore_file {
+        Some(path) => Some(path.clone()),
+        None => Some(input.join(IGNORE_FILE_NAME)).filter(|path| path.is_file()),
+    };
+    if let Some(path) = &ignore_file {
+        println!("Reading exclude patterns from: {}", path.display());
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            builder.add_line(Some(path.clone()), line).map_err(to_io)?;
+            patterns.push(line.to_string());
+        }
+    }
+    for pattern in &options.exclude {
+        builder.add_line(None, pattern).map_err(to_io)?;
+        patterns.push(pattern.clone());
+    }
+
+    if options.verbose {
+        println!("Effective exclude patterns ({}):", patterns.len());
+        for pattern in &patterns {
+            println!("  {}", pattern);
+        }
+    }
+    builder.build().map_err(to_io)
+}
+
+/// Appends a regular file to a tar archive using the requested header format.
+///
+/// # Arguments
+/// * `archive` - Tar builder to append to
+/// * `path` - Path of the file on disk
+/// * `name` - Path stored in the archive
+/// * `format` - Tar header format
+fn append_tar_file<W: Write>(
+    archive: &mut tar::Builder<W>,
+    path: &Path,
+    name: &Path,
+    format: TarFormat,
+) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    if format == TarFormat::Gnu {
+        return archive.append_file(name, &mut file);
+    }
+
+    let metadata = file.metadata()?;
+    let mut header = tar::Header::new_ustar();
+    header.set_metadata(&metadata);
+    let path_fits = header.set_path(name).is_ok();
+    let size_fits = metadata.len() <= USTAR_MAX_SIZE;
+
+    if !path_fits || !size_fits {
+        if format == TarFormat::Ustar {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} exceeds ustar limits (255-byte path, 8 GiB size); use --tar-format pax or gnu",
+                    name.display()
+                ),
+            ));
+        }
+
+        let (path_bytes, fallback_path) = pax_path(name)?;
+        let size_str = metadata.len().to_string();
+        let mut extensions: Vec<(&str, &[u8])> = Vec::new();
+        if !path_fits {
+            extensions.push(("path", path_bytes));
+        }
+        if !size_fits {
+            extensions.push(("size", size_str.as_bytes()));
+        }
+        archive.append_pax_extensions(extensions)?;
+
+        if !path_fits {
+            // Readers use the pax path; the header keeps a truncated fallback
+            header.set_path(fallback_path)?;
+        }
+    }
+
+    header.set_cksum();
+    archive.append(&header, file)
+}
+
+/// Returns the bytes stored as the pax `path` record of `name`, and the
+/// fallback name for the ustar header, cut to its 100-byte name field.
+///
+/// On Unix the raw bytes of the path are used, so names that are not valid
+/// UTF-8 are archived unchanged.
+#[cfg(unix)]
+fn pax_path(name: &Path) -> io::Result<(&[u8], &Path)> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let bytes = name.as_os_str().as_bytes();
+    let mut end = bytes.len().min(100);
+    // Valid UTF-8 names are cut on a character boundary
+    if let Ok(name_str) = std::str::from_utf8(bytes) {
+        while !name_str.is_char_boundary(end) {
+            end -= 1;
+        }
+    }
+    Ok((bytes, Path::new(OsStr::from_bytes(&bytes[..end]))))
+}
+
+#[cfg(not(unix))]
+fn pax_path(name: &Path) -> io::Result<(&[u8], &Path)> {
+    let name_str = name.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("non-UTF-8 path cannot be stored in pax: {}", name.display()),
+        )
+    })?;
+    let mut end = name_str.len().min(100);
+    while !name_str.is_char_boundary(end) {
+        end -= 1;
+    }
+    Ok((name_str.as_bytes(), Path::new(&name_str[..end])))
+}
+
+/// File extensions of formats that are already compressed.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "avif", "heic", "mp3", "ogg", "flac", "aac", "mp4", "m4a",
+    "m4v", "mkv", "mov", "avi", "webm", "zip", "gz", "tgz", "bz2", "xz", "zst", "br", "7z", "rar",
+    "lz4",
+];
+
+/// Magic numbers of formats that are already compressed.
+const COMPRESSED_MAGIC: &[&[u8]] = &[
+    &[0x1f, 0x8b],                         // gzip
+    &[0x28, 0xb5, 0x2f, 0xfd],             // zstd
+    &[0xfd, b'7', b'z', b'X', b'Z', 0x00], // xz
+    b"BZh",                                // bzip2
+    b"PK\x03\x04",                         // zip
+    &[b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c], // 7z
+    b"Rar!",                               // rar
+    &[0x89, b'P', b'N', b'G'],             // png
+    &[0xff, 0xd8, 0xff],                   // jpeg
+    b"GIF8",                               // gif
+];
+
+/// Checks whether a file is already compressed, first by extension and
+/// then by looking at its magic bytes.
+///
+/// # Arguments
+/// * `path` - Path to the file to check
+fn is_already_compressed(path: &Path) -> io::Result<bool> {
+    let by_extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| COMPRESSED_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false);
+    if by_extension {
+        return Ok(true);
+    }
+
+    let mut header = [0u8; 12];
+    let read = File::open(path)?.read(&mut header)?;
+    let header = &header[..read];
+    let is_riff_webp = header.len() >= 12 && &header[..4] == b"RIFF" && &header[8..12] == b"WEBP";
+    let is_mp4 = header.len() >= 8 && &header[4..8] == b"ftyp";
+    Ok(is_riff_webp
+        || is_mp4
+        || COMPRESSED_MAGIC
+            .iter()
+            .any(|magic| header.starts_with(magic)))
+}
+
+/// Number of leading bytes sampled to estimate a file's entropy.
+const ENTROPY_SAMPLE_LEN: u64 = 4096;
+
+/// Sampled entropy, in bits per byte, above which data is treated as
+/// already compressed or random.
+const ENTROPY_SKIP_THRESHOLD: f64 = 7.5;
+
+/// Estimates the Shannon entropy of the first [`ENTROPY_SAMPLE_LEN`] bytes
+/// of a file, in bits per byte (0 to 8).
+///
+/// # Arguments
+/// * `path` - Path to the file to sample
+fn sample_entropy(path: &Path) -> io::Result<f64> {
+    let mut sample = Vec::new();
+    File::open(path)?
+        .take(ENTROPY_SAMPLE_LEN)
+        .read_to_end(&mut sample)?;
+
+    let mut counts = [0usize; 256];
+    for &byte in &sample {
+        counts[byte as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    Ok(counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum())
+}
+
+/// Returns the sampled entropy of a file if `--entropy-skip` is set and the
+/// entropy is above [`ENTROPY_SKIP_THRESHOLD`]. The estimate is printed in
+/// verbose mode.
+///
+/// # Arguments
+/// * `path` - Path to the file to check
+/// * `options` - Compression options
+fn high_entropy(path: &Path, options: &CompressOptions) -> io::Result<Option<f64>> {
+    if !options.entropy_skip {
+        return Ok(None);
+    }
+    let entropy = sample_entropy(path)?;
+    if options.verbose {
+        println!(
+            "Estimated entropy of {}: {:.2} bits/byte",
+            path.display(),
+            entropy
+        );
+    }
+    Ok((entropy > ENTROPY_SKIP_THRESHOLD).then_some(entropy))
+}
+
+/// Result of compressing the same input with one algorithm.
+struct Comparison {
+    algorithm: Algorithm,
+    size: usize,
+    compress_time: Duration,
+    decompress_time: Duration,
+}
+
+/// Compresses the input with every available algorithm in memory and prints
+/// a table ranked by output size.
+///
+/// # Arguments
+/// * `input` - Path to the input file or directory (directories are tarred first)
+/// * `level` - Compression level, with presets resolved per algorithm
+/// * `winner` - Whether to recommend the algorithm with the best ratio per second
+fn compare_algorithms(input: &str, level: Level, winner: bool) -> io::Result<()> {
+    let data = if Path::new(input).is_dir() {
+        build_tar(input)?
+    } else {
+        fs::read(input)?
+    };
+
+    let mut results = Vec::new();
+    for algorithm in Algorithm::available() {
+        let level = level.resolve(algorithm);
+        println!("Compressing with {} level {}...", algorithm, level);
+        let start = Instant::now();
+        let compressed = algorithm::compress_bytes(algorithm, &data, level)?;
+        let compress_time = start.elapsed();
+
+        let start = Instant::now();
+        let decompressed = algorithm::decompress_bytes(algorithm, &compressed)?;
+        let decompress_time = start.elapsed();
+        if decompressed != data {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} round-trip produced different data", algorithm),
+            ));
+        }
+
+        results.push(Comparison {
+            algorithm,
+            size: compressed.len(),
+            compress_time,
+            decompress_time,
+        });
+    }
+    results.sort_by_key(|r| r.size);
+
+    println!(
+        "{:<5} {:<10} {:>14} {:>8} {:>14} {:>14}",
+        "Rank", "Algorithm", "Size", "Ratio", "Compress", "Decompress"
+    );
+    for (rank, result) in results.iter().enumerate() {
+        println!(
+            "{:<5} {:<10} {:>14} {:>7.1}% {:>11.1} ms {:>11.1} ms",
+            rank + 1,
+            result.algorithm,
+            format_size(result.size as u64),
+            (1.0 - result.size as f64 / data.len().max(1) as f64) * 100.0,
+            result.compress_time.as_secs_f64() * 1000.0,
+            result.decompress_time.as_secs_f64() * 1000.0,
+        );
+    }
+
+    if winner {
+        // Ratio per second: how many times smaller the output is, per second of compression.
+        let score = |r: &Comparison| {
+            (data.len() as f64 / r.size.max(1) as f64) / r.compress_time.as_secs_f64().max(1e-9)
+        };
+        if let Some(best) = results.iter().max_by(|a, b| score(a).total_cmp(&score(b))) {
+            println!(
+                "Winner: {} (best ratio per second: {:.2})",
+                best.algorithm,
+                score(best)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Converts a compressed file to another algorithm by streaming the decoded
+/// data straight into a new encoder, without writing it to disk.
+///
+/// # Arguments
+/// * `input` - Path to the compressed input file
+/// * `output` - Path where the recompressed file will be saved
+/// * `algorithm` - Target compression algorithm
+/// * `level` - Target compression level
+fn recompress_file(input: &str, output: &str, algorithm: Algorithm, level: u32) -> io::Result<()> {
+    let source = detect_input_algorithm(input)?;
+    println!("Detected {} input", source);
+
+    let input_file = File::open(input)?;
+    let old_size = input_file.metadata()?.len();
+    let mut decoder = algorithm::make_decoder(source, io::BufReader::new(input_file))?;
+    let mut encoder = algorithm::make_encoder(algorithm, File::create(output)?, level)?;
+    io::copy(&mut decoder, &mut encoder)?;
+    encoder.finish()?;
+
+    let new_size = fs::metadata(output)?.len();
+    println!(
+        "Recompression complete! {}: {} -> {}: {} ({:+.1}%)",
+        source,
+        format_size(old_size),
+        algorithm,
+        format_size(new_size),
+        (new_size as f64 / old_size.max(1) as f64 - 1.0) * 100.0
+    );
+    Ok(())
+}
+
+/// Detects the compression algorithm of an existing file.
+///
+/// The magic bytes take precedence; the extension is only consulted for
+/// formats without one (brotli).
+///
+/// # Arguments
+/// * `input` - Path to the compressed file
+///
+/// # Returns
+/// The detected algorithm, or an error if neither check matched
+fn detect_input_algorithm(input: &str) -> io::Result<Algorithm> {
+    let mut input_file = File::open(input)?;
+    let mut magic = [0u8; 6];
+    let read = input_file.read(&mut magic)?;
+    Algorithm::detect(&magic[..read])
+        .or_else(|| Algorithm::from_path(Path::new(input)))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Cannot detect the compression algorithm of {}", input),
+            )
+        })
+}
+
+/// What `merge` does when an entry path was already written by an earlier
+/// archive.
+///
+/// Directory entries are never treated as collisions; repeats are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MergeCollision {
+    /// Abort the merge and remove the partial output
+    Error,
+    /// Keep the first entry and drop later ones
+    Skip,
+    /// Append `-1`, `-2`, ... to the file stem of later entries
+    Rename,
+}
+
+/// Returns the first `stem-N.ext` variant of `path` not yet in `written`.
+fn merge_rename(path: &Path, written: &HashSet<PathBuf>) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut suffix = 1;
+    loop {
+        let candidate = path.with_file_name(format!("{}-{}{}", stem, suffix, ext));
+        if !written.contains(&candidate) {
+            return candidate;
+        }
+        suffix += 1;
+    }
+}
+
+/// Merges several compressed tar archives into a single new archive.
+///
+/// Entries are streamed from each input straight into the output, so no
+/// archive is ever held in memory or unpacked to disk. The output algorithm
+/// comes from its extension and falls back to gzip.
+///
+/// # Arguments
+/// * `inputs` - Archives to read, in the order their entries are written
+/// * `output` - Path of the merged archive
+/// * `collision` - How to handle a path that appears in more than one input
+/// * `level` - Compression level, resolved for the output algorithm
+fn merge_archives(
+    inputs: &[&String],
+    output: &str,
+    collision: MergeCollision,
+    level: Level,
+) -> io::Result<()> {
+    let algorithm = Algorithm::from_path(Path::new(output)).unwrap_or_else(|| {
+        eprintln!(
+            "Warning: cannot tell the algorithm from '{}', using gzip",
+            output
+        );
+        Algorithm::Gzip
+    });
+    let encoder =
+        algorithm::make_encoder(algorithm, File::create(output)?, level.resolve(algorithm))?;
+    let mut archive = tar::Builder::new(encoder);
+
+    let result = merge_entries(&mut archive, inputs, collision);
+    let result = result.and_then(|stats| {
+        archive.into_inner()?.finish()?;
+        Ok(stats)
+    });
+    let (merged, skipped, renamed) = match result {
+        Ok(stats) => stats,
+        Err(e) => {
+            let _ = fs::remove_file(output);
+            return Err(e);
+        }
+    };
+
+    println!(
+        "Merge complete! {} entries from {} archives written to {} ({} skipped, {} renamed)",
+        merged,
+        inputs.len(),
+        output,
+        skipped,
+        renamed
+    );
+    Ok(())
+}
+
+/// Copies the entries of every input archive into `archive`.
+///
+/// # Returns
+/// The number of entries written, skipped and renamed
+fn merge_entries<W: Write>(
+    archive: &mut tar::Builder<W>,
+    inputs: &[&String],
+    collision: MergeCollision,
+) -> io::Result<(usize, usize, usize)> {
+    let mut written: HashSet<PathBuf> = HashSet::new();
+    let (mut merged, mut skipped, mut renamed) = (0, 0, 0);
+
+    for input in inputs {
+        let source = detect_input_algorithm(input)?;
+        let decoder = algorithm::make_decoder(source, io::BufReader::new(File::open(input)?))?;
+        let mut reader = tar::Archive::new(decoder);
+
+        for entry in reader.entries()? {
+            let mut entry = entry?;
+            let Some(mut path) = safe_entry_path(&entry.path()?, 0)? else {
+                continue;
+            };
+            let is_dir = entry.header().entry_type().is_dir();
+
+            if written.contains(&path) {
+                if is_dir {
+                    continue;
+                }
+                match collision {
+                    MergeCollision::Error => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("{} in {} is already in the archive", path.display(), input),
+                        ));
+                    }
+                    MergeCollision::Skip => {
+                        eprintln!(
+                            "Warning: skipping {} from {}, already in the archive",
+                            path.display(),
+                            input
+                        );
+                        skipped += 1;
+                        continue;
+                    }
+                    MergeCollision::Rename => {
+                        let renamed_path = merge_rename(&path, &written);
+                        eprintln!(
+                            "Warning: {} from {} renamed to {}",
+                            path.display(),
+                            input,
+                            renamed_path.display()
+                        );
+                        path = renamed_path;
+                        renamed += 1;
+                    }
+                }
+            }
+
+            // The cloned header holds at most 100 bytes of the path and link
+            // target; the full ones come from the GNU or pax extensions
+            let mut header = entry.header().clone();
+            header.set_size(entry.size());
+            match entry.link_name()? {
+                Some(target)
+                    if entry.header().entry_type().is_symlink()
+                        || entry.header().entry_type().is_hard_link() =>
+                {
+                    let target = target.into_owned();
+                    archive.append_link(&mut header, &path, target)?;
+                }
+                _ => archive.append_data(&mut header, &path, &mut entry)?,
+            }
+            written.insert(path);
+            merged += 1;
+        }
+    }
+
+    Ok((merged, skipped, renamed))
+}
+
+/// Prints a summary of a compressed file without extracting it.
+///
+/// The data is decompressed into a counter to measure its size. Tar
+/// archives, recognized by the ustar magic of their first header, also
+/// report the number of entries.
+///
+/// # Arguments
+/// * `input` - Path to the compressed file
+fn stat_file(input: &str) -> io::Result<()> {
+    check_dictionary(input, None)?;
+    let algorithm = detect_input_algorithm(input)?;
+    let compressed_size = fs::metadata(input)?.len();
+
+    let input_file = io::BufReader::new(File::open(input)?);
+    let mut decoder = algorithm::make_decoder(algorithm, input_file)?;
+    let mut header = Vec::with_capacity(512);
+    decoder.by_ref().take(512).read_to_end(&mut header)?;
+    let is_tar = header.len() == 512 && header[257..262] == *b"ustar";
+    let mut reader = CountingReader::new(io::Cursor::new(header).chain(decoder));
+
+    let entries = if is_tar {
+        let mut archive = tar::Archive::new(&mut reader);
+        let mut entries = 0usize;
+        for entry in archive.entries()? {
+            entry?;
+            entries += 1;
+        }
+        Some(entries)
+    } else {
+        None
+    };
+    // Counts the data of single files and the padding after a tar's last entry
+    io::copy(&mut reader, &mut io::sink())?;
+    let decompressed_size = reader.count;
+
+    println!("File:              {}", input);
+    println!("Algorithm:         {}", algorithm);
+    println!("Compressed size:   {}", format_size(compressed_size));
+    println!("Decompressed size: {}", format_size(decompressed_size));
+    if let Some(entries) = entries {
+        println!("Tar entries:       {}", entries);
+    }
+    println!(
+        "Ratio:             {:.1}%",
+        (1.0 - compressed_size as f64 / decompressed_size.max(1) as f64) * 100.0
+    );
+    Ok(())
+}
+
+/// Writes the checksum of a compressed file to a sidecar.
+///
+/// The digest is computed while the file is written, so the output is not
+/// read again. The sidecar is named `<output>.<algorithm>` and uses the BSD
+/// tag format (`SHA256 (file) = digest`) so the algorithm is recorded with
+/// the digest.
+///
+/// # Arguments
+/// * `output` - Path to the compressed file
+/// * `algorithm` - Hash algorithm the digest was computed with
+/// * `digest` - Hex digest of the compressed file
+fn write_checksum_sidecar(
+    output: &str,
+    algorithm: HashAlgorithm,
+    digest: &str,
+) -> io::Result<PathBuf> {
+    let file_name = Path::new(output)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| output.to_string());
+
+    let sidecar = PathBuf::from(format!("{}.{}", output, algorithm));
+    fs::write(
+        &sidecar,
+        format!(
+            "{} ({}) = {}\n",
+            algorithm.name().to_uppercase(),
+            file_name,
+            digest
+        ),
+    )?;
+    println!("{}: {}", algorithm, digest);
+    Ok(sidecar)
+}
+
+/// Options controlling which tar entries are extracted and where.
+#[derive(Debug, Clone, Default)]
+struct ExtractOptions {
+    /// Glob patterns selecting which entries to extract (empty extracts all)
+    only: Vec<String>,
+    /// Number of leading path components removed from each entry
+    strip_components: usize,
+    /// Smallest regular file extracted, in bytes
+    min_size: Option<u64>,
+    /// Largest regular file extracted, in bytes
+    max_size: Option<u64>,
+    /// Only regular files modified after this time are extracted
+    newer_than: Option<SystemTime>,
+    /// Zstd dictionary the input was compressed with
+    dict: Option<PathBuf>,
+    /// Report what would be extracted instead of writing it
+    dry_run: bool,
+    /// Threads writing regular files in parallel; entries are unpacked in
+    /// archive order when unset
+    jobs: Option<usize>,
+    /// Stop at the first corrupt or truncated entry, keeping the entries
+    /// extracted before it, instead of failing
+    salvage: bool,
+}
+
+impl ExtractOptions {
+    /// Whether entries must be extracted one by one instead of with `unpack`.
+    fn needs_entry_iteration(&self) -> bool {
+        !self.only.is_empty() || self.strip_components > 0 || self.dry_run || self.filters_files()
+    }
+
+    /// Whether regular files are filtered by size or modification time.
+    fn filters_files(&self) -> bool {
+        self.min_size.is_some() || self.max_size.is_some() || self.newer_than.is_some()
+    }
+
+    /// Whether a regular file entry passes `--min-size`, `--max-size` and
+    /// `--newer-than`. Entries whose mtime cannot be read are kept, erring
+    /// toward restoring too much.
+    fn keeps_file(&self, size: u64, header: &tar::Header) -> bool {
+        let modified = header
+            .mtime()
+            .ok()
+            .map(|mtime| SystemTime::UNIX_EPOCH + Duration::from_secs(mtime));
+        self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size <= max)
+            && self
+                .newer_than
+                .is_none_or(|cutoff| modified.is_none_or(|modified| modified > cutoff))
+    }
 }
 
 /// Decompresses a file or archive.
-/// Supports both .gz and .tar.gz/.tgz formats.
+/// Supports single files and tar archives compressed with any compiled-in
+/// algorithm, detected from the magic bytes or, for brotli, the extension.
 ///
 /// # Arguments
 /// * `input` - Path to the compressed file
 /// * `output` - Path where files will be extracted
-fn decompress_file(input: &str, output: &str) -> io::Result<()> {
+/// * `options` - Tar extraction options
+fn decompress_file(input: &str, output: &str, options: &ExtractOptions) -> io::Result<()> {
+    if let (Some(min), Some(max)) = (options.min_size, options.max_size) {
+        if min > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--min-size {} is larger than --max-size {}", min, max),
+            ));
+        }
+    }
     println!("Opening compressed file: {}", input);
     let input_file = File::open(input)?;
     let input_size = input_file.metadata()?.len();
 
     let pb = ProgressBar::new(input_size);
-    let style = ProgressStyle::default_bar()
-        .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-        .unwrap();
-    pb.set_style(style);
+    pb.set_style(byte_progress_style());
 
-    let decoder = GzDecoder::new(input_file);
+    let dict = options.dict.as_deref().map(fs::read).transpose()?;
+    check_dictionary(input, dict.as_deref())?;
+    let (decoder, algorithm): (Box<dyn Read>, _) = match &dict {
+        Some(dict) => (
+            algorithm::make_dict_decoder(input_file, dict)?,
+            Algorithm::Zstd,
+        ),
+        None => {
+            let algorithm = detect_input_algorithm(input)?;
+            (
+                algorithm::make_decoder(algorithm, io::BufReader::new(input_file))?,
+                algorithm,
+            )
+        }
+    };
+    let format = compressed_extension(algorithm, false);
+    let lower = input.to_lowercase();
 
-    if input.ends_with(".tar.gz") || input.ends_with(".tgz") {
-        println!("Detected tar.gz format, extracting archive...");
+    if tar_suffixes(algorithm)
+        .iter()
+        .any(|suffix| lower.ends_with(suffix))
+    {
+        println!("Detected tar.{} format, extracting archive...", format);
         let mut archive = tar::Archive::new(decoder);
-        fs::create_dir_all(output)?;
-        archive.unpack(output)?;
+        if !options.dry_run {
+            let output_path = Path::new(output);
+            if output_path.exists() && !output_path.is_dir() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} is not a directory; tar archives are extracted into one",
+                        output
+                    ),
+                ));
+            }
+            if !output_path.exists() {
+                println!("Creating output directory: {}", output);
+            }
+            fs::create_dir_all(output)?;
+        }
+        if options.needs_entry_iteration() || options.jobs.is_some() || options.salvage {
+            extract_entries(&mut archive, output, options)?;
+        } else {
+            archive.unpack(output)?;
+        }
+    } else if options.dry_run {
+        println!(
+            "Dry run: would decompress single-file {} input to {}",
+            format, output
+        );
+        return Ok(());
     } else {
-        println!("Detected gz format, decompressing file...");
+        if options.needs_entry_iteration() {
+            eprintln!(
+                "Warning: entry filters and --strip-components are ignored for single-file {} input",
+                format
+            );
+        }
+        if options.jobs.is_some() {
+            eprintln!(
+                "Warning: --jobs is ignored for single-file {} input",
+                format
+            );
+        }
+        println!("Detected {} format, decompressing file...", format);
         let mut output_file = File::create(output)?;
-        io::copy(&mut pb.wrap_read(decoder), &mut output_file)?;
+        match io::copy(&mut pb.wrap_read(decoder), &mut output_file) {
+            Ok(_) => {}
+            Err(e) if options.salvage => {
+                pb.abandon();
+                eprintln!(
+                    "Warning: stopped at corrupt or truncated data: {}; kept the first {} in {}",
+                    e,
+                    format_size(output_file.metadata()?.len()),
+                    output
+                );
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
     }
 
     pb.finish_with_message("Decompression complete!");
     Ok(())
 }
+
+/// Validates the dictionary supplied for `input` against the dictionary ID
+/// recorded in its zstd frame header, so a missing or wrong dictionary is
+/// reported up front instead of as corrupt data partway through.
+///
+/// # Arguments
+/// * `input` - Path to the compressed file
+/// * `dict` - Zstd dictionary contents, if one was supplied
+fn check_dictionary(input: &str, dict: Option<&[u8]>) -> io::Result<()> {
+    // Large enough for the longest zstd frame header.
+    let mut header = [0u8; 18];
+    let read = File::open(input)?.read(&mut header)?;
+    let header = &header[..read];
+    let is_zstd = Algorithm::detect(header) == Some(Algorithm::Zstd);
+    let required = if is_zstd {
+        algorithm::frame_dict_id(header)
+    } else {
+        None
+    };
+
+    match (dict, required) {
+        (None, None) => Ok(()),
+        (None, Some(id)) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} was compressed with dictionary {}; pass it with --dict",
+                input, id
+            ),
+        )),
+        (Some(_), _) if !is_zstd => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--dict requires zstd input, but {} is not zstd", input),
+        )),
+        (Some(_), None) => {
+            eprintln!(
+                "Warning: {} does not record a dictionary ID; the dictionary cannot be validated",
+                input
+            );
+            Ok(())
+        }
+        (Some(dict), Some(id)) => match algorithm::dict_id(dict) {
+            Some(supplied) if supplied == id => {
+                println!("Dictionary ID {} matches", id);
+                Ok(())
+            }
+            supplied => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} needs dictionary {}, but the supplied dictionary has {}",
+                    input,
+                    id,
+                    supplied.map_or_else(|| "no ID".to_string(), |s| format!("ID {}", s))
+                ),
+            )),
+        },
+    }
+}
+
+/// Trains a zstd dictionary from sample files and writes it to `output`.
+/// Directories are walked recursively and every regular file in them is
+/// used as one sample.
+///
+/// # Arguments
+/// * `inputs` - Sample files or directories of samples
+/// * `output` - Path where the dictionary will be saved
+/// * `max_size` - Maximum dictionary size in bytes
+fn train_dictionary(inputs: &[&String], output: &str, max_size: usize) -> io::Result<()> {
+    let mut samples = Vec::new();
+    for input in inputs {
+        for entry in WalkDir::new(input) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let data = fs::read(entry.path())?;
+            if !data.is_empty() {
+                samples.push(data);
+            }
+        }
+    }
+
+    let total: usize = samples.iter().map(Vec::len).sum();
+    println!(
+        "Collected {} samples ({})",
+        samples.len(),
+        format_size(total as u64)
+    );
+    if samples.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No non-empty sample files found",
+        ));
+    }
+
+    let dict = algorithm::train_dict(&samples, max_size)?;
+    fs::write(output, &dict)?;
+    match algorithm::dict_id(&dict) {
+        Some(id) => println!(
+            "Dictionary written to {}: {}, ID {}",
+            output,
+            format_size(dict.len() as u64),
+            id
+        ),
+        None => println!(
+            "Dictionary written to {}: {}",
+            output,
+            format_size(dict.len() as u64)
+        ),
+    }
+    Ok(())
+}
+
+/// Extracts tar entries one by one, applying the `--only` filter, the
+/// size and mtime filters and `--strip-components`. When patterns are
+/// given, reports how many entries each pattern matched and warns about
+/// patterns that matched nothing. Regular files failing the size or mtime
+/// filters are skipped and counted, along with hard links to them.
+/// With `options.dry_run`, every entry is listed with its recorded mode and
+/// owner instead, and unsafe paths are flagged rather than skipped. With
+/// `options.jobs`, regular files are handed to a [`ParallelExtractor`].
+/// With `options.salvage`, the first read error ends extraction: the
+/// partially written entry is removed and the entries extracted before it
+/// are listed.
+///
+/// # Arguments
+/// * `archive` - Tar archive to read entries from
+/// * `output` - Directory where entries will be extracted
+/// * `options` - Tar extraction options
+fn extract_entries<R: Read>(
+    archive: &mut tar::Archive<R>,
+    output: &str,
+    options: &ExtractOptions,
+) -> io::Result<()> {
+    let patterns = &options.only;
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob =
+            Glob::new(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        builder.add(glob);
+    }
+    let globs = builder
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut counts = vec![0usize; patterns.len()];
+    let (mut listed, mut unsafe_entries) = (0usize, 0usize);
+    if options.dry_run {
+        println!(
+            "{:<8} {:>4} {:<20} Target",
+            "Type", "Mode", "Owner (uid:gid)"
+        );
+    }
+    let mut parallel = match options.jobs {
+        Some(jobs) if !options.dry_run => Some(ParallelExtractor::new(jobs)?),
+        _ => None,
+    };
+    // Resolved once, so every target can be checked to stay inside it
+    let root = match options.dry_run {
+        true => PathBuf::new(),
+        false => fs::canonicalize(output)?,
+    };
+    let mut recovered = Vec::new();
+    // Entries skipped by the size and mtime filters
+    let mut filtered: HashSet<PathBuf> = HashSet::new();
+    // The entry being read when --salvage stopped, if its name was readable
+    let mut corrupt: Option<(Option<PathBuf>, io::Error)> = None;
+    for entry in archive.entries()? {
+        let (mut entry, path) = match entry.and_then(|entry| {
+            let path = entry.path()?.into_owned();
+            Ok((entry, path))
+        }) {
+            Ok(entry) => entry,
+            Err(e) if options.salvage => {
+                corrupt = Some((None, e));
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        if !patterns.is_empty() {
+            let matched = globs.matches(&path);
+            if matched.is_empty() {
+                continue;
+            }
+            for index in matched {
+                counts[index] += 1;
+            }
+        }
+        if options.filters_files() {
+            let entry_type = entry.header().entry_type();
+            let skip = if entry_type.is_file() {
+                !options.keeps_file(entry.size(), entry.header())
+            } else if entry_type.is_hard_link() {
+                // Its source was not extracted, so the link cannot be made
+                entry
+                    .link_name()?
+                    .is_some_and(|link| filtered.contains(link.as_ref()))
+            } else {
+                false
+            };
+            if skip {
+                filtered.insert(path);
+                continue;
+            }
+        }
+
+        let relative = match safe_entry_path(&path, options.strip_components) {
+            Ok(Some(relative)) => relative,
+            Ok(None) => {
+                println!(
+                    "Skipping (fewer than {} components): {}",
+                    options.strip_components,
+                    path.display()
+                );
+                continue;
+            }
+            Err(_) if options.dry_run => {
+                println!(
+                    "{} UNSAFE {}",
+                    entry_summary(entry.header()),
+                    path.display()
+                );
+                unsafe_entries += 1;
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Warning: {}", e);
+                continue;
+            }
+        };
+        let target = Path::new(output).join(&relative);
+        if options.dry_run {
+            if options.salvage {
+                // Read the data so truncation is blamed on the right entry
+                if let Err(e) = io::copy(&mut entry, &mut io::sink()) {
+                    corrupt = Some((Some(relative), e));
+                    break;
+                }
+                recovered.push(relative);
+            }
+            println!("{} {}", entry_summary(entry.header()), target.display());
+            listed += 1;
+            continue;
+        }
+        if let Err(e) = create_entry_parents(&root, Path::new(output), &relative) {
+            if e.kind() != io::ErrorKind::InvalidData {
+                return Err(e);
+            }
+            eprintln!("Warning: {}", e);
+            continue;
+        }
+        println!("Extracting: {}", relative.display());
+        let buffered =
+            entry.header().entry_type().is_file() && entry.size() <= PARALLEL_BATCH_BYTES;
+        if let Some(parallel) = parallel.as_mut().filter(|_| !buffered) {
+            // Anything else sees the files before it, as in archive order
+            parallel.flush()?;
+        }
+        // Link targets are entry names too, so they are resolved the same way
+        if entry.header().entry_type().is_hard_link() {
+            let link = entry.link_name()?.map(|link| link.into_owned());
+            match link.map(|link| safe_entry_path(&link, options.strip_components)) {
+                Some(Ok(Some(source))) => {
+                    let source = Path::new(output).join(source);
+                    if let Err(e) = check_inside(&root, &source) {
+                        eprintln!("Warning: {}", e);
+                        continue;
+                    }
+                    hard_link_entry(&source, &target)?;
+                    recovered.push(relative);
+                }
+                Some(Err(e)) => eprintln!("Warning: {}", e),
+                _ => eprintln!("Warning: hard link {} has no usable target", path.display()),
+            }
+            continue;
+        }
+        if let Some(parallel) = parallel.as_mut().filter(|_| buffered) {
+            match parallel.add_file(target, &mut entry) {
+                Ok(()) => recovered.push(relative),
+                Err(e) if options.salvage => {
+                    corrupt = Some((Some(relative), e));
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+            continue;
+        }
+        match entry.unpack(&target) {
+            Ok(_) => recovered.push(relative),
+            Err(e) if options.salvage => {
+                if entry.header().entry_type().is_file() {
+                    let _ = fs::remove_file(&target);
+                }
+                corrupt = Some((Some(relative), e));
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(parallel) = parallel {
+        parallel.finish()?;
+    }
+    if options.salvage {
+        let verb = if options.dry_run {
+            "Recoverable"
+        } else {
+            "Recovered"
+        };
+        println!("{} {} entries:", verb, recovered.len());
+        for name in &recovered {
+            println!("  {}", name.display());
+        }
+        match corrupt {
+            Some((Some(name), e)) => eprintln!(
+                "Warning: stopped at corrupt or truncated entry {}: {}",
+                name.display(),
+                error_chain(&e)
+            ),
+            Some((None, e)) => eprintln!(
+                "Warning: stopped at corrupt or truncated data: {}",
+                error_chain(&e)
+            ),
+            None => println!("No corruption found; the archive is complete"),
+        }
+    }
+
+    if options.filters_files() {
+        println!(
+            "Skipped {} entries outside the size and modification time filters",
+            filtered.len()
+        );
+    }
+    for (pattern, count) in patterns.iter().zip(&counts) {
+        if *count == 0 {
+            eprintln!("Warning: pattern '{}' matched no entries", pattern);
+        } else {
+            println!("Pattern '{}' matched {} entries", pattern, count);
+        }
+    }
+    if options.dry_run {
+        println!(
+            "Dry run: {} entries would be extracted, {} unsafe entries would be skipped; nothing was written",
+            listed, unsafe_entries
+        );
+    }
+    Ok(())
+}
+
+/// Buffered file bytes that trigger a parallel write of the pending batch.
+/// Larger files are unpacked directly instead of being held in memory.
+const PARALLEL_BATCH_BYTES: u64 = 64 * 1024 * 1024;
+
+/// A regular tar entry read into memory, waiting to be written.
+struct PendingFile {
+    target: PathBuf,
+    data: Vec<u8>,
+    mode: Option<u32>,
+    mtime: Option<u64>,
+}
+
+/// Writes regular files from a tar stream on a thread pool for `--jobs`.
+///
+/// The archive itself can only be read sequentially, so entries are
+/// buffered in batches of about [`PARALLEL_BATCH_BYTES`] and each batch is
+/// written in parallel. The caller flushes the batch before unpacking any
+/// other entry, and a batch never holds the same path twice, so the result
+/// is the same as extracting in archive order.
+struct ParallelExtractor {
+    pool: rayon::ThreadPool,
+    jobs: usize,
+    pending: Vec<PendingFile>,
+    pending_targets: HashSet<PathBuf>,
+    pending_bytes: u64,
+    files: u64,
+    bytes: u64,
+    started: Instant,
+}
+
+impl ParallelExtractor {
+    fn new(jobs: usize) -> io::Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .thread_name(|index| format!("extract-{}", index))
+            .build()
+            .map_err(io::Error::other)?;
+        Ok(ParallelExtractor {
+            pool,
+            jobs,
+            pending: Vec::new(),
+            pending_targets: HashSet::new(),
+            pending_bytes: 0,
+            files: 0,
+            bytes: 0,
+            started: Instant::now(),
+        })
+    }
+
+    /// Reads a regular file entry into the pending batch, writing the batch
+    /// once it is large enough. A repeated path flushes the batch first, so
+    /// the later entry wins.
+    fn add_file<R: Read>(&mut self, target: PathBuf, entry: &mut tar::Entry<R>) -> io::Result<()> {
+        if !self.pending_targets.insert(target.clone()) {
+            self.flush()?;
+            self.pending_targets.insert(target.clone());
+        }
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut data)?;
+        self.pending_bytes += data.len() as u64;
+        self.pending.push(PendingFile {
+            target,
+            data,
+            mode: entry.header().mode().ok(),
+            mtime: entry.header().mtime().ok(),
+        });
+        if self.pending_bytes >= PARALLEL_BATCH_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the pending batch across the pool.
+    fn flush(&mut self) -> io::Result<()> {
+        let batch = std::mem::take(&mut self.pending);
+        self.pending_targets.clear();
+        self.pool
+            .install(|| batch.par_iter().try_for_each(write_pending_file))?;
+        self.files += batch.len() as u64;
+        self.bytes += self.pending_bytes;
+        self.pending_bytes = 0;
+        Ok(())
+    }
+
+    /// Writes the remaining files and reports the extraction throughput.
+    fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        let secs = self.started.elapsed().as_secs_f64();
+        println!(
+            "Wrote {} files ({}) on {} threads in {:.2}s ({}/s)",
+            self.files,
+            format_size(self.bytes),
+            self.jobs,
+            secs,
+            format_size((self.bytes as f64 / secs.max(f64::EPSILON)) as u64)
+        );
+        Ok(())
+    }
+}
+
+/// Formats an I/O error with its causes; tar wraps the decoder error that
+/// explains a failed unpack.
+fn error_chain(e: &io::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.get_ref().and_then(|inner| inner.source());
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    message
+}
+
+/// Creates a hard link entry at `target`, replacing any existing file.
+fn hard_link_entry(source: &Path, target: &Path) -> io::Result<()> {
+    if target.symlink_metadata().is_ok() {
+        fs::remove_file(target)?;
+    }
+    fs::hard_link(source, target)
+}
+
+/// Writes one buffered file, restoring its recorded mode and mtime. Like
+/// tar's `unpack`, an existing entry at the target is removed and the file
+/// is created anew, so a symlink planted there is never followed.
+fn write_pending_file(file: &PendingFile) -> io::Result<()> {
+    if file
+        .target
+        .symlink_metadata()
+        .is_ok_and(|meta| !meta.is_dir())
+    {
+        fs::remove_file(&file.target)?;
+    }
+    let mut out = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&file.target)?;
+    out.write_all(&file.data)?;
+    if let Some(mtime) = file.mtime {
+        out.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))?;
+    }
+    if let Some(mode) = file.mode {
+        set_entry_mode(&out, mode)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_entry_mode(file: &File, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(fs::Permissions::from_mode(mode & 0o777))
+}
+
+#[cfg(not(unix))]
+fn set_entry_mode(_file: &File, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Adds the execute bits wherever the read bits of `path` are set.
+#[cfg(unix)]
+fn make_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path)?.permissions().mode();
+    fs::set_permissions(path, fs::Permissions::from_mode(mode | (mode & 0o444) >> 2))
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Formats the type, mode and owner recorded in a tar header as the
+/// leading columns of a `--dry-run` line.
+fn entry_summary(header: &tar::Header) -> String {
+    let entry_type = header.entry_type();
+    let kind = if entry_type.is_file() {
+        "file"
+    } else if entry_type.is_dir() {
+        "dir"
+    } else if entry_type.is_symlink() {
+        "symlink"
+    } else if entry_type.is_hard_link() {
+        "hardlink"
+    } else {
+        "other"
+    };
+    let id = |id: io::Result<u64>| id.map_or_else(|_| "?".to_string(), |id| id.to_string());
+    let owner = format!("{}:{}", id(header.uid()), id(header.gid()));
+    let mode = header
+        .mode()
+        .map_or_else(|_| "?".to_string(), |m| format!("{:04o}", m & 0o7777));
+    format!("{:<8} {:>4} {:<20}", kind, mode, owner)
+}
+
+/// Creates the parent directories of an entry below `output`, one
+/// component at a time.
+///
+/// `safe_entry_path` only looks at the entry name, so like tar's
+/// `unpack_in` this also refuses to descend through a symlink that an
+/// earlier entry may have planted to redirect writes outside `output`.
+/// Such entries fail with `InvalidData`.
+///
+/// # Arguments
+/// * `root` - Canonical path of the output directory
+/// * `output` - Directory where entries are extracted
+/// * `relative` - Sanitized entry path below `output`
+fn create_entry_parents(root: &Path, output: &Path, relative: &Path) -> io::Result<()> {
+    let mut dir = output.to_path_buf();
+    for part in relative.parent().into_iter().flat_map(Path::components) {
+        dir.push(part);
+        match fs::symlink_metadata(&dir) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "refusing to extract {} through symlink {}",
+                        relative.display(),
+                        dir.display()
+                    ),
+                ))
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match fs::create_dir(&dir) {
+                Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+                _ => {}
+            },
+            Err(e) => return Err(e),
+        }
+    }
+    check_inside(root, &output.join(relative))
+}
+
+/// Fails with `InvalidData` unless the parent directory of `path`
+/// resolves to a location inside `root`.
+fn check_inside(root: &Path, path: &Path) -> io::Result<()> {
+    let parent = path.parent().unwrap_or(path);
+    if fs::canonicalize(parent)?.starts_with(root) {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "refusing to extract outside the output directory: {}",
+            path.display()
+        ),
+    ))
+}
+
+/// Validates a tar entry path and removes its first `strip` components.
+///
+/// Absolute paths and `..` components are rejected so that an entry can
+/// never be written outside the output directory (zip-slip). Returns
+/// `Ok(None)` when the path has no components left after stripping.
+///
+/// # Arguments
+/// * `path` - Path of the tar entry
+/// * `strip` - Number of leading components to remove
+fn safe_entry_path(path: &Path, strip: usize) -> io::Result<Option<PathBuf>> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part),
+            Component::CurDir => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsafe path in archive: {}", path.display()),
+                ))
+            }
+        }
+    }
+
+    if parts.len() <= strip {
+        return Ok(None);
+    }
+    Ok(Some(parts[strip..].iter().collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty scratch directory unique to this test run.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rcomp-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Returns the raw path bytes of every entry in a tar archive.
+    fn entry_names(tar: &[u8]) -> Vec<Vec<u8>> {
+        tar::Archive::new(tar)
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path_bytes().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn deep_path_round_trips_in_every_tar_format() {
+        let dir = scratch_dir("deep-path");
+        let relative: PathBuf = (0..5)
+            .map(|depth| format!("nested-directory-level-{}", depth))
+            .chain(["file.txt".to_string()])
+            .collect();
+        assert!(relative.as_os_str().len() > 100);
+        fs::create_dir_all(dir.join(relative.parent().unwrap())).unwrap();
+        fs::write(dir.join(&relative), b"data").unwrap();
+
+        for tar_format in [TarFormat::Gnu, TarFormat::Pax, TarFormat::Ustar] {
+            let options = CompressOptions {
+                tar_format,
+                ..CompressOptions::default()
+            };
+            let tar = write_tar(dir.to_str().unwrap(), Vec::new(), &options, None).unwrap();
+            let paths: Vec<PathBuf> = tar::Archive::new(tar.as_slice())
+                .entries()
+                .unwrap()
+                .map(|entry| entry.unwrap().path().unwrap().into_owned())
+                .collect();
+            assert_eq!(paths, vec![relative.clone()], "{:?}", tar_format);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_file_name_round_trips() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = scratch_dir("non-utf8");
+        let name = OsStr::from_bytes(b"\xff.bin");
+        fs::write(dir.join(name), b"data").unwrap();
+
+        let tar = build_tar(dir.to_str().unwrap()).unwrap();
+        assert_eq!(entry_names(&tar), vec![b"\xff.bin".to_vec()]);
+
+        // A name too long for the header goes through the pax path record
+        let long = [&[b'd'; 120][..], b"/\xff.bin"].concat();
+        let mut archive = tar::Builder::new(Vec::new());
+        let path = dir.join(name);
+        append_tar_file(
+            &mut archive,
+            &path,
+            OsStr::from_bytes(&long).as_ref(),
+            TarFormat::Pax,
+        )
+        .unwrap();
+        assert_eq!(entry_names(&archive.into_inner().unwrap()), vec![long]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn merge_keeps_long_paths_and_link_targets() {
+        let dir = scratch_dir("merge-long");
+        let long_name = PathBuf::from(format!("{}/file.txt", "n".repeat(120)));
+        let long_target = PathBuf::from(format!("{}/target.txt", "t".repeat(120)));
+        let pax_name = PathBuf::from(format!("{}/pax.txt", "p".repeat(120)));
+
+        let mut source = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        source
+            .append_data(&mut header, &long_name, &b"data"[..])
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        source
+            .append_link(&mut header, "link", &long_target)
+            .unwrap();
+        let pax_path = pax_name.to_str().unwrap().as_bytes();
+        source.append_pax_extensions([("path", pax_path)]).unwrap();
+        let mut header = tar::Header::new_ustar();
+        header.set_path("pax.txt").unwrap();
+        header.set_size(3);
+        header.set_cksum();
+        source.append(&header, &b"pax"[..]).unwrap();
+        let tar = source.into_inner().unwrap();
+
+        let input = dir.join("in.tar.gz").to_str().unwrap().to_string();
+        fs::write(
+            &input,
+            algorithm::compress_bytes(Algorithm::Gzip, &tar, 6).unwrap(),
+        )
+        .unwrap();
+        let output = dir.join("out.tar.gz");
+        merge_archives(
+            &[&input],
+            output.to_str().unwrap(),
+            MergeCollision::Error,
+            Level::Numeric(6),
+        )
+        .unwrap();
+
+        let merged =
+            algorithm::decompress_bytes(Algorithm::Gzip, &fs::read(&output).unwrap()).unwrap();
+        let entries: Vec<(PathBuf, Option<PathBuf>)> = tar::Archive::new(merged.as_slice())
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let link = entry.link_name().unwrap().map(|link| link.into_owned());
+                (entry.path().unwrap().into_owned(), link)
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (long_name, None),
+                (PathBuf::from("link"), Some(long_target)),
+                (pax_name, None),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tar sink that records whether each entry was asked to be stored.
+    #[derive(Default)]
+    struct StorageLog {
+        data: Vec<u8>,
+        stored: Vec<bool>,
+    }
+
+    impl Write for StorageLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.data.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl EntryStorage for StorageLog {
+        fn store_uncompressed(&mut self, stored: bool) -> io::Result<bool> {
+            self.stored.push(stored);
+            Ok(stored)
+        }
+    }
+
+    #[test]
+    fn entropy_skip_stores_random_files() {
+        let dir = scratch_dir("entropy-skip");
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let random: Vec<u8> = (0..ENTROPY_SAMPLE_LEN * 2)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        fs::write(dir.join("random.bin"), random).unwrap();
+        fs::write(dir.join("text.txt"), b"text ".repeat(1024)).unwrap();
+
+        for entropy_skip in [false, true] {
+            let options = CompressOptions {
+                entropy_skip,
+                ..CompressOptions::default()
+            };
+            let mut log =
+                write_tar(dir.to_str().unwrap(), StorageLog::default(), &options, None).unwrap();
+            log.stored.sort();
+            let expected = match entropy_skip {
+                true => vec![false, true],
+                false => vec![false, false],
+            };
+            assert_eq!(log.stored, expected);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merged_archives_extract_with_every_algorithm() {
+        let dir = scratch_dir("merge-extract");
+        let mut source = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        source
+            .append_data(&mut header, "file.txt", &b"data"[..])
+            .unwrap();
+        let tar = source.into_inner().unwrap();
+        let source = Algorithm::available().next().unwrap();
+        let input = dir.join(format!("in{}", tar_suffixes(source)[0]));
+        fs::write(&input, algorithm::compress_bytes(source, &tar, 6).unwrap()).unwrap();
+        let input = input.to_str().unwrap().to_string();
+
+        for target in Algorithm::available() {
+            let merged = dir.join(format!("out{}", tar_suffixes(target)[0]));
+            let merged = merged.to_str().unwrap();
+            merge_archives(&[&input], merged, MergeCollision::Error, Level::Numeric(6)).unwrap();
+            let output = dir.join(target.name());
+            decompress_file(merged, output.to_str().unwrap(), &ExtractOptions::default()).unwrap();
+            assert_eq!(
+                fs::read(output.join("file.txt")).unwrap(),
+                b"data",
+                "{}",
+                target
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recompressed_files_decompress_with_every_algorithm() {
+        let dir = scratch_dir("recompress-round-trip");
+        let source = Algorithm::available().next().unwrap();
+        let data = b"round trip data ".repeat(64);
+        let input = dir.join(format!("in.{}", compressed_extension(source, false)));
+        fs::write(&input, algorithm::compress_bytes(source, &data, 6).unwrap()).unwrap();
+
+        for target in Algorithm::available() {
+            let recompressed = dir.join(format!("out.{}", compressed_extension(target, false)));
+            let recompressed = recompressed.to_str().unwrap();
+            recompress_file(input.to_str().unwrap(), recompressed, target, 6).unwrap();
+            let output = dir.join(format!("{}.txt", target));
+            let output = output.to_str().unwrap();
+            decompress_file(recompressed, output, &ExtractOptions::default()).unwrap();
+            assert_eq!(fs::read(output).unwrap(), data, "{}", target);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn self_extracting_stub_offset_counts_bytes() {
+        for name in [None, Some("file.txt"), Some("two\nlines"), Some("it's.txt")] {
+            let stub = self_extracting_stub(name);
+            let offset: usize = stub
+                .split("tail -c +")
+                .nth(1)
+                .and_then(|rest| rest.split(' ').next())
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert_eq!(offset, stub.len() + 1, "{:?}", name);
+        }
+    }
+}
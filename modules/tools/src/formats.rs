@@ -0,0 +1,540 @@
+use crate::EncodeOptions;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::codecs::pnm::{PnmEncoder, PnmSubtype, SampleEncoding};
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::{OnceLock, RwLock};
+
+/// Encodes a decoded image into the bytes of a container format.
+pub type EncodeFn =
+    Box<dyn Fn(&DynamicImage, &EncodeOptions) -> Result<Vec<u8>, Box<dyn Error>> + Send + Sync>;
+
+/// Decodes the bytes of a container format into an image.
+pub type DecodeFn = Box<dyn Fn(&[u8]) -> Result<DynamicImage, Box<dyn Error>> + Send + Sync>;
+
+/// A format known to the image tool, with the callbacks used to encode and decode it.
+pub struct FormatEntry {
+    /// Canonical name used on the command line and in headers
+    pub name: &'static str,
+    /// File extensions (lowercase, without the dot) that map to this format
+    pub extensions: &'static [&'static str],
+    /// Matching `image` crate format, used when detecting formats from content
+    pub image_format: Option<ImageFormat>,
+    /// Whether the `--quality` option applies to this format
+    pub supports_quality: bool,
+    /// Whether the `--alpha-quality` option applies to this format
+    pub supports_alpha_quality: bool,
+    /// Whether `--embed-thumbnail` can store a preview in this format
+    pub supports_thumbnail: bool,
+    /// Whether encoding keeps every pixel, so a round trip can compare them
+    pub lossless: bool,
+    pub encode: EncodeFn,
+    pub decode: DecodeFn,
+}
+
+/// Registry mapping format names and extensions to their encoders and decoders.
+pub struct FormatRegistry {
+    entries: Vec<FormatEntry>,
+}
+
+impl FormatRegistry {
+    /// Creates a registry containing the formats supported out of the box.
+    pub fn with_builtin_formats() -> Self {
+        let mut registry = FormatRegistry {
+            entries: Vec::new(),
+        };
+
+        let mut png = builtin("png", &["png"], ImageFormat::Png);
+        png.encode = Box::new(|img, options| {
+            if options.bit_depth_reduce {
+                if let Some(buffer) = encode_reduced_png(img, options)? {
+                    return Ok(buffer);
+                }
+                info!("Image needs more than 8 bits per pixel; bit depth left unchanged");
+            }
+            let mut buffer = Vec::new();
+            if options.png_compression.is_some() || options.png_filter.is_some() {
+                info!(
+                    "Saving image as PNG with compression {:?} and filter {:?}",
+                    options.png_compression, options.png_filter
+                );
+                let encoder = PngEncoder::new_with_quality(
+                    Cursor::new(&mut buffer),
+                    options.png_compression.unwrap_or_default(),
+                    options.png_filter.unwrap_or_default(),
+                );
+                img.write_with_encoder(encoder)?;
+            } else {
+                info!("Saving image as PNG");
+                img.write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png)?;
+            }
+            Ok(buffer)
+        });
+        registry.register(png);
+
+        let mut jpeg = builtin("jpeg", &["jpg", "jpeg"], ImageFormat::Jpeg);
+        jpeg.supports_quality = true;
+        jpeg.lossless = false;
+        jpeg.supports_thumbnail = true;
+        jpeg.encode = Box::new(|img, options| {
+            let mut buffer = Vec::new();
+            match options.quality {
+                Some(q) => {
+                    info!("Saving image as JPEG with quality {}", q);
+                    img.write_with_encoder(JpegEncoder::new_with_quality(
+                        Cursor::new(&mut buffer),
+                        q,
+                    ))?;
+                }
+                None => {
+                    info!("Saving image as JPEG with default quality");
+                    img.write_to(&mut Cursor::new(&mut buffer), ImageFormat::Jpeg)?;
+                }
+            }
+            #[cfg(feature = "metadata")]
+            if let Some(size) = options.embed_thumbnail {
+                buffer = embed_exif_thumbnail(buffer, img, size)?;
+            }
+            Ok(buffer)
+        });
+        registry.register(jpeg);
+
+        #[cfg(feature = "lossy-webp")]
+        registry.register(lossy_webp());
+        #[cfg(not(feature = "lossy-webp"))]
+        registry.register(builtin("webp", &["webp"], ImageFormat::WebP));
+        registry.register(builtin("bmp", &["bmp"], ImageFormat::Bmp));
+        registry.register(builtin("tiff", &["tif", "tiff"], ImageFormat::Tiff));
+        registry.register(plain_pnm(
+            "ppm",
+            &["ppm", "pnm"],
+            PnmSubtype::Pixmap(SampleEncoding::Ascii),
+        ));
+        registry.register(plain_pnm(
+            "pgm",
+            &["pgm"],
+            PnmSubtype::Graymap(SampleEncoding::Ascii),
+        ));
+        registry.register(plain_pnm(
+            "pbm",
+            &["pbm"],
+            PnmSubtype::Bitmap(SampleEncoding::Ascii),
+        ));
+        registry
+    }
+
+    /// Adds a format, replacing any existing format with the same name.
+    pub fn register(&mut self, entry: FormatEntry) {
+        self.entries.retain(|existing| existing.name != entry.name);
+        self.entries.push(entry);
+    }
+
+    /// Looks up a format by name or by one of its extensions.
+    pub fn get(&self, name: &str) -> Option<&FormatEntry> {
+        let name = name.to_lowercase();
+        self.entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .find(|entry| entry.extensions.contains(&name.as_str()))
+            })
+    }
+
+    /// Looks up a format from the extension of a path.
+    pub fn for_path(&self, path: &Path) -> Option<&FormatEntry> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        self.entries
+            .iter()
+            .find(|entry| entry.extensions.contains(&ext.as_str()))
+    }
+
+    /// Looks up a format by its `image` crate equivalent.
+    pub fn for_image_format(&self, format: ImageFormat) -> Option<&FormatEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.image_format == Some(format))
+    }
+
+    /// Every registered format, in registration order.
+    pub fn entries(&self) -> &[FormatEntry] {
+        &self.entries
+    }
+
+    /// Names and extension aliases accepted by `--format`.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = self.entries.iter().map(|entry| entry.name).collect();
+        for entry in &self.entries {
+            for ext in entry.extensions {
+                if !names.contains(ext) {
+                    names.push(ext);
+                }
+            }
+        }
+        names
+    }
+}
+
+/// Builds an entry for a format handled entirely by the `image` crate.
+fn builtin(
+    name: &'static str,
+    extensions: &'static [&'static str],
+    format: ImageFormat,
+) -> FormatEntry {
+    FormatEntry {
+        name,
+        extensions,
+        image_format: Some(format),
+        supports_quality: false,
+        supports_alpha_quality: false,
+        supports_thumbnail: false,
+        lossless: true,
+        encode: Box::new(move |img, _options| {
+            info!("Saving image as {:?}", format);
+            let mut buffer = Vec::new();
+            img.write_to(&mut Cursor::new(&mut buffer), format)?;
+            Ok(buffer)
+        }),
+        decode: Box::new(move |data| Ok(image::load_from_memory_with_format(data, format)?)),
+    }
+}
+
+/// JPEG quality of thumbnails embedded by `--embed-thumbnail`.
+#[cfg(feature = "metadata")]
+const THUMBNAIL_JPEG_QUALITY: u8 = 75;
+
+/// Largest payload of a JPEG marker segment: its 16-bit length field
+/// counts itself too.
+#[cfg(feature = "metadata")]
+const MAX_SEGMENT_PAYLOAD: usize = u16::MAX as usize - 2;
+
+/// Builds the payload of an APP1 EXIF segment whose IFD1 holds `thumbnail`
+/// as a JPEG preview, in little-endian TIFF layout.
+///
+/// IFD0 only records a normal orientation, which is right because encoding
+/// applies the EXIF orientation of the source; IFD1 holds the Compression
+/// (6, JPEG), JPEGInterchangeFormat and JPEGInterchangeFormatLength tags,
+/// followed by the thumbnail bytes.
+#[cfg(feature = "metadata")]
+fn exif_thumbnail_segment(thumbnail: &[u8]) -> Vec<u8> {
+    const TYPE_SHORT: u16 = 3;
+    const TYPE_LONG: u16 = 4;
+    // TIFF header, then IFD0 with one entry and IFD1 with three
+    const IFD0_OFFSET: u32 = 8;
+    const IFD1_OFFSET: u32 = IFD0_OFFSET + 2 + 12 + 4;
+    const THUMBNAIL_OFFSET: u32 = IFD1_OFFSET + 2 + 3 * 12 + 4;
+
+    let mut tiff = Vec::with_capacity(THUMBNAIL_OFFSET as usize + thumbnail.len());
+    tiff.extend_from_slice(b"II");
+    tiff.extend_from_slice(&42u16.to_le_bytes());
+    tiff.extend_from_slice(&IFD0_OFFSET.to_le_bytes());
+    let mut ifd = |entries: &[(u16, u16, u32)], next: u32| {
+        tiff.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for &(tag, kind, value) in entries {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&kind.to_le_bytes());
+            tiff.extend_from_slice(&1u32.to_le_bytes());
+            // SHORT values sit in the low bytes of the value field
+            tiff.extend_from_slice(&value.to_le_bytes());
+        }
+        tiff.extend_from_slice(&next.to_le_bytes());
+    };
+    // Orientation: normal
+    ifd(&[(0x0112, TYPE_SHORT, 1)], IFD1_OFFSET);
+    ifd(
+        &[
+            // Compression: JPEG
+            (0x0103, TYPE_SHORT, 6),
+            (0x0201, TYPE_LONG, THUMBNAIL_OFFSET),
+            (0x0202, TYPE_LONG, thumbnail.len() as u32),
+        ],
+        0,
+    );
+    tiff.extend_from_slice(thumbnail);
+
+    let mut payload = b"Exif\0\0".to_vec();
+    payload.extend_from_slice(&tiff);
+    payload
+}
+
+/// Inserts an EXIF segment carrying a JPEG thumbnail of `img`, shrunk to at
+/// most `size` pixels on its longest side, into the encoded JPEG `jpeg`. The
+/// segment goes right after the JFIF header, where readers expect it.
+#[cfg(feature = "metadata")]
+fn embed_exif_thumbnail(
+    jpeg: Vec<u8>,
+    img: &DynamicImage,
+    size: u32,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let preview = if img.width().max(img.height()) > size {
+        img.thumbnail(size, size)
+    } else {
+        img.clone()
+    };
+    let preview = DynamicImage::ImageRgb8(preview.to_rgb8());
+    let mut thumbnail = Vec::new();
+    preview.write_with_encoder(JpegEncoder::new_with_quality(
+        Cursor::new(&mut thumbnail),
+        THUMBNAIL_JPEG_QUALITY,
+    ))?;
+    let payload = exif_thumbnail_segment(&thumbnail);
+    if payload.len() > MAX_SEGMENT_PAYLOAD {
+        return Err(format!(
+            "{}x{} thumbnail of {} bytes does not fit in an EXIF segment; use a smaller --embed-thumbnail size",
+            preview.width(),
+            preview.height(),
+            thumbnail.len()
+        )
+        .into());
+    }
+
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return Err("JPEG encoder output does not start with a SOI marker".into());
+    }
+    let mut insert_at = 2;
+    if jpeg[2..].starts_with(&[0xFF, 0xE0]) && jpeg.len() >= 6 {
+        insert_at = 4 + usize::from(u16::from_be_bytes([jpeg[4], jpeg[5]]));
+    }
+    let mut output = Vec::with_capacity(jpeg.len() + payload.len() + 4);
+    output.extend_from_slice(&jpeg[..insert_at]);
+    output.extend_from_slice(&[0xFF, 0xE1]);
+    output.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+    output.extend_from_slice(&payload);
+    output.extend_from_slice(&jpeg[insert_at..]);
+    info!(
+        "Embedded {}x{} EXIF thumbnail ({} bytes)",
+        preview.width(),
+        preview.height(),
+        thumbnail.len()
+    );
+    Ok(output)
+}
+
+/// Gray levels a grayscale PNG can store exactly, as (bit depth, level step).
+const GRAY_STEPS: [(u8, u8); 4] = [(1, 255), (2, 85), (4, 17), (8, 1)];
+
+/// Encodes `img` as PNG at the smallest bit depth that keeps every pixel,
+/// either as opaque grayscale or as a palette of up to 256 RGBA colors,
+/// preferring grayscale when both need the same depth. Returns `None` when
+/// neither fits, i.e. samples wider than 8 bits or more than 256 colors.
+fn encode_reduced_png(
+    img: &DynamicImage,
+    options: &EncodeOptions,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let Some(rgba) = rgba8_exact(img) else {
+        return Ok(None);
+    };
+
+    // Histogram of gray levels and distinct colors
+    let mut gray_levels = [false; 256];
+    let mut all_gray = true;
+    let mut colors = HashSet::new();
+    for pixel in rgba.pixels() {
+        let [r, g, b, a] = pixel.0;
+        if all_gray && a == 255 && r == g && g == b {
+            gray_levels[r as usize] = true;
+        } else {
+            all_gray = false;
+        }
+        colors.insert(pixel.0);
+        if !all_gray && colors.len() > 256 {
+            return Ok(None);
+        }
+    }
+
+    let gray_depth = all_gray
+        .then(|| {
+            GRAY_STEPS.iter().find(|&&(_, step)| {
+                (0..256).all(|level| !gray_levels[level] || level % step as usize == 0)
+            })
+        })
+        .flatten()
+        .map(|&(depth, _)| depth);
+    let palette_depth = match colors.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let (depth, palette) = match gray_depth {
+        Some(depth) if depth <= palette_depth => (depth, None),
+        _ => {
+            // Translucent entries first so the tRNS chunk can stop early
+            let mut palette: Vec<[u8; 4]> = colors.into_iter().collect();
+            palette.sort_unstable_by_key(|color| (color[3] == 255, *color));
+            (palette_depth, Some(palette))
+        }
+    };
+
+    let index: HashMap<[u8; 4], u8> = palette
+        .iter()
+        .flatten()
+        .enumerate()
+        .map(|(i, &color)| (color, i as u8))
+        .collect();
+    let sample = |color: [u8; 4]| match palette {
+        Some(_) => index[&color],
+        None => color[0] / GRAY_STEPS.iter().find(|step| step.0 == depth).unwrap().1,
+    };
+
+    // Pack samples most significant bits first, each row starting on a byte
+    let bits = depth as usize;
+    let per_byte = 8 / bits;
+    let row_bytes = (rgba.width() as usize * bits).div_ceil(8);
+    let mut data = vec![0u8; row_bytes * rgba.height() as usize];
+    for (row, line) in rgba.rows().zip(data.chunks_exact_mut(row_bytes)) {
+        for (x, pixel) in row.enumerate() {
+            let shift = 8 - bits * (x % per_byte + 1);
+            line[x / per_byte] |= sample(pixel.0) << shift;
+        }
+    }
+
+    let mut buffer = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buffer, rgba.width(), rgba.height());
+    encoder.set_depth(png::BitDepth::from_u8(depth).unwrap());
+    encoder.set_compression(match options.png_compression {
+        Some(CompressionType::Fast) => png::Compression::Fast,
+        Some(CompressionType::Best) => png::Compression::High,
+        _ => png::Compression::Balanced,
+    });
+    encoder.set_filter(match options.png_filter {
+        Some(PngFilterType::NoFilter) => png::Filter::NoFilter,
+        Some(PngFilterType::Sub) => png::Filter::Sub,
+        Some(PngFilterType::Up) => png::Filter::Up,
+        Some(PngFilterType::Avg) => png::Filter::Avg,
+        Some(PngFilterType::Paeth) => png::Filter::Paeth,
+        _ => png::Filter::Adaptive,
+    });
+    match &palette {
+        Some(palette) => {
+            info!(
+                "Saving image as {}-bit indexed PNG with {} colors",
+                depth,
+                palette.len()
+            );
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_palette(
+                palette
+                    .iter()
+                    .flat_map(|c| [c[0], c[1], c[2]])
+                    .collect::<Vec<_>>(),
+            );
+            let alphas: Vec<u8> = palette
+                .iter()
+                .map(|c| c[3])
+                .take_while(|&alpha| alpha != 255)
+                .collect();
+            if !alphas.is_empty() {
+                encoder.set_trns(alphas);
+            }
+        }
+        None => {
+            info!("Saving image as {}-bit grayscale PNG", depth);
+            encoder.set_color(png::ColorType::Grayscale);
+        }
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(Some(buffer))
+}
+
+/// Converts `img` to 8-bit RGBA, or returns `None` if that would lose precision.
+fn rgba8_exact(img: &DynamicImage) -> Option<RgbaImage> {
+    let color = img.color();
+    match color.bytes_per_pixel() / color.channel_count() {
+        1 => Some(img.to_rgba8()),
+        // 16-bit samples survive only when they are 8-bit values scaled by 257
+        2 => img
+            .to_rgba16()
+            .iter()
+            .all(|&sample| sample % 257 == 0)
+            .then(|| img.to_rgba8()),
+        _ => None,
+    }
+}
+
+/// Builds the WebP entry on top of libwebp, which unlike the `image` crate
+/// can write lossy WebP. Images are stored losslessly unless a quality or
+/// alpha quality is given; a missing quality falls back to libwebp's 75 and
+/// a missing alpha quality to 100, which keeps mask edges exact.
+#[cfg(feature = "lossy-webp")]
+fn lossy_webp() -> FormatEntry {
+    let mut entry = builtin("webp", &["webp"], ImageFormat::WebP);
+    entry.supports_quality = true;
+    entry.supports_alpha_quality = true;
+    entry.encode = Box::new(|img, options| {
+        let mut buffer = Vec::new();
+        if options.quality.is_none() && options.alpha_quality.is_none() {
+            info!("Saving image as lossless WebP");
+            img.write_to(&mut Cursor::new(&mut buffer), ImageFormat::WebP)?;
+            return Ok(buffer);
+        }
+
+        let mut config =
+            webp::WebPConfig::new().map_err(|_| "Failed to initialize the WebP encoder")?;
+        config.quality = options.quality.map_or(75.0, f32::from);
+        config.alpha_quality = options.alpha_quality.map_or(100, i32::from);
+        info!(
+            "Saving image as lossy WebP with quality {} and alpha quality {}",
+            config.quality, config.alpha_quality
+        );
+        let encoded = if img.color().has_alpha() {
+            let rgba = img.to_rgba8();
+            webp::Encoder::from_rgba(&rgba, img.width(), img.height()).encode_advanced(&config)
+        } else {
+            let rgb = img.to_rgb8();
+            webp::Encoder::from_rgb(&rgb, img.width(), img.height()).encode_advanced(&config)
+        }
+        .map_err(|e| format!("WebP encoding failed: {:?}", e))?;
+        buffer.extend_from_slice(&encoded);
+        Ok(buffer)
+    });
+    entry
+}
+
+/// Builds an entry that writes plain (ASCII) PNM, readable as text and by
+/// most terminal image viewers. The image is converted to RGB, grayscale or
+/// black and white to match `subtype`; any PNM variant can be decoded.
+fn plain_pnm(
+    name: &'static str,
+    extensions: &'static [&'static str],
+    subtype: PnmSubtype,
+) -> FormatEntry {
+    let mut entry = builtin(name, extensions, ImageFormat::Pnm);
+    entry.encode = Box::new(move |img, _options| {
+        info!("Saving image as plain {}", name.to_uppercase());
+        let img = match subtype {
+            PnmSubtype::Graymap(_) => DynamicImage::ImageLuma8(img.to_luma8()),
+            PnmSubtype::Bitmap(_) => {
+                // PBM samples are 1 for black and 0 for white
+                let mut luma = img.to_luma8();
+                for pixel in luma.pixels_mut() {
+                    pixel.0[0] = u8::from(pixel.0[0] < 128);
+                }
+                DynamicImage::ImageLuma8(luma)
+            }
+            _ => DynamicImage::ImageRgb8(img.to_rgb8()),
+        };
+        let mut buffer = Vec::new();
+        img.write_with_encoder(PnmEncoder::new(&mut buffer).with_subtype(subtype))?;
+        Ok(buffer)
+    });
+    // PBM keeps one bit per pixel
+    entry.lossless = !matches!(subtype, PnmSubtype::Bitmap(_));
+    entry
+}
+
+/// Returns the process-wide format registry, populated with the built-in
+/// formats on first use. Additional formats can be registered through the
+/// write lock before encoding.
+pub fn format_registry() -> &'static RwLock<FormatRegistry> {
+    static REGISTRY: OnceLock<RwLock<FormatRegistry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(FormatRegistry::with_builtin_formats()))
+}
@@ -1,163 +1,3934 @@
-use base64::{engine::general_purpose, Engine as _};
-use clap::{Arg, ArgAction, Command};
-use image::{imageops::FilterType, ImageFormat, ImageReader};
-use log::{error, info};
+use base64::{
+    engine::{general_purpose, GeneralPurpose},
+    Engine as _,
+};
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
+use image::codecs::png::{CompressionType, FilterType as PngFilterType};
+use image::metadata::Orientation;
+use image::{
+    imageops::FilterType, DynamicImage, GenericImageView, ImageBuffer, ImageDecoder, ImageError,
+    ImageFormat, ImageReader,
+};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::fs::{read_to_string, File};
-use std::io::{Cursor, Read, Write};
+use std::fmt;
+use std::fs::{read_to_string, File, OpenOptions};
+use std::io::{Cursor, IsTerminal, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-fn encode_image_to_base64(
-    image_path: &str,
-    format: Option<ImageFormat>,
+#[macro_use]
+mod profiling;
+mod algorithm;
+mod checksum;
+mod formats;
+mod units;
+
+use algorithm::Algorithm;
+use checksum::HashAlgorithm;
+use formats::{format_registry, FormatEntry};
+use units::format_size;
+
+/// Compression level used for `encode --compress`.
+const BASE64_COMPRESSION_LEVEL: u32 = 6;
+
+/// Default upper bound on the number of pixels a resize may produce.
+const DEFAULT_MAX_RESIZE_PIXELS: u64 = 100_000_000;
+
+/// Set by `--crlf`; text output lines end with `\r\n` instead of `\n`.
+static CRLF: AtomicBool = AtomicBool::new(false);
+
+/// Line separator of text output: `\r\n` with `--crlf`, otherwise `\n`.
+fn line_ending() -> &'static str {
+    if CRLF.load(Ordering::Relaxed) {
+        "\r\n"
+    } else {
+        "\n"
+    }
+}
+
+/// Returns `text` with its line breaks written as [`line_ending`].
+fn with_line_endings(text: &str) -> Cow<'_, str> {
+    match line_ending() {
+        "\n" => Cow::Borrowed(text),
+        ending => Cow::Owned(text.replace('\n', ending)),
+    }
+}
+
+/// Prints `text` as one or more lines of stdout, honouring `--crlf`.
+fn print_line(text: &str) {
+    print!("{}{}", with_line_endings(text), line_ending());
+}
+
+/// Options controlling how an image is transformed and encoded.
+#[derive(Debug, Clone)]
+struct EncodeOptions {
+    /// Registered name of the output format; inferred from the input path when unset
+    format: Option<&'static str>,
     quality: Option<u8>,
+    /// WebP alpha channel quality, independent of the color `quality`
+    alpha_quality: Option<u8>,
+    /// Crop away a uniform border before any other transform; the value is
+    /// the per-channel tolerance around the top-left pixel color
+    trim: Option<u8>,
     resize: Option<(u32, u32)>,
-    url_safe: bool,
-) -> Result<String, Box<dyn Error>> {
-    info!("Starting to encode image: {}", image_path);
-    let mut img = ImageReader::open(image_path)?.decode()?;
-    info!("Image opened and decoded successfully");
+    max_resize_pixels: u64,
+    /// Downscale so that neither side exceeds this many pixels; applied after `resize`
+    max_dimension: Option<u32>,
+    /// Contrast stretch applied after resizing, before encoding
+    contrast: Option<ContrastStretch>,
+    /// Number of levels per color channel, applied after the contrast stretch
+    posterize: Option<u16>,
+    /// Use error-diffusion dithering when posterizing
+    dither: bool,
+    png_compression: Option<CompressionType>,
+    png_filter: Option<PngFilterType>,
+    /// Write PNG output at the smallest grayscale or palette bit depth that
+    /// holds every pixel exactly
+    bit_depth_reduce: bool,
+    /// Longest side of a preview thumbnail embedded in the output's EXIF data
+    embed_thumbnail: Option<u32>,
+    /// Rotate and flip the decoded image according to its EXIF orientation tag
+    auto_orient: bool,
+    /// In batches, Base64 the file bytes as they are when the input cannot be
+    /// decoded as an image, instead of failing
+    raw_non_images: bool,
+    /// In batches, compute the perceptual hash of every encoded image
+    phash: bool,
+}
 
-    // Resize the image if needed
-    if let Some((width, height)) = resize {
-        info!("Resizing image to {}x{}", width, height);
-        img = img.resize_exact(width, height, FilterType::Lanczos3);
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            format: None,
+            quality: None,
+            alpha_quality: None,
+            trim: None,
+            resize: None,
+            max_resize_pixels: DEFAULT_MAX_RESIZE_PIXELS,
+            max_dimension: None,
+            contrast: None,
+            posterize: None,
+            dither: false,
+            png_compression: None,
+            png_filter: None,
+            bit_depth_reduce: false,
+            embed_thumbnail: None,
+            auto_orient: true,
+            raw_non_images: false,
+            phash: false,
+        }
     }
+}
 
-    let mut buffer = Vec::new();
-    let fmt = format.unwrap_or_else(|| {
-        Path::new(image_path)
-            .extension()
-            .and_then(
-                |ext| match ext.to_str().unwrap_or("").to_lowercase().as_str() {
-                    "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
-                    "png" => Some(ImageFormat::Png),
-                    "tif" | "tiff" => Some(ImageFormat::Tiff),
-                    "webp" => Some(ImageFormat::WebP),
-                    "bmp" => Some(ImageFormat::Bmp),
-                    _ => Some(ImageFormat::Png),
-                },
-            )
-            .unwrap_or(ImageFormat::Png)
-    });
+#[cfg(feature = "watch")]
+fn encode_image_to_base64(
+    image_path: &str,
+    options: &EncodeOptions,
+    url_safe: bool,
+) -> Result<String, Box<dyn Error>> {
+    let encoded_image = encode_image(image_path, options)?;
+    let encoded = base64_engine(url_safe).encode(&encoded_image.data);
+    info!("Image encoded to Base64 successfully");
+    Ok(encoded)
+}
 
-    // Validate quality parameter
-    if quality.is_some() && fmt != ImageFormat::Jpeg {
-        error!("Quality parameter is only supported for JPEG format");
-        return Err("Quality parameter is only supported for JPEG format".into());
+/// Warns when padded Base64 is not a whole number of 4-character groups,
+/// which usually means the input was truncated or mangled on the way in.
+fn check_padded_length(base64_str: &str) {
+    let len = base64_str.len();
+    if !len.is_multiple_of(4) {
+        warn!("Base64 input length {} is not a multiple of 4", len);
+        eprintln!(
+            "Warning: Base64 input is {} characters, not a multiple of 4; it may be truncated",
+            len
+        );
     }
+}
 
-    // Save the image to buffer
-    match fmt {
-        ImageFormat::Jpeg => {
-            info!("Saving image as JPEG with quality {:?}", quality);
-            img.write_to(&mut Cursor::new(&mut buffer), ImageFormat::Jpeg)?;
-        }
-        _ => {
-            info!("Saving image as {:?}", fmt);
-            img.write_to(&mut Cursor::new(&mut buffer), fmt)?;
-        }
-    }
+/// Base64 alphabets tried by `decode --auto-alphabet`, standard ones first.
+const BASE64_ALPHABETS: [(&str, &GeneralPurpose); 4] = [
+    ("standard", &general_purpose::STANDARD),
+    ("standard unpadded", &general_purpose::STANDARD_NO_PAD),
+    ("URL-safe", &general_purpose::URL_SAFE),
+    ("URL-safe unpadded", &general_purpose::URL_SAFE_NO_PAD),
+];
 
-    // Choose Base64 encoding engine
-    let engine = if url_safe {
+fn base64_engine(url_safe: bool) -> &'static GeneralPurpose {
+    if url_safe {
         info!("Using URL-safe Base64 encoding");
         &general_purpose::URL_SAFE
     } else {
         info!("Using standard Base64 encoding");
         &general_purpose::STANDARD
-    };
+    }
+}
 
-    let encoded = engine.encode(&buffer);
-    info!("Image encoded to Base64 successfully");
-    Ok(encoded)
+/// Encoded image bytes together with the properties of the encoded image.
+struct EncodedImage {
+    data: Vec<u8>,
+    format: &'static str,
+    width: u32,
+    height: u32,
 }
 
-fn decode_base64_to_image(
-    base64_str: &str,
-    output_path: &str,
+/// Header line emitted by `encode --with-header`: `WxH FORMAT BYTES`.
+struct PayloadHeader {
+    width: u32,
+    height: u32,
+    format: String,
+    bytes: usize,
+}
+
+impl PayloadHeader {
+    fn for_image(image: &EncodedImage) -> Self {
+        PayloadHeader {
+            width: image.width,
+            height: image.height,
+            format: image.format.to_string(),
+            bytes: image.data.len(),
+        }
+    }
+
+    fn parse(line: &str) -> Result<Self, Box<dyn Error>> {
+        let invalid = || format!("Invalid payload header: '{}'", line.trim());
+        let mut parts = line.split_whitespace();
+        let (width, height) = parts
+            .next()
+            .and_then(|dims| dims.split_once('x'))
+            .ok_or_else(invalid)?;
+        let format = parts.next().ok_or_else(invalid)?.to_string();
+        let bytes = parts.next().ok_or_else(invalid)?;
+        if parts.next().is_some() {
+            return Err(invalid().into());
+        }
+
+        Ok(PayloadHeader {
+            width: width.parse().map_err(|_| invalid())?,
+            height: height.parse().map_err(|_| invalid())?,
+            format,
+            bytes: bytes.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for PayloadHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} {} {}",
+            self.width, self.height, self.format, self.bytes
+        )
+    }
+}
+
+/// Layout of the encode output checked by `--verify-roundtrip`.
+#[derive(Debug, Clone, Copy)]
+struct WrittenLayout {
     url_safe: bool,
+    svg: bool,
+    with_header: bool,
+}
+
+/// Parses `written`, the encode output exactly as it is written out, and
+/// checks that its Base64 decodes to an image in the expected format whose
+/// dimensions match `source`. Pixels are compared with `source` as well
+/// unless the encoding is `lossy`.
+fn verify_roundtrip(
+    written: &str,
+    layout: WrittenLayout,
+    expected: &EncodedImage,
+    source: &DynamicImage,
+    lossy: bool,
 ) -> Result<(), Box<dyn Error>> {
-    info!("Starting to decode Base64 string to image");
-    let engine = if url_safe {
-        info!("Using URL-safe Base64 decoding");
-        &general_purpose::URL_SAFE
+    let fail = |reason: String| -> Box<dyn Error> {
+        error!("Round-trip verification failed: {}", reason);
+        format!("Round-trip verification failed: {}", reason).into()
+    };
+
+    let (header, body) = if layout.with_header {
+        let (line, body) = written
+            .split_once('\n')
+            .ok_or_else(|| fail("payload header line is missing".to_string()))?;
+        let header = PayloadHeader::parse(line.strip_suffix('\r').unwrap_or(line))
+            .map_err(|e| fail(format!("payload header is invalid: {}", e)))?;
+        (Some(header), body)
     } else {
-        info!("Using standard Base64 decoding");
-        &general_purpose::STANDARD
+        (None, written)
     };
+    let data = if layout.svg {
+        // The SVG wrapper always embeds a standard Base64 data URI
+        let data = body
+            .split_once("href=\"data:")
+            .and_then(|(_, uri)| uri.split_once(";base64,"))
+            .and_then(|(_, data)| data.split_once('"'))
+            .map(|(data, _)| data)
+            .ok_or_else(|| fail("SVG has no Base64 data URI".to_string()))?;
+        general_purpose::STANDARD.decode(data)
+    } else {
+        base64_engine(layout.url_safe).decode(body.trim())
+    }
+    .map_err(|e| fail(format!("Base64 is invalid: {}", e)))?;
 
-    // Decode the Base64 string
-    let decoded_data = engine.decode(base64_str.trim())?;
-    info!("Base64 string decoded successfully");
+    let registry = format_registry().read().unwrap();
+    let entry = registry
+        .get(expected.format)
+        .ok_or_else(|| fail(format!("format {} is not registered", expected.format)))?;
+    if let Some(format) = entry.image_format {
+        let detected = image::guess_format(&data).ok();
+        if detected != Some(format) {
+            return Err(fail(format!(
+                "data is detected as {:?}, expected {}",
+                detected, entry.name
+            )));
+        }
+    }
 
-    // Open the decoded data as an image
-    let img = ImageReader::new(Cursor::new(decoded_data))
-        .with_guessed_format()?
-        .decode()?;
-    info!("Image data decoded successfully");
+    let img = (entry.decode)(&data).map_err(|e| fail(format!("decode failed: {}", e)))?;
+    if img.dimensions() != source.dimensions() {
+        return Err(fail(format!(
+            "decoded image is {}x{}, expected {}x{}",
+            img.width(),
+            img.height(),
+            source.width(),
+            source.height()
+        )));
+    }
+    if let Some(header) = header {
+        if (header.width, header.height) != img.dimensions() || header.bytes != data.len() {
+            return Err(fail(format!(
+                "header declares {} but the image is {}x{} ({} bytes)",
+                header,
+                img.width(),
+                img.height(),
+                data.len()
+            )));
+        }
+    }
+    if !lossy && !same_pixels(source, &img) {
+        return Err(fail(
+            "decoded pixels differ from the source image".to_string(),
+        ));
+    }
 
-    // Synchronously create and write to the output file
-    let mut output_file = File::create(output_path)?;
-    img.write_to(&mut output_file, ImageFormat::Png)?;
-    info!("Image saved to {}", output_path);
+    info!(
+        "Round-trip verified: {} {}x{}{}",
+        entry.name,
+        img.width(),
+        img.height(),
+        if lossy {
+            " (lossy, pixels not compared)"
+        } else {
+            ""
+        }
+    );
+    Ok(())
+}
+
+/// Returns true if `decoded` holds the pixels of `source` at 8 bits per
+/// sample. Channels the encoder dropped, such as alpha in BMP or color in
+/// PGM, are dropped from `source` the same way before comparing.
+fn same_pixels(source: &DynamicImage, decoded: &DynamicImage) -> bool {
+    let channels = decoded.color().channel_count();
+    let source = if channels < source.color().channel_count() {
+        match channels {
+            1 => DynamicImage::ImageLuma8(source.to_luma8()),
+            2 => DynamicImage::ImageLumaA8(source.to_luma_alpha8()),
+            _ => DynamicImage::ImageRgb8(source.to_rgb8()),
+        }
+        .to_rgba8()
+    } else {
+        source.to_rgba8()
+    };
+    source == decoded.to_rgba8()
+}
+
+/// Reads the bit depth and color type from the IHDR chunk of PNG `data`.
+fn png_layout(data: &[u8]) -> Option<(u8, &'static str)> {
+    // Signature (8), chunk length (4), "IHDR" (4), width (4), height (4)
+    if data.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let color = match data.get(25)? {
+        0 => "grayscale",
+        2 => "RGB",
+        3 => "indexed",
+        4 => "grayscale with alpha",
+        6 => "RGBA",
+        _ => "unknown color type",
+    };
+    Some((*data.get(24)?, color))
+}
+
+/// Returns the MIME type used in data URIs for a registered format.
+fn format_mime_type(format: &str) -> &'static str {
+    format_registry()
+        .read()
+        .unwrap()
+        .get(format)
+        .and_then(|entry| entry.image_format)
+        .map_or("application/octet-stream", |f| f.to_mime_type())
+}
+
+/// Escapes the characters that are not allowed verbatim in an XML attribute.
+fn escape_xml_attr(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Wraps an encoded image in a minimal SVG document that embeds it as a
+/// standard Base64 data URI, sized to the image.
+fn svg_wrapper(image: &EncodedImage) -> String {
+    let data_uri = format!(
+        "data:{};base64,{}",
+        format_mime_type(image.format),
+        general_purpose::STANDARD.encode(&image.data)
+    );
+    format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" ",
+            "viewBox=\"0 0 {w} {h}\">",
+            "<image href=\"{href}\" width=\"{w}\" height=\"{h}\"/>",
+            "</svg>\n"
+        ),
+        w = image.width,
+        h = image.height,
+        href = escape_xml_attr(&data_uri)
+    )
+}
+
+/// Gaussian blur sigma applied to `--lqip` placeholders, in output pixels.
+const LQIP_BLUR_SIGMA: f32 = 1.0;
+
+/// JPEG quality of opaque `--lqip` placeholders; they are blurred anyway.
+const LQIP_JPEG_QUALITY: u8 = 50;
+
+/// Builds a low-quality image placeholder (LQIP) for "blur-up" loading as a
+/// standard Base64 data URI. The image is shrunk so its longest side is at
+/// most `size` pixels, blurred, and saved as JPEG, or as PNG when it has an
+/// alpha channel.
+fn lqip_data_uri(img: &DynamicImage, size: u32) -> Result<String, Box<dyn Error>> {
+    let placeholder = img.thumbnail(size, size).blur(LQIP_BLUR_SIGMA);
+    let (format, options) = if placeholder.color().has_alpha() {
+        ("png", EncodeOptions::default())
+    } else {
+        (
+            "jpeg",
+            EncodeOptions {
+                quality: Some(LQIP_JPEG_QUALITY),
+                ..Default::default()
+            },
+        )
+    };
+    let registry = format_registry().read().unwrap();
+    let entry = registry
+        .get(format)
+        .ok_or_else(|| format!("{} format is not registered", format))?;
+    let data = (entry.encode)(&placeholder, &options)?;
+    info!(
+        "Built {}x{} LQIP placeholder ({} bytes)",
+        placeholder.width(),
+        placeholder.height(),
+        data.len()
+    );
+    Ok(format!(
+        "data:{};base64,{}",
+        format_mime_type(entry.name),
+        general_purpose::STANDARD.encode(data)
+    ))
+}
+
+/// A scaled copy of the image requested by `encode --variants` or `--widths`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Variant {
+    /// Pixel density descriptor, labelled like `2x`
+    Density(f32),
+    /// Target width in pixels, labelled like `640w`
+    Width(u32),
+}
+
+impl Variant {
+    /// Key of the variant in the JSON output and `{label}` in file patterns,
+    /// matching the descriptors of an HTML `srcset`.
+    fn label(self) -> String {
+        match self {
+            Variant::Density(density) => format!("{}x", density),
+            Variant::Width(width) => format!("{}w", width),
+        }
+    }
+}
+
+fn parse_density_variant(value: &str) -> Result<Variant, String> {
+    let invalid = || format!("expected a density like 2x or 1.5x, got '{}'", value);
+    let density: f32 = value
+        .strip_suffix(['x', 'X'])
+        .ok_or_else(invalid)?
+        .parse()
+        .map_err(|_| invalid())?;
+    if !(density.is_finite() && density > 0.0) {
+        return Err(invalid());
+    }
+    Ok(Variant::Density(density))
+}
+
+fn parse_width_variant(value: &str) -> Result<Variant, String> {
+    match value.trim_end_matches(['w', 'W']).parse() {
+        Ok(width) if width > 0 => Ok(Variant::Width(width)),
+        _ => Err(format!(
+            "expected a width in pixels like 640, got '{}'",
+            value
+        )),
+    }
+}
+
+fn parse_variant_pattern(value: &str) -> Result<String, String> {
+    if value.contains("{label}") || value.contains("{width}") {
+        Ok(value.to_string())
+    } else {
+        Err("pattern must contain {label} or {width} so variants get distinct files".to_string())
+    }
+}
+
+/// Expands the `{label}`, `{width}` and `{height}` placeholders of a
+/// `--variant-output` pattern.
+fn variant_output_path(pattern: &str, label: &str, img: &DynamicImage) -> String {
+    pattern
+        .replace("{label}", label)
+        .replace("{width}", &img.width().to_string())
+        .replace("{height}", &img.height().to_string())
+}
+
+/// Scales `img` once per variant, in the order given.
+///
+/// Density variants are relative to the largest density requested, which
+/// keeps the size of `img`; so `1x,2x` of a 1200px image gives 600px and
+/// 1200px. Width variants keep the aspect ratio. Variants are never
+/// upscaled: one asking for more pixels than `img` has gets `img` as is.
+/// Downscaling uses the Lanczos3 filter, like `--resize`.
+fn scale_variants(
+    img: &DynamicImage,
+    variants: &[Variant],
+) -> Result<Vec<(String, DynamicImage)>, Box<dyn Error>> {
+    let max_density = variants
+        .iter()
+        .filter_map(|variant| match variant {
+            Variant::Density(density) => Some(*density),
+            Variant::Width(_) => None,
+        })
+        .fold(0.0, f32::max);
+
+    let mut scaled: Vec<(String, DynamicImage)> = Vec::with_capacity(variants.len());
+    for &variant in variants {
+        let label = variant.label();
+        if scaled.iter().any(|(existing, _)| *existing == label) {
+            return Err(format!("variant {} is listed more than once", label).into());
+        }
+        let scale = match variant {
+            Variant::Density(density) => f64::from(density / max_density),
+            Variant::Width(width) => f64::from(width) / f64::from(img.width().max(1)),
+        };
+        let width = (f64::from(img.width()) * scale).round().max(1.0) as u32;
+        let height = (f64::from(img.height()) * scale).round().max(1.0) as u32;
+        let variant_img = if width >= img.width() {
+            if width > img.width() {
+                warn!(
+                    "Variant {} is wider than the {}px image; not upscaling",
+                    label,
+                    img.width()
+                );
+            }
+            img.clone()
+        } else {
+            info!("Scaling variant {} to {}x{}", label, width, height);
+            let _span = phase_span!("resize", width, height);
+            img.resize_exact(width, height, FilterType::Lanczos3)
+        };
+        scaled.push((label, variant_img));
+    }
+    Ok(scaled)
+}
+
+/// Language of the array written by `encode --as-source`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SourceLanguage {
+    C,
+    Rust,
+}
+
+impl SourceLanguage {
+    /// Identifier used when `--var-name` is not given.
+    fn default_var_name(self) -> &'static str {
+        match self {
+            SourceLanguage::C => "image",
+            SourceLanguage::Rust => "IMAGE",
+        }
+    }
+}
+
+/// Number of array elements per line of generated source.
+const SOURCE_BYTES_PER_LINE: usize = 12;
+
+/// Renders the raw bytes of an encoded image as a C or Rust array named
+/// `var_name`, followed by a `<var_name>_len` (C) or `<var_name>_LEN` (Rust)
+/// constant holding its length.
+fn source_array(image: &EncodedImage, language: SourceLanguage, var_name: &str) -> String {
+    let mut body = String::new();
+    for line in image.data.chunks(SOURCE_BYTES_PER_LINE) {
+        let bytes: Vec<String> = line.iter().map(|b| format!("0x{:02x}", b)).collect();
+        body.push_str(&format!("    {},\n", bytes.join(", ")));
+    }
+    let comment = format!(
+        "{} bytes of {} image data, {}x{}",
+        image.data.len(),
+        image.format.to_uppercase(),
+        image.width,
+        image.height
+    );
+    match language {
+        SourceLanguage::C => format!(
+            "/* {} */\nconst unsigned char {name}[] = {{\n{}}};\nconst unsigned int {name}_len = {};\n",
+            comment,
+            body,
+            image.data.len(),
+            name = var_name
+        ),
+        SourceLanguage::Rust => format!(
+            "/// {}\npub static {name}: &[u8] = &[\n{}];\npub const {name}_LEN: usize = {};\n",
+            comment,
+            body,
+            image.data.len(),
+            name = var_name
+        ),
+    }
+}
+
+/// Parses a C/Rust identifier for `--var-name`.
+fn parse_identifier(value: &str) -> Result<String, String> {
+    let mut chars = value.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(value.to_string())
+    } else {
+        Err(format!("'{}' is not a valid identifier", value))
+    }
+}
+
+/// Errors raised when encode options are invalid for the requested output.
+enum EncodeError {
+    QualityNotAllowed(&'static str),
+    QualityOutOfRange(u8),
+    AlphaQualityNotAllowed(&'static str),
+    ZeroResize(u32, u32),
+    ResizeTooLarge {
+        width: u32,
+        height: u32,
+        max_pixels: u64,
+    },
+    PngOptionsNotAllowed(&'static str),
+    ThumbnailNotAllowed(&'static str),
+    OutputTooLarge {
+        base64_len: u64,
+        max_bytes: u64,
+    },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::QualityNotAllowed(format) => {
+                write!(
+                    f,
+                    "Quality parameter is not supported for {} format",
+                    format
+                )
+            }
+            EncodeError::QualityOutOfRange(quality) => {
+                write!(f, "Quality must be between 1 and 100, got {}", quality)
+            }
+            // WebP only rejects it when built without libwebp
+            EncodeError::AlphaQualityNotAllowed("webp") => write!(
+                f,
+                "Alpha quality for WebP requires a build with the lossy-webp feature"
+            ),
+            EncodeError::AlphaQualityNotAllowed(format) => write!(
+                f,
+                "Alpha quality is only supported for WebP format, not {}",
+                format
+            ),
+            EncodeError::ZeroResize(width, height) => write!(
+                f,
+                "Resize dimensions must be non-zero, got {}x{}",
+                width, height
+            ),
+            EncodeError::ResizeTooLarge {
+                width,
+                height,
+                max_pixels,
+            } => write!(
+                f,
+                "Resize to {}x{} ({} pixels) exceeds the maximum of {} pixels",
+                width,
+                height,
+                u64::from(*width) * u64::from(*height),
+                max_pixels
+            ),
+            EncodeError::PngOptionsNotAllowed(format) => write!(
+                f,
+                "PNG compression, filter and bit depth options are only supported for PNG format, not {}",
+                format
+            ),
+            EncodeError::ThumbnailNotAllowed(format) => write!(
+                f,
+                "Embedded thumbnails are only supported for JPEG format, not {}",
+                format
+            ),
+            EncodeError::OutputTooLarge {
+                base64_len,
+                max_bytes,
+            } => write!(
+                f,
+                "Base64 output would be {} bytes, exceeding --max-output-bytes {}",
+                base64_len, max_bytes
+            ),
+        }
+    }
+}
+
+// `main` reports errors through `Debug`, so show the readable message there too.
+impl fmt::Debug for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Error for EncodeError {}
+
+/// Length of the padded Base64 encoding of `len` bytes: every started group
+/// of 3 input bytes becomes 4 characters, so `ceil(len / 3) * 4`.
+fn base64_encoded_len(len: usize) -> u64 {
+    (len as u64).div_ceil(3) * 4
+}
+
+/// Fails if the Base64 encoding of `data_len` bytes would exceed `max_bytes`,
+/// so the string is never allocated.
+fn check_output_size(data_len: usize, max_bytes: Option<u64>) -> Result<(), EncodeError> {
+    let base64_len = base64_encoded_len(data_len);
+    match max_bytes {
+        Some(max_bytes) if base64_len > max_bytes => {
+            error!(
+                "Base64 output of {} bytes exceeds the limit of {} bytes",
+                base64_len, max_bytes
+            );
+            Err(EncodeError::OutputTooLarge {
+                base64_len,
+                max_bytes,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_resize(width: u32, height: u32, max_pixels: u64) -> Result<(), EncodeError> {
+    if width == 0 || height == 0 {
+        error!("Invalid resize dimensions {}x{}", width, height);
+        return Err(EncodeError::ZeroResize(width, height));
+    }
+    if u64::from(width) * u64::from(height) > max_pixels {
+        error!("Resize to {}x{} exceeds pixel limit", width, height);
+        return Err(EncodeError::ResizeTooLarge {
+            width,
+            height,
+            max_pixels,
+        });
+    }
+    Ok(())
+}
+
+fn validate_quality(quality: Option<u8>, format: &FormatEntry) -> Result<(), EncodeError> {
+    match quality {
+        Some(_) if !format.supports_quality => {
+            error!("Quality parameter is not supported for {}", format.name);
+            Err(EncodeError::QualityNotAllowed(format.name))
+        }
+        Some(q) if !(1..=100).contains(&q) => {
+            error!("Quality {} is out of range", q);
+            Err(EncodeError::QualityOutOfRange(q))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Pixel layouts accepted for headerless raw pixel input and `decode --as`.
+#[derive(Debug, Clone, Copy)]
+enum PixelFormat {
+    Rgb8,
+    Rgba8,
+    Luma8,
+    LumaA8,
+}
+
+impl PixelFormat {
+    /// Names accepted on the command line.
+    const NAMES: [&'static str; 4] = ["rgb8", "rgba8", "luma8", "luma-a8"];
+
+    fn parse(name: &str) -> Self {
+        match name {
+            "rgb8" => PixelFormat::Rgb8,
+            "rgba8" => PixelFormat::Rgba8,
+            "luma8" => PixelFormat::Luma8,
+            "luma-a8" => PixelFormat::LumaA8,
+            _ => unreachable!(),
+        }
+    }
 
+    fn channels(self) -> u64 {
+        match self {
+            PixelFormat::Rgb8 => 3,
+            PixelFormat::Rgba8 => 4,
+            PixelFormat::Luma8 => 1,
+            PixelFormat::LumaA8 => 2,
+        }
+    }
+
+    /// Converts an image to this layout. Converting to a layout without
+    /// alpha drops the alpha channel; converting to one with alpha adds an
+    /// opaque one.
+    fn convert(self, img: DynamicImage) -> DynamicImage {
+        match self {
+            PixelFormat::Rgb8 => DynamicImage::ImageRgb8(img.to_rgb8()),
+            PixelFormat::Rgba8 => DynamicImage::ImageRgba8(img.to_rgba8()),
+            PixelFormat::Luma8 => DynamicImage::ImageLuma8(img.to_luma8()),
+            PixelFormat::LumaA8 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        }
+    }
+}
+
+/// Options controlling how a Base64 image is decoded.
+#[derive(Debug, Clone, Copy, Default)]
+struct DecodeOptions {
+    /// Registered format to decode the data as, skipping content detection
+    input_format: Option<&'static str>,
+    /// Pixel layout the image is converted to before it is written
+    convert_to: Option<PixelFormat>,
+    /// Retry with the other Base64 alphabets when the selected one fails
+    auto_alphabet: bool,
+    /// Write `<output>.json` describing the decoded image
+    sidecar: bool,
+    /// Registered format the output is written in instead of PNG
+    output_format: Option<&'static str>,
+    /// Fail unless the decoded image has these dimensions
+    expect_size: Option<(u32, u32)>,
+    /// Fail unless the data is detected as this registered format
+    expect_format: Option<&'static str>,
+    /// Encode the output straight into a memory map of the file
+    mmap: bool,
+}
+
+/// How `encode --normalize` / `--auto-level` stretch contrast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ContrastStretch {
+    /// Stretch the darkest and brightest sample over all color channels to
+    /// the full range, preserving the color balance
+    Global,
+    /// Stretch every color channel to the full range independently, which
+    /// also removes color casts
+    PerChannel,
+}
+
+/// Returns the 8-bit samples of an image together with the number of
+/// channels per pixel and how many of them are color (non-alpha) channels.
+///
+/// Images that are not 8 bits per channel are converted to RGBA8 first.
+fn samples_u8_mut(img: &mut DynamicImage) -> (&mut [u8], usize, usize) {
+    if !matches!(
+        img,
+        DynamicImage::ImageLuma8(_)
+            | DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageRgb8(_)
+            | DynamicImage::ImageRgba8(_)
+    ) {
+        warn!("Converting {:?} image to RGBA8 for processing", img.color());
+        *img = DynamicImage::ImageRgba8(img.to_rgba8());
+    }
+    match img {
+        DynamicImage::ImageLuma8(buf) => (&mut **buf, 1, 1),
+        DynamicImage::ImageLumaA8(buf) => (&mut **buf, 2, 1),
+        DynamicImage::ImageRgb8(buf) => (&mut **buf, 3, 3),
+        DynamicImage::ImageRgba8(buf) => (&mut **buf, 4, 3),
+        _ => unreachable!(),
+    }
+}
+
+/// Stretches the sample range of an image to the full 0-255 range.
+///
+/// Alpha is left untouched.
+fn stretch_contrast(img: &mut DynamicImage, stretch: ContrastStretch) {
+    let (samples, channels, color_channels) = samples_u8_mut(img);
+
+    // (darkest, brightest) sample of each color channel
+    let mut ranges = vec![(u8::MAX, u8::MIN); color_channels];
+    for pixel in samples.chunks_exact(channels) {
+        for ((low, high), &sample) in ranges.iter_mut().zip(pixel) {
+            *low = (*low).min(sample);
+            *high = (*high).max(sample);
+        }
+    }
+    if stretch == ContrastStretch::Global {
+        let low = ranges.iter().map(|r| r.0).min().unwrap();
+        let high = ranges.iter().map(|r| r.1).max().unwrap();
+        ranges.fill((low, high));
+    }
+
+    // One lookup table per color channel; flat channels are left as they are
+    let tables: Vec<[u8; 256]> = ranges
+        .iter()
+        .map(|&(low, high)| {
+            let mut table = [0u8; 256];
+            for (value, entry) in table.iter_mut().enumerate() {
+                let value = value as u8;
+                *entry = if high > low {
+                    let offset = u32::from(value.clamp(low, high) - low);
+                    (offset * 255 / u32::from(high - low)) as u8
+                } else {
+                    value
+                };
+            }
+            table
+        })
+        .collect();
+    for pixel in samples.chunks_exact_mut(channels) {
+        for (sample, table) in pixel.iter_mut().zip(&tables) {
+            *sample = table[usize::from(*sample)];
+        }
+    }
+}
+
+/// Reduces every color channel to `levels` evenly spaced values.
+///
+/// With `dither`, Floyd-Steinberg error diffusion spreads the quantization
+/// error to neighbouring pixels to avoid banding. Alpha is left untouched.
+fn posterize(img: &mut DynamicImage, levels: u16, dither: bool) {
+    let width = img.width() as usize;
+    if width == 0 {
+        return;
+    }
+    let (samples, channels, color_channels) = samples_u8_mut(img);
+    let steps = f32::from(levels - 1);
+    let quantize = |value: f32| (value.clamp(0.0, 255.0) / 255.0 * steps).round() / steps * 255.0;
+
+    if !dither {
+        for pixel in samples.chunks_exact_mut(channels) {
+            for sample in &mut pixel[..color_channels] {
+                *sample = quantize(f32::from(*sample)) as u8;
+            }
+        }
+        return;
+    }
+
+    // Errors carried into the current and the next row, per color sample
+    let mut current = vec![0f32; width * color_channels];
+    let mut next = vec![0f32; width * color_channels];
+    for row in samples.chunks_exact_mut(width * channels) {
+        for x in 0..width {
+            for c in 0..color_channels {
+                let e = x * color_channels + c;
+                let sample = &mut row[x * channels + c];
+                let wanted = f32::from(*sample) + current[e];
+                let quantized = quantize(wanted);
+                *sample = quantized as u8;
+
+                let error = wanted - quantized;
+                if x + 1 < width {
+                    current[e + color_channels] += error * 7.0 / 16.0;
+                    next[e + color_channels] += error / 16.0;
+                }
+                if x > 0 {
+                    next[e - color_channels] += error * 3.0 / 16.0;
+                }
+                next[e] += error * 5.0 / 16.0;
+            }
+        }
+        std::mem::swap(&mut current, &mut next);
+        next.fill(0.0);
+    }
+}
+
+/// Splits an image into its color channels as RGB and its alpha channel as
+/// an 8-bit grayscale image of the same size.
+fn split_alpha(img: &DynamicImage) -> (DynamicImage, DynamicImage) {
+    let rgba = img.to_rgba8();
+    let alpha = ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+        image::Luma([rgba.get_pixel(x, y)[3]])
+    });
+    (
+        DynamicImage::ImageRgb8(img.to_rgb8()),
+        DynamicImage::ImageLuma8(alpha),
+    )
+}
+
+/// Encodes the color and alpha channels of an image as two separate images
+/// and returns their Base64 strings as `(rgb, alpha)`.
+///
+/// Fails for images without an alpha channel.
+fn encode_split_alpha(
+    img: DynamicImage,
+    image_path: &str,
+    options: &EncodeOptions,
+    url_safe: bool,
+    max_output_bytes: Option<u64>,
+) -> Result<(String, String), Box<dyn Error>> {
+    if !img.color().has_alpha() {
+        error!("Image has no alpha channel to split");
+        return Err(format!(
+            "--split-alpha needs an image with an alpha channel, but {} is {:?}",
+            image_path,
+            img.color()
+        )
+        .into());
+    }
+
+    // Transform once so both images get the same size; alpha is never
+    // touched by the contrast and posterize steps
+    let img = transform_image(img, options)?;
+    let (rgb, alpha) = split_alpha(&img);
+    info!(
+        "Split {}x{} image into RGB and alpha",
+        img.width(),
+        img.height()
+    );
+
+    let encode = |part: &DynamicImage| -> Result<String, Box<dyn Error>> {
+        let encoded = encode_transformed_image(part, image_path, options)?;
+        check_output_size(encoded.data.len(), max_output_bytes)?;
+        Ok(base64_engine(url_safe).encode(&encoded.data))
+    };
+    Ok((encode(&rgb)?, encode(&alpha)?))
+}
+
+/// Writes the output of `encode --split-alpha`: `<output>.rgb.b64` and
+/// `<output>.alpha.b64` (a `.b64` extension on `output` is dropped first),
+/// or a two-element JSON array `[rgb, alpha]` with `json`.
+fn write_split_alpha_output(
+    rgb: &str,
+    alpha: &str,
+    json: bool,
+    output: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    match (json, output) {
+        (true, Some(output_path)) => {
+            std::fs::write(output_path, serde_json::to_string(&[rgb, alpha])?)?;
+            println!("RGB and alpha images saved to {}", output_path);
+        }
+        (true, None) => print_line(&serde_json::to_string(&[rgb, alpha])?),
+        (false, Some(output_path)) => {
+            let stem = output_path.strip_suffix(".b64").unwrap_or(output_path);
+            let (rgb_path, alpha_path) =
+                (format!("{}.rgb.b64", stem), format!("{}.alpha.b64", stem));
+            std::fs::write(&rgb_path, rgb)?;
+            std::fs::write(&alpha_path, alpha)?;
+            println!(
+                "RGB and alpha images saved to {} and {}",
+                rgb_path, alpha_path
+            );
+        }
+        (false, None) => {
+            print_line(rgb);
+            print_line(alpha);
+        }
+    }
+    Ok(())
+}
+
+/// Builds an image from a headerless buffer of 8-bit pixels.
+fn load_raw_pixels(
+    raw_path: &str,
+    width: u32,
+    height: u32,
+    pixel_format: PixelFormat,
+) -> Result<DynamicImage, Box<dyn Error>> {
+    info!(
+        "Reading raw {:?} pixels ({}x{}) from {}",
+        pixel_format, width, height, raw_path
+    );
+    let data = std::fs::read(raw_path)?;
+    let expected = u64::from(width) * u64::from(height) * pixel_format.channels();
+    if data.len() as u64 != expected {
+        error!("Raw pixel data length does not match dimensions");
+        return Err(format!(
+            "Raw pixel data is {} bytes but {}x{} {:?} requires {} bytes",
+            data.len(),
+            width,
+            height,
+            pixel_format,
+            expected
+        )
+        .into());
+    }
+
+    let mismatch = || "Raw pixel buffer does not match the requested dimensions";
+    Ok(match pixel_format {
+        PixelFormat::Rgb8 => DynamicImage::ImageRgb8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(mismatch)?,
+        ),
+        PixelFormat::Rgba8 => DynamicImage::ImageRgba8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(mismatch)?,
+        ),
+        PixelFormat::Luma8 => DynamicImage::ImageLuma8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(mismatch)?,
+        ),
+        PixelFormat::LumaA8 => DynamicImage::ImageLumaA8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(mismatch)?,
+        ),
+    })
+}
+
+#[cfg(feature = "watch")]
+fn encode_image(image_path: &str, options: &EncodeOptions) -> Result<EncodedImage, Box<dyn Error>> {
+    info!("Starting to encode image: {}", image_path);
+    let img = open_image(image_path, options.auto_orient)?;
+    encode_decoded_image(img, image_path, options)
+}
+
+/// Opens and decodes the image file at `image_path`.
+///
+/// With `auto_orient` the EXIF orientation tag (usually found in JPEG and
+/// TIFF files from cameras and phones) is applied, so the pixels come out
+/// the way the image is meant to be viewed. Images without the tag are
+/// returned unchanged either way.
+fn open_image(image_path: &str, auto_orient: bool) -> Result<DynamicImage, Box<dyn Error>> {
+    let size = std::fs::metadata(image_path)?.len();
+    if size == 0 {
+        error!("{} is empty", image_path);
+        return Err(format!("empty input file: {}", image_path).into());
+    }
+
+    let img = {
+        let _span = phase_span!("decode", path = image_path);
+        let decode = || -> Result<_, Box<dyn Error>> {
+            let mut decoder = ImageReader::open(image_path)?.into_decoder()?;
+            let orientation = decoder.orientation()?;
+            Ok((orientation, DynamicImage::from_decoder(decoder)?))
+        };
+        let (orientation, mut img) = decode().map_err(|e| describe_decode_error(e, size))?;
+        if auto_orient && orientation != Orientation::NoTransforms {
+            info!("Applying EXIF orientation {:?}", orientation);
+            img.apply_orientation(orientation);
+        }
+        img
+    };
+    info!("Image opened and decoded successfully");
+    Ok(img)
+}
+
+/// Applies the requested transforms to a decoded image and encodes it.
+/// `image_path` is only used to infer the output format from its extension.
+fn encode_decoded_image(
+    img: DynamicImage,
+    image_path: &str,
+    options: &EncodeOptions,
+) -> Result<EncodedImage, Box<dyn Error>> {
+    let img = transform_image(img, options)?;
+    encode_transformed_image(&img, image_path, options)
+}
+
+/// Applies the resize, contrast and posterize options to a decoded image.
+fn transform_image(
+    mut img: DynamicImage,
+    options: &EncodeOptions,
+) -> Result<DynamicImage, Box<dyn Error>> {
+    let EncodeOptions {
+        trim,
+        resize,
+        max_resize_pixels,
+        max_dimension,
+        contrast,
+        posterize: posterize_levels,
+        dither,
+        ..
+    } = *options;
+
+    // Trim first so resizing and contrast only see the content
+    if let Some(tolerance) = trim {
+        if img.width() > 0 && img.height() > 0 {
+            let _span = phase_span!("trim", tolerance);
+            match trim_bounds(&img, tolerance) {
+                Some((x, y, width, height)) => {
+                    info!(
+                        "Trimming {}x{} border to {}x{} at ({}, {})",
+                        img.width(),
+                        img.height(),
+                        width,
+                        height,
+                        x,
+                        y
+                    );
+                    img = img.crop_imm(x, y, width, height);
+                }
+                None => warn!("Image is a single uniform color; not trimming"),
+            }
+        }
+    }
+
+    // Validate resize dimensions before allocating the resized image
+    if let Some((width, height)) = resize {
+        validate_resize(width, height, max_resize_pixels)?;
+    }
+
+    // Resize the image if needed
+    if let Some((width, height)) = resize {
+        info!("Resizing image to {}x{}", width, height);
+        let _span = phase_span!("resize", width, height);
+        img = img.resize_exact(width, height, FilterType::Lanczos3);
+    }
+
+    // Cap the longest side without ever upscaling
+    if let Some(max) = max_dimension {
+        if img.width().max(img.height()) > max {
+            info!(
+                "Downscaling {}x{} to fit within {}px",
+                img.width(),
+                img.height(),
+                max
+            );
+            let _span = phase_span!("resize", max_dimension = max);
+            img = img.resize(max, max, FilterType::Lanczos3);
+        }
+    }
+
+    if let Some(stretch) = contrast {
+        info!("Applying {:?} contrast stretch", stretch);
+        let _span = phase_span!("normalize");
+        stretch_contrast(&mut img, stretch);
+    }
+
+    if let Some(levels) = posterize_levels {
+        info!("Posterizing to {} levels (dither: {})", levels, dither);
+        let _span = phase_span!("posterize", levels, dither);
+        posterize(&mut img, levels, dither);
+    }
+
+    Ok(img)
+}
+
+/// Returns the bounds `(x, y, width, height)` of `img` without its uniform
+/// border, or `None` when the whole image matches the border color.
+///
+/// The border color is the top-left pixel, and a pixel belongs to the border
+/// when none of its RGBA channels differs from it by more than `tolerance`.
+/// Rows and then columns are scanned inward from each edge until one holds
+/// a differing pixel. `img` must not be empty.
+fn trim_bounds(img: &DynamicImage, tolerance: u8) -> Option<(u32, u32, u32, u32)> {
+    let rgba = img.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let border = rgba.get_pixel(0, 0).0;
+    let differs = |x: u32, y: u32| {
+        rgba.get_pixel(x, y)
+            .0
+            .iter()
+            .zip(border)
+            .any(|(&channel, b)| channel.abs_diff(b) > tolerance)
+    };
+
+    let row_differs = |y: u32| (0..width).any(|x| differs(x, y));
+    let top = (0..height).find(|&y| row_differs(y))?;
+    let bottom = (top..height).rev().find(|&y| row_differs(y))?;
+    let column_differs = |x: u32| (top..=bottom).any(|y| differs(x, y));
+    let left = (0..width).find(|&x| column_differs(x))?;
+    let right = (left..width).rev().find(|&x| column_differs(x))?;
+    Some((left, top, right - left + 1, bottom - top + 1))
+}
+
+/// Encodes an already transformed image in the requested output format.
+/// `image_path` is only used to infer the output format from its extension.
+fn encode_transformed_image(
+    img: &DynamicImage,
+    image_path: &str,
+    options: &EncodeOptions,
+) -> Result<EncodedImage, Box<dyn Error>> {
+    let EncodeOptions {
+        format,
+        quality,
+        alpha_quality,
+        png_compression,
+        png_filter,
+        bit_depth_reduce,
+        embed_thumbnail,
+        ..
+    } = *options;
+
+    // Resolve the output format, falling back to PNG for unknown extensions
+    let registry = format_registry().read().unwrap();
+    let entry = match format {
+        Some(name) => registry
+            .get(name)
+            .ok_or_else(|| format!("Unknown image format: {}", name))?,
+        None => registry
+            .for_path(Path::new(image_path))
+            .or_else(|| registry.get("png"))
+            .ok_or("PNG format is not registered")?,
+    };
+
+    // Validate quality parameters
+    validate_quality(quality, entry)?;
+    if alpha_quality.is_some() && !entry.supports_alpha_quality {
+        error!("Alpha quality is not supported for {}", entry.name);
+        return Err(EncodeError::AlphaQualityNotAllowed(entry.name).into());
+    }
+
+    // Validate PNG encoder parameters
+    if (png_compression.is_some() || png_filter.is_some() || bit_depth_reduce)
+        && entry.name != "png"
+    {
+        error!("PNG compression, filter and bit depth options are only supported for PNG format");
+        return Err(EncodeError::PngOptionsNotAllowed(entry.name).into());
+    }
+
+    if embed_thumbnail.is_some() && !entry.supports_thumbnail {
+        error!("Embedded thumbnails are not supported for {}", entry.name);
+        return Err(EncodeError::ThumbnailNotAllowed(entry.name).into());
+    }
+
+    // Save the image to buffer
+    let buffer = {
+        let _span = phase_span!("encode", format = entry.name);
+        (entry.encode)(img, options)?
+    };
+
+    Ok(EncodedImage {
+        data: buffer,
+        format: entry.name,
+        width: img.width(),
+        height: img.height(),
+    })
+}
+
+/// Decodes a Base64 image and writes it to `output_path` as PNG.
+///
+/// Unless `options.input_format` names the format, it is guessed from the
+/// decoded bytes.
+fn decode_base64_to_image(
+    base64_str: &str,
+    output_path: &str,
+    url_safe: bool,
+    options: &DecodeOptions,
+) -> Result<(u32, u32, usize), Box<dyn Error>> {
+    info!("Starting to decode Base64 string to image");
+    let engine = if url_safe {
+        info!("Using URL-safe Base64 decoding");
+        &general_purpose::URL_SAFE
+    } else {
+        info!("Using standard Base64 decoding");
+        &general_purpose::STANDARD
+    };
+
+    // Decode the Base64 string
+    let decoded_data = {
+        let _span = phase_span!("base64_decode", chars = base64_str.len());
+        if options.auto_alphabet {
+            decode_any_alphabet(base64_str.trim(), url_safe)?
+        } else {
+            check_padded_length(base64_str.trim());
+            engine.decode(base64_str.trim())?
+        }
+    };
+    let decoded_len = decoded_data.len();
+    info!("Base64 string decoded successfully");
+
+    // Read before decoding, which consumes the buffer
+    let source_format = if options.sidecar || options.expect_format.is_some() {
+        options.input_format.or_else(|| {
+            let format = image_header_info(&decoded_data).ok()?.format?;
+            format_registry()
+                .read()
+                .unwrap()
+                .for_image_format(format)
+                .map(|entry| entry.name)
+        })
+    } else {
+        None
+    };
+
+    let mut img = decode_image_data(decoded_data, options.input_format)?;
+    info!("Image data decoded successfully");
+    check_expectations(&img, source_format, options)?;
+
+    if let Some(pixel_format) = options.convert_to {
+        info!("Converting {:?} image to {:?}", img.color(), pixel_format);
+        img = pixel_format.convert(img);
+    }
+
+    // Synchronously create and write to the output file
+    let _span = phase_span!("write", path = output_path);
+    let output_bytes = match (options.mmap, streamed_format(options.output_format)) {
+        (true, Some(format)) => write_mapped(output_path, &img, format)?,
+        (mmap, _) => {
+            if mmap {
+                info!(
+                    "{} is encoded in memory; writing {} without a memory map",
+                    options.output_format.unwrap_or("png"),
+                    output_path
+                );
+            }
+            let mut output_file = File::create(output_path)?;
+            match options.output_format {
+                Some(name) => {
+                    let registry = format_registry().read().unwrap();
+                    let entry = registry
+                        .get(name)
+                        .ok_or_else(|| format!("Unknown image format: {}", name))?;
+                    output_file.write_all(&(entry.encode)(&img, &EncodeOptions::default())?)?;
+                }
+                None => img.write_to(&mut output_file, ImageFormat::Png)?,
+            }
+            output_file.metadata()?.len()
+        }
+    };
+    info!("Image saved to {}", output_path);
+
+    if options.sidecar {
+        let sidecar_path = format!("{}.json", output_path);
+        let metadata = serde_json::json!({
+            "source_format": source_format,
+            "width": img.width(),
+            "height": img.height(),
+            "color_type": format!("{:?}", img.color()),
+            "decoded_bytes": decoded_len,
+            "output_bytes": output_bytes,
+            "base64_length": base64_str.trim().len(),
+        });
+        std::fs::write(&sidecar_path, serde_json::to_string_pretty(&metadata)?)?;
+        info!("Metadata sidecar saved to {}", sidecar_path);
+    }
+
+    Ok((img.width(), img.height(), decoded_len))
+}
+
+/// Returns the `image` format a decode output is written in when its
+/// encoder can stream into a writer: PNG, the default, and the uncompressed
+/// BMP and TIFF. Their registered entries write `img.write_to` unchanged
+/// under default options; every other format is encoded in memory first.
+fn streamed_format(output_format: Option<&str>) -> Option<ImageFormat> {
+    match output_format {
+        None | Some("png") => Some(ImageFormat::Png),
+        Some("bmp") => Some(ImageFormat::Bmp),
+        Some("tiff") => Some(ImageFormat::Tiff),
+        Some(_) => None,
+    }
+}
+
+/// Room left for headers, palettes and metadata when a mapped output is
+/// pre-sized from the pixel data.
+const MAPPED_HEADER_SLACK: u64 = 64 * 1024;
+
+/// Encodes `img` as `format` straight into a shared memory map of the file
+/// at `path` and returns the number of bytes written.
+///
+/// The file is pre-sized with `set_len` to the raw pixel size plus
+/// [`MAPPED_HEADER_SLACK`], which covers uncompressed output; the map grows
+/// if the encoder writes more, and the file is cut to the written length at
+/// the end. Outputs that are not regular files (pipes, `/dev/stdout`) and
+/// maps that fail, e.g. on filesystems without shared mappings, are written
+/// through the file instead.
+///
+/// Platform caveats: `set_len` leaves the file sparse on most Unix
+/// filesystems, so running out of disk space while the pages are flushed
+/// kills the process with SIGBUS instead of returning an error, as does
+/// another process truncating the file meanwhile. On Windows the file cannot
+/// be resized or deleted by others while it is mapped.
+fn write_mapped(
+    path: &str,
+    img: &DynamicImage,
+    format: ImageFormat,
+) -> Result<u64, Box<dyn Error>> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    if !file.metadata()?.is_file() {
+        info!(
+            "{} is not a regular file; writing without a memory map",
+            path
+        );
+        img.write_to(&mut file, format)?;
+        return Ok(file.metadata()?.len());
+    }
+
+    file.set_len(img.as_bytes().len() as u64 + MAPPED_HEADER_SLACK)?;
+    let mut writer = match MappedWriter::new(file) {
+        Ok(writer) => writer,
+        Err((mut file, e)) => {
+            warn!("Cannot memory-map {}: {}; writing normally", path, e);
+            file.set_len(0)?;
+            img.write_to(&mut file, format)?;
+            return Ok(file.metadata()?.len());
+        }
+    };
+    img.write_to(&mut writer, format)?;
+    let written = writer.finish()?;
+    info!("Wrote {} through a memory map", format_size(written));
+    Ok(written)
+}
+
+/// Seekable writer over a shared memory map of a file. Writes past the end
+/// of the map grow the file and map it again; `finish` cuts the file to the
+/// furthest byte written.
+struct MappedWriter {
+    file: File,
+    /// Only `None` while the file is resized, which Windows refuses to do
+    /// under a live map
+    map: Option<memmap2::MmapMut>,
+    pos: usize,
+    len: usize,
+}
+
+impl MappedWriter {
+    /// Maps the whole of `file`, which must not be empty. The file is handed
+    /// back with the error when it cannot be mapped.
+    fn new(file: File) -> Result<Self, (File, std::io::Error)> {
+        // SAFETY: the file was just created and sized by this process; a
+        // concurrent truncation by another process is the SIGBUS caveat of
+        // `write_mapped`
+        match unsafe { memmap2::MmapMut::map_mut(&file) } {
+            Ok(map) => Ok(MappedWriter {
+                file,
+                map: Some(map),
+                pos: 0,
+                len: 0,
+            }),
+            Err(e) => Err((file, e)),
+        }
+    }
+
+    fn map(&mut self) -> &mut memmap2::MmapMut {
+        self.map.as_mut().expect("map is only taken while resizing")
+    }
+
+    /// Grows the file and the map to hold at least `end` bytes, doubling the
+    /// size so repeated small overruns do not remap every time.
+    fn reserve(&mut self, end: usize) -> std::io::Result<()> {
+        let mapped = self.map().len();
+        if end <= mapped {
+            return Ok(());
+        }
+        if let Some(map) = self.map.take() {
+            map.flush()?;
+        }
+        self.file.set_len(end.max(mapped * 2) as u64)?;
+        // SAFETY: as in `new`
+        self.map = Some(unsafe { memmap2::MmapMut::map_mut(&self.file)? });
+        Ok(())
+    }
+
+    /// Flushes the map and cuts the file to the bytes written.
+    fn finish(mut self) -> std::io::Result<u64> {
+        if let Some(map) = self.map.take() {
+            map.flush()?;
+        }
+        self.file.set_len(self.len as u64)?;
+        Ok(self.len as u64)
+    }
+}
+
+impl Write for MappedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let end = self.pos + buf.len();
+        self.reserve(end)?;
+        let pos = self.pos;
+        self.map()[pos..end].copy_from_slice(buf);
+        self.pos = end;
+        self.len = self.len.max(end);
+        Ok(buf.len())
+    }
+
+    /// The map is the file's page cache, so there is nothing to push;
+    /// `finish` flushes it to disk.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MappedWriter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.len as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => (self.pos as u64).checked_add_signed(offset),
+        };
+        let target = target.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before the start of the output",
+            )
+        })?;
+        self.pos = usize::try_from(target)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        Ok(target)
+    }
+}
+
+/// Compares a decoded image against `--expect-size` and `--expect-format`
+/// and fails with every mismatch listed.
+fn check_expectations(
+    img: &DynamicImage,
+    source_format: Option<&str>,
+    options: &DecodeOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut mismatches = Vec::new();
+    if let Some((width, height)) = options.expect_size {
+        if img.dimensions() != (width, height) {
+            mismatches.push(format!(
+                "size is {}x{}, expected {}x{}",
+                img.width(),
+                img.height(),
+                width,
+                height
+            ));
+        }
+    }
+    if let Some(expected) = options.expect_format {
+        if source_format != Some(expected) {
+            mismatches.push(format!(
+                "format is {}, expected {}",
+                source_format.unwrap_or("unknown"),
+                expected
+            ));
+        }
+    }
+
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    error!("Decoded image does not match the expectations");
+    Err(format!(
+        "Decoded image does not match the expectations: {}",
+        mismatches.join("; ")
+    )
+    .into())
+}
+
+/// Parses a `WxH` size such as `640x480`.
+fn parse_size(value: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("expected WxH, e.g. 640x480, got '{}'", value);
+    let (width, height) = value.split_once(['x', 'X']).ok_or_else(invalid)?;
+    Ok((
+        width.parse().map_err(|_| invalid())?,
+        height.parse().map_err(|_| invalid())?,
+    ))
+}
+
+/// Opens decoded Base64 data as an image, preferring the registered decoder
+/// of `input_format` or of the guessed format.
+fn decode_image_data(
+    decoded_data: Vec<u8>,
+    input_format: Option<&str>,
+) -> Result<DynamicImage, Box<dyn Error>> {
+    if decoded_data.is_empty() {
+        error!("Decoded Base64 data is empty");
+        return Err("empty input: the Base64 data decodes to zero bytes".into());
+    }
+    let size = decoded_data.len() as u64;
+    decode_nonempty_image_data(decoded_data, input_format)
+        .map_err(|e| describe_decode_error(e, size))
+}
+
+/// Body of [`decode_image_data`] once the data is known to be non-empty.
+fn decode_nonempty_image_data(
+    decoded_data: Vec<u8>,
+    input_format: Option<&str>,
+) -> Result<DynamicImage, Box<dyn Error>> {
+    if let Some(name) = input_format {
+        let _span = phase_span!("decode", bytes = decoded_data.len(), format = name);
+        return decode_as_format(&decoded_data, name);
+    }
+
+    let _span = phase_span!("decode", bytes = decoded_data.len());
+    let guessed = image::guess_format(&decoded_data).ok();
+    match guessed.and_then(|f| {
+        format_registry()
+            .read()
+            .unwrap()
+            .for_image_format(f)
+            .map(|entry| (entry.decode)(&decoded_data))
+    }) {
+        Some(decoded) => decoded,
+        None => Ok(ImageReader::new(Cursor::new(decoded_data))
+            .with_guessed_format()?
+            .decode()?),
+    }
+}
+
+/// Returns whether an image decode error means the data ended early.
+///
+/// Decoders report running out of data either as an `UnexpectedEof` I/O
+/// error or in a format-specific decoding error, so the latter is matched
+/// on its message.
+fn is_truncation_error(err: &ImageError) -> bool {
+    match err {
+        ImageError::IoError(e) => e.kind() == std::io::ErrorKind::UnexpectedEof,
+        ImageError::Decoding(_) => {
+            let message = err.to_string().to_lowercase();
+            ["eof", "not enough bytes", "truncated", "unexpected end"]
+                .iter()
+                .any(|m| message.contains(m))
+        }
+        _ => false,
+    }
+}
+
+/// Replaces decode errors caused by incomplete data with a message that
+/// names the likely cause and how many bytes were available.
+fn describe_decode_error(err: Box<dyn Error>, bytes: u64) -> Box<dyn Error> {
+    match err.downcast_ref::<ImageError>() {
+        Some(image_err) if is_truncation_error(image_err) => {
+            error!("Image data ended early after {} bytes", bytes);
+            format!(
+                "image appears truncated or corrupt ({} bytes read): {}",
+                bytes,
+                image_err.to_string().trim_end()
+            )
+            .into()
+        }
+        _ => err,
+    }
+}
+
+/// Decodes a Base64 image and returns the RGBA value of the pixel at (`x`, `y`).
+fn sample_pixel(
+    base64_str: &str,
+    x: u32,
+    y: u32,
+    url_safe: bool,
+) -> Result<image::Rgba<u8>, Box<dyn Error>> {
+    let data = base64_engine(url_safe).decode(base64_str.trim())?;
+    let img = decode_image_data(data, None)?;
+    if x >= img.width() || y >= img.height() {
+        error!("Pixel ({}, {}) is outside the image", x, y);
+        return Err(format!(
+            "Pixel ({}, {}) is outside the {}x{} image",
+            x,
+            y,
+            img.width(),
+            img.height()
+        )
+        .into());
+    }
+    Ok(img.get_pixel(x, y))
+}
+
+/// Decodes `base64_str` with the first alphabet that accepts it, starting
+/// with the padded and unpadded variants of the one `url_safe` selects, and
+/// reports which alphabet succeeded.
+fn decode_any_alphabet(base64_str: &str, url_safe: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut alphabets = BASE64_ALPHABETS;
+    if url_safe {
+        alphabets.rotate_left(2);
+    }
+
+    let mut first_error = None;
+    for (name, engine) in alphabets {
+        match engine.decode(base64_str) {
+            Ok(data) => {
+                if first_error.is_some() {
+                    warn!("Base64 input only decoded with the {} alphabet", name);
+                }
+                println!("Decoded with the {} Base64 alphabet", name);
+                return Ok(data);
+            }
+            Err(e) => {
+                info!("{} Base64 decoding failed: {}", name, e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    error!("Input is not valid Base64 in any alphabet");
+    Err(format!(
+        "Input is not valid Base64 in any alphabet: {}",
+        first_error.unwrap()
+    )
+    .into())
+}
+
+/// Decodes image bytes as the registered format `name` without guessing.
+fn decode_as_format(data: &[u8], name: &str) -> Result<DynamicImage, Box<dyn Error>> {
+    let registry = format_registry().read().unwrap();
+    let entry = registry
+        .get(name)
+        .ok_or_else(|| format!("Unknown image format: {}", name))?;
+    info!("Decoding image as {} without format detection", entry.name);
+
+    let decoded = match entry.image_format {
+        Some(format) => ImageReader::with_format(Cursor::new(data), format)
+            .decode()
+            .map_err(|e| e.to_string()),
+        None => (entry.decode)(data).map_err(|e| e.to_string()),
+    };
+    decoded.map_err(|e| {
+        error!("Data could not be decoded as {}", entry.name);
+        format!("Data could not be decoded as {}: {}", entry.name, e).into()
+    })
+}
+
+/// Side of the grayscale thumbnail whose DCT [`perceptual_hash`] takes.
+const PHASH_SAMPLE_SIZE: usize = 32;
+
+/// Side of the block of lowest DCT frequencies that becomes the hash bits.
+const PHASH_FREQUENCIES: usize = 8;
+
+/// Computes the perceptual hash of an image as 16 hex digits.
+///
+/// Uses the DCT-based pHash: the image is reduced to a 32x32 grayscale
+/// thumbnail, and the 8x8 lowest frequencies of its 2D DCT-II each give one
+/// bit, set when the coefficient is above the median of the 64. The 64-bit
+/// hash has the first row of frequencies in its most significant bits.
+/// Visually similar images differ in few bits, so near-duplicates can be
+/// found by comparing Hamming distances.
+fn perceptual_hash(img: &DynamicImage) -> String {
+    const N: usize = PHASH_SAMPLE_SIZE;
+    const K: usize = PHASH_FREQUENCIES;
+    let sample = img
+        .resize_exact(N as u32, N as u32, FilterType::Triangle)
+        .to_luma8();
+    // cos((2x + 1) u pi / 2N) for every sample x and kept frequency u
+    let cosines: Vec<[f64; K]> = (0..N)
+        .map(|x| {
+            std::array::from_fn(|u| {
+                ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2 * N) as f64).cos()
+            })
+        })
+        .collect();
+
+    // The DCT is separable: transform the rows, then the columns
+    let rows: Vec<[f64; K]> = sample
+        .rows()
+        .map(|row| {
+            std::array::from_fn(|u| {
+                row.clone()
+                    .zip(&cosines)
+                    .map(|(pixel, cos)| f64::from(pixel.0[0]) * cos[u])
+                    .sum()
+            })
+        })
+        .collect();
+    let coefficients: Vec<f64> = (0..K)
+        .flat_map(|v| {
+            let (rows, cosines) = (&rows, &cosines);
+            (0..K).map(move |u| {
+                rows.iter()
+                    .zip(cosines)
+                    .map(|(row, cos)| row[u] * cos[v])
+                    .sum()
+            })
+        })
+        .collect();
+
+    let mut sorted = coefficients.clone();
+    sorted.sort_by(f64::total_cmp);
+    let median = (sorted[K * K / 2 - 1] + sorted[K * K / 2]) / 2.0;
+    let hash = coefficients
+        .iter()
+        .fold(0u64, |hash, &c| (hash << 1) | u64::from(c > median));
+    format!("{:016x}", hash)
+}
+
+/// Encode timings of one format measured by `bench-encode`.
+struct BenchResult {
+    format: &'static str,
+    /// Size of the encoded output in bytes
+    bytes: usize,
+    mean: Duration,
+    min: Duration,
+}
+
+/// Encodes `img` with every registered format at default settings, timing
+/// `iterations` runs of each after one untimed warm-up run. Results are
+/// ranked by mean encode time, fastest first; formats that cannot encode
+/// the image are returned separately with their error.
+fn bench_encode(
+    img: &DynamicImage,
+    iterations: u32,
+) -> (Vec<BenchResult>, Vec<(&'static str, String)>) {
+    let registry = format_registry().read().unwrap();
+    let options = EncodeOptions::default();
+    let mut results = Vec::new();
+    let mut failed = Vec::new();
+    for entry in registry.entries() {
+        info!("Benchmarking {} encoding", entry.name);
+        let bytes = match (entry.encode)(img, &options) {
+            Ok(data) => data.len(),
+            Err(e) => {
+                warn!("{} cannot encode this image: {}", entry.name, e);
+                failed.push((entry.name, e.to_string()));
+                continue;
+            }
+        };
+        let mut total = Duration::ZERO;
+        let mut min = Duration::MAX;
+        for _ in 0..iterations {
+            let start = Instant::now();
+            // The warm-up run already succeeded with the same input
+            let data = (entry.encode)(img, &options).unwrap_or_default();
+            let elapsed = start.elapsed();
+            std::hint::black_box(data);
+            total += elapsed;
+            min = min.min(elapsed);
+        }
+        results.push(BenchResult {
+            format: entry.name,
+            bytes,
+            mean: total / iterations,
+            min,
+        });
+    }
+    results.sort_by_key(|result| result.mean);
+    (results, failed)
+}
+
+/// Distinct colors counted by `info --colors` before it reports "more than".
+const UNIQUE_COLOR_LIMIT: usize = 1 << 20;
+
+/// Counts the distinct 8-bit RGBA colors of an image, stopping once more
+/// than `limit` have been seen so the set stays bounded on large photos.
+/// Returns the count and whether counting stopped at the limit.
+fn count_unique_colors(img: &DynamicImage, limit: usize) -> (usize, bool) {
+    let mut colors = HashSet::new();
+    for (_, _, pixel) in img.pixels() {
+        colors.insert(u32::from_ne_bytes(pixel.0));
+        if colors.len() > limit {
+            return (limit, true);
+        }
+    }
+    (colors.len(), false)
+}
+
+/// Bins of every `histogram` channel, one per 8-bit sample value.
+const HISTOGRAM_BINS: usize = 256;
+
+/// Height of the chart written by `histogram --render`, in pixels; it is
+/// one pixel wide per bin.
+const HISTOGRAM_RENDER_HEIGHT: u32 = 128;
+
+/// Counts the samples of each channel of `img` per 8-bit value, returning
+/// the named channels in output order.
+///
+/// Bin `i` holds the samples whose 8-bit value is `i`; 16-bit and float
+/// images are converted to 8 bits first, so each bin covers an equal
+/// 1/256 slice of the full range. Luminance is the image crate's 8-bit
+/// grayscale conversion (Rec. 709 weights). Color images get red, green,
+/// blue and luminance, grayscale images or `gray_only` just luminance, and
+/// images with an alpha channel also get alpha.
+fn channel_histograms(img: &DynamicImage, gray_only: bool) -> Vec<(&'static str, Vec<u64>)> {
+    let mut histograms = Vec::new();
+    let mut count = |name, samples: &mut dyn Iterator<Item = u8>| {
+        let mut bins = vec![0u64; HISTOGRAM_BINS];
+        for sample in samples {
+            bins[usize::from(sample)] += 1;
+        }
+        histograms.push((name, bins));
+    };
+
+    let rgba = img.to_rgba8();
+    if img.color().has_color() && !gray_only {
+        for (channel, name) in ["red", "green", "blue"].into_iter().enumerate() {
+            count(name, &mut rgba.pixels().map(|pixel| pixel.0[channel]));
+        }
+    }
+    count("luminance", &mut img.to_luma8().into_raw().into_iter());
+    if img.color().has_alpha() {
+        count("alpha", &mut rgba.pixels().map(|pixel| pixel.0[3]));
+    }
+    histograms
+}
+
+/// Draws `histograms` as a bar chart, one column per bin, scaled so the
+/// tallest bin fills the height. Red, green and blue are drawn on top of
+/// each other in their own colors, so overlaps mix (red and green give
+/// yellow, all three white); without them, luminance is drawn in white.
+/// Alpha is not drawn.
+fn render_histograms(histograms: &[(&'static str, Vec<u64>)]) -> image::RgbImage {
+    let colored: Vec<_> = histograms
+        .iter()
+        .filter_map(|(name, bins)| {
+            let color = match *name {
+                "red" => [255, 0, 0],
+                "green" => [0, 255, 0],
+                "blue" => [0, 0, 255],
+                _ => return None,
+            };
+            Some((color, bins))
+        })
+        .collect();
+    let drawn = if colored.is_empty() {
+        histograms
+            .iter()
+            .filter(|(name, _)| *name == "luminance")
+            .map(|(_, bins)| ([255, 255, 255], bins))
+            .collect()
+    } else {
+        colored
+    };
+
+    let height = HISTOGRAM_RENDER_HEIGHT;
+    let tallest = drawn
+        .iter()
+        .flat_map(|(_, bins)| bins.iter())
+        .copied()
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let mut chart = image::RgbImage::new(HISTOGRAM_BINS as u32, height);
+    for (color, bins) in drawn {
+        for (x, &bin) in bins.iter().enumerate() {
+            let bar = (bin as f64 / tallest as f64 * f64::from(height)).round() as u32;
+            for y in height - bar..height {
+                let pixel = chart.get_pixel_mut(x as u32, y);
+                for (channel, value) in pixel.0.iter_mut().zip(color) {
+                    *channel |= value;
+                }
+            }
+        }
+    }
+    chart
+}
+
+/// Side of the square Gaussian window SSIM is computed over, in pixels.
+const SSIM_WINDOW: usize = 11;
+
+/// Standard deviation of the Gaussian SSIM window, in pixels.
+const SSIM_SIGMA: f64 = 1.5;
+
+/// Stabilizing constants of SSIM; `C1 = (K1 * 255)^2` and `C2 = (K2 * 255)^2`
+/// keep the ratios finite in flat regions.
+const SSIM_K1: f64 = 0.01;
+const SSIM_K2: f64 = 0.03;
+
+/// Computes the mean structural similarity (SSIM) index of two images.
+///
+/// Follows Wang et al. (2004): both images are compared as 8-bit grayscale
+/// through an 11x11 Gaussian window with a standard deviation of 1.5 pixels,
+/// using K1 = 0.01 and K2 = 0.03. Only windows that lie fully inside the
+/// image count towards the mean; images smaller than the window use a window
+/// as large as their shorter side. `b` is resized to the size of `a` when
+/// they differ. The result is 1.0 for identical images and lower the more
+/// structure, contrast or brightness differ.
+fn ssim(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    let resized;
+    let b = if a.dimensions() != b.dimensions() {
+        warn!(
+            "Resizing second image from {}x{} to {}x{} for SSIM",
+            b.width(),
+            b.height(),
+            a.width(),
+            a.height()
+        );
+        resized = b.resize_exact(a.width(), a.height(), FilterType::Lanczos3);
+        &resized
+    } else {
+        b
+    };
+
+    let (width, height) = (a.width() as usize, a.height() as usize);
+    let gray = |img: &DynamicImage| -> Vec<f64> {
+        img.to_luma8()
+            .into_raw()
+            .into_iter()
+            .map(f64::from)
+            .collect()
+    };
+    let (x, y) = (gray(a), gray(b));
+    let product =
+        |p: &[f64], q: &[f64]| -> Vec<f64> { p.iter().zip(q).map(|(p, q)| p * q).collect() };
+
+    let window = SSIM_WINDOW.min(width).min(height);
+    let kernel = gaussian_kernel(window, SSIM_SIGMA);
+    let blur = |data: &[f64]| gaussian_blur_valid(data, width, height, &kernel);
+    let (mu_x, mu_y) = (blur(&x), blur(&y));
+    let (xx, yy, xy) = (
+        blur(&product(&x, &x)),
+        blur(&product(&y, &y)),
+        blur(&product(&x, &y)),
+    );
+
+    let c1 = (SSIM_K1 * 255.0).powi(2);
+    let c2 = (SSIM_K2 * 255.0).powi(2);
+    let total: f64 = (0..mu_x.len())
+        .map(|i| {
+            let (mx, my) = (mu_x[i], mu_y[i]);
+            let var_x = xx[i] - mx * mx;
+            let var_y = yy[i] - my * my;
+            let cov = xy[i] - mx * my;
+            ((2.0 * mx * my + c1) * (2.0 * cov + c2))
+                / ((mx * mx + my * my + c1) * (var_x + var_y + c2))
+        })
+        .sum();
+    total / mu_x.len() as f64
+}
+
+/// Returns a normalized 1-D Gaussian kernel of `size` taps.
+fn gaussian_kernel(size: usize, sigma: f64) -> Vec<f64> {
+    let center = (size - 1) as f64 / 2.0;
+    let kernel: Vec<f64> = (0..size)
+        .map(|i| (-((i as f64 - center).powi(2)) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f64 = kernel.iter().sum();
+    kernel.into_iter().map(|k| k / sum).collect()
+}
+
+/// Blurs a `width` x `height` plane with the separable `kernel`, keeping
+/// only the positions where the whole window fits inside the plane.
+fn gaussian_blur_valid(data: &[f64], width: usize, height: usize, kernel: &[f64]) -> Vec<f64> {
+    let (out_width, out_height) = (width + 1 - kernel.len(), height + 1 - kernel.len());
+    let mut rows = Vec::with_capacity(out_width * height);
+    for row in data.chunks_exact(width) {
+        for x in 0..out_width {
+            rows.push(
+                kernel
+                    .iter()
+                    .zip(&row[x..])
+                    .map(|(k, v)| k * v)
+                    .sum::<f64>(),
+            );
+        }
+    }
+
+    let mut out = Vec::with_capacity(out_width * out_height);
+    for y in 0..out_height {
+        for x in 0..out_width {
+            out.push(
+                kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(i, k)| k * rows[(y + i) * out_width + x])
+                    .sum::<f64>(),
+            );
+        }
+    }
+    out
+}
+
+/// Returns the width and height of a Base64-encoded image.
+///
+/// Only the Base64 text and the image header are decoded: the pixel buffer
+/// is never allocated, so this stays cheap for images too large to load.
+///
+/// # Arguments
+/// * `base64` - Base64 text of the encoded image, surrounding whitespace allowed
+/// * `url_safe` - Whether the text uses the URL-safe alphabet
+fn base64_image_dimensions(base64: &str, url_safe: bool) -> Result<(u32, u32), Box<dyn Error>> {
+    let data = base64_engine(url_safe).decode(base64.trim())?;
+    Ok(ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_dimensions()?)
+}
+
+/// Format, dimensions and color type read from the header of encoded
+/// image bytes.
+struct ImageHeaderInfo {
+    /// Format guessed from the leading bytes, if recognized
+    format: Option<ImageFormat>,
+    dimensions: (u32, u32),
+    color_type: image::ColorType,
+}
+
+/// Reads the guessed format, dimensions and color type of encoded image
+/// bytes without decoding the pixel data.
+fn image_header_info(data: &[u8]) -> Result<ImageHeaderInfo, Box<dyn Error>> {
+    let reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    let format = reader.format();
+    let decoder = reader.into_decoder()?;
+    Ok(ImageHeaderInfo {
+        format,
+        dimensions: decoder.dimensions(),
+        color_type: decoder.color_type(),
+    })
+}
+
+/// What `info` reports about an image, printed as text or as JSON.
+#[derive(Debug, Serialize)]
+struct InfoReport {
+    /// Registered name of the detected format
+    format: Option<&'static str>,
+    width: u32,
+    height: u32,
+    /// Pixel layout declared by the header, e.g. `Rgba8`
+    color_type: String,
+    /// Whether the header could be read
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phash: Option<String>,
+    /// Number of distinct RGBA colors, at most [`UNIQUE_COLOR_LIMIT`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unique_colors: Option<usize>,
+    /// Whether the image has more colors than `unique_colors`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unique_colors_capped: Option<bool>,
+}
+
+impl InfoReport {
+    /// Builds the report from the header of encoded image bytes. The pixel
+    /// data is never decoded, so this is much cheaper than a full decode.
+    fn from_header(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let header = image_header_info(data)?;
+        let format = header.format.and_then(|f| {
+            format_registry()
+                .read()
+                .unwrap()
+                .for_image_format(f)
+                .map(|entry| entry.name)
+        });
+        Ok(InfoReport {
+            format,
+            color_type: format!("{:?}", header.color_type),
+            ..InfoReport::dimensions_only(header.dimensions.0, header.dimensions.1)
+        })
+    }
+
+    /// Builds a report holding only the dimensions, as read by
+    /// [`base64_image_dimensions`].
+    fn dimensions_only(width: u32, height: u32) -> Self {
+        InfoReport {
+            format: None,
+            width,
+            height,
+            color_type: String::new(),
+            valid: true,
+            phash: None,
+            unique_colors: None,
+            unique_colors_capped: None,
+        }
+    }
+
+    /// Prints the report as text. The format and color type lines are only
+    /// printed when `verbose`, keeping the default output to the dimensions
+    /// line scripts already parse.
+    fn print_text(&self, verbose: bool) {
+        println!("Dimensions: {}x{}", self.width, self.height);
+        if verbose {
+            println!("Format: {}", self.format.unwrap_or("unknown"));
+            println!("Color type: {}", self.color_type);
+        }
+        if let Some(phash) = &self.phash {
+            println!("Perceptual hash: {}", phash);
+        }
+        match (self.unique_colors, self.unique_colors_capped) {
+            (Some(colors), Some(true)) => println!("Unique colors: more than {}", colors),
+            (Some(colors), _) if colors <= 256 => {
+                println!("Unique colors: {} (fits a 256-color palette)", colors)
+            }
+            (Some(colors), _) => println!("Unique colors: {}", colors),
+            (None, _) => {}
+        }
+    }
+}
+
+/// Resolves the `--format` argument (a name or extension alias) to the
+/// registered format name.
+fn format_arg(matches: &ArgMatches) -> Option<&'static str> {
+    registered_format_arg(matches, "format")
+}
+
+/// Reads the `--input-format` and `--as` arguments of the decode subcommands.
+fn decode_options_arg(matches: &ArgMatches) -> DecodeOptions {
+    DecodeOptions {
+        input_format: registered_format_arg(matches, "input-format"),
+        convert_to: matches
+            .get_one::<String>("as")
+            .map(|name| PixelFormat::parse(name)),
+        ..Default::default()
+    }
+}
+
+fn registered_format_arg(matches: &ArgMatches, id: &str) -> Option<&'static str> {
+    let name = matches.get_one::<String>(id)?;
+    format_registry()
+        .read()
+        .unwrap()
+        .get(name)
+        .map(|entry| entry.name)
+}
+
+/// Opens the `--state-file` of the batch subcommands, honouring `--restart`.
+fn batch_state_arg(matches: &ArgMatches) -> Result<Option<BatchState>, Box<dyn Error>> {
+    matches
+        .get_one::<String>("state-file")
+        .map(|path| BatchState::open(Path::new(path), matches.get_flag("restart")))
+        .transpose()
+}
+
+/// Reads the `--per-file-timeout` argument of the batch subcommands.
+fn per_file_timeout(matches: &ArgMatches) -> Option<Duration> {
+    matches
+        .get_one::<u64>("per-file-timeout")
+        .map(|secs| Duration::from_secs(*secs))
+}
+
+/// Splits `text` into chunks of at most `chunk_size` characters.
+fn split_chunks(text: &str, chunk_size: usize) -> Vec<&str> {
+    let mut chunks = Vec::with_capacity(text.len().div_ceil(chunk_size));
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(chunk_size);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+/// Writes `encode --chunk` output: a JSON array of the chunks with `json`,
+/// otherwise one `<output>.partN` file per chunk. Without an output path the
+/// JSON array, or one chunk per line, is printed to stdout.
+fn write_chunked_output(
+    text: &str,
+    chunk_size: usize,
+    json: bool,
+    output: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let chunks = split_chunks(text, chunk_size);
+    info!(
+        "Split {} characters into {} chunks of at most {}",
+        text.len(),
+        chunks.len(),
+        chunk_size
+    );
+
+    match (json, output) {
+        (true, Some(output_path)) => {
+            std::fs::write(output_path, serde_json::to_string(&chunks)?)?;
+            println!(
+                "Encoded image saved to {} as {} chunks",
+                output_path,
+                chunks.len()
+            );
+        }
+        (true, None) => print_line(&serde_json::to_string(&chunks)?),
+        (false, Some(output_path)) => {
+            for (index, chunk) in chunks.iter().enumerate() {
+                std::fs::write(format!("{}.part{}", output_path, index), chunk)?;
+            }
+            println!(
+                "Encoded image saved to {}.part0 .. {}.part{}",
+                output_path,
+                output_path,
+                chunks.len().saturating_sub(1)
+            );
+        }
+        (false, None) => {
+            for chunk in &chunks {
+                print!("{}{}", chunk, line_ending());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reassembles output written by `encode --chunk`: `input` is either a JSON
+/// array of chunks or the path prefix of `<input>.part0`, `<input>.part1`, ...
+fn read_chunked_base64(input: &str) -> Result<String, Box<dyn Error>> {
+    if Path::new(input).is_file() {
+        info!("Reassembling chunks from JSON array {}", input);
+        let chunks: Vec<String> = serde_json::from_str(&read_to_string(input)?)?;
+        return Ok(chunks.concat());
+    }
+
+    let mut joined = String::new();
+    let mut count = 0;
+    loop {
+        let part = format!("{}.part{}", input, count);
+        if !Path::new(&part).is_file() {
+            break;
+        }
+        joined.push_str(&read_to_string(&part)?);
+        count += 1;
+    }
+    if count == 0 {
+        return Err(format!("Neither {} nor {}.part0 exists", input, input).into());
+    }
+    info!("Reassembled {} chunk files from {}", count, input);
+    Ok(joined)
+}
+
+/// Returns the Base64 text given on the command line, or reads it from
+/// stdin when the argument is `-`.
+fn read_base64_arg(base64_input: &str) -> Result<String, Box<dyn Error>> {
+    if base64_input == "-" {
+        let mut buffer = String::new();
+        std::io::stdin().read_to_string(&mut buffer)?;
+        if buffer.trim().is_empty() {
+            error!("No Base64 data received on stdin");
+            return Err("no Base64 data received on stdin".into());
+        }
+        Ok(buffer)
+    } else {
+        Ok(base64_input.to_string())
+    }
+}
+
+/// Returns the Base64 text of a `pixel` input: read from stdin for `-`, from
+/// the file when the argument names one, and otherwise the argument itself.
+fn read_base64_or_file(base64_input: &str) -> Result<String, Box<dyn Error>> {
+    if base64_input != "-" && Path::new(base64_input).is_file() {
+        info!("Reading Base64 from file: {}", base64_input);
+        return Ok(read_to_string(base64_input)?);
+    }
+    read_base64_arg(base64_input)
+}
+
+/// Returns true if an encode or decode input names an `http://` or `https://` URL.
+fn is_http_url(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
+
+/// Maximum number of redirects followed when fetching a Base64 payload.
+#[cfg(feature = "network")]
+const MAX_REDIRECTS: u32 = 5;
+
+/// Errors raised while fetching a Base64 payload over HTTP.
+///
+/// Kept separate from decode errors so a failed download is never mistaken
+/// for a malformed payload.
+#[cfg(feature = "network")]
+enum FetchError {
+    Status {
+        url: String,
+        code: u16,
+    },
+    Transport {
+        url: String,
+        message: String,
+    },
+    Body {
+        url: String,
+        message: String,
+    },
+    Incomplete {
+        url: String,
+        expected: u64,
+        received: u64,
+    },
+}
+
+#[cfg(feature = "network")]
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Status { url, code } => {
+                write!(f, "HTTP error fetching {}: status {}", url, code)
+            }
+            FetchError::Transport { url, message } => {
+                write!(f, "HTTP error fetching {}: {}", url, message)
+            }
+            FetchError::Body { url, message } => {
+                write!(f, "HTTP error reading response from {}: {}", url, message)
+            }
+            FetchError::Incomplete {
+                url,
+                expected,
+                received,
+            } => write!(
+                f,
+                "HTTP download of {} is incomplete: received {} of {} bytes",
+                url, received, expected
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "network")]
+impl fmt::Debug for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(feature = "network")]
+impl Error for FetchError {}
+
+/// Fetches a Base64 payload from `url`, following redirects.
+///
+/// The response body may be raw Base64 or a `data:` URI, in which case only
+/// the Base64 part after the comma is returned.
+#[cfg(feature = "network")]
+fn fetch_base64_url(url: &str, timeout: Duration) -> Result<String, Box<dyn Error>> {
+    info!("Fetching Base64 payload from {}", url);
+    let agent = ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .redirects(MAX_REDIRECTS)
+        .build();
+    let response = agent.get(url).call().map_err(|e| match e {
+        ureq::Error::Status(code, _) => FetchError::Status {
+            url: url.to_string(),
+            code,
+        },
+        ureq::Error::Transport(transport) => FetchError::Transport {
+            url: url.to_string(),
+            message: transport.to_string(),
+        },
+    })?;
+    let body = response.into_string().map_err(|e| FetchError::Body {
+        url: url.to_string(),
+        message: e.to_string(),
+    })?;
+    info!("Fetched {} bytes from {}", body.len(), url);
+
+    Ok(strip_data_uri(&body)?.to_string())
+}
+
+/// Returns the Base64 data of a `data:...;base64,` URI, or `payload`
+/// unchanged when it is not a data URI.
+#[cfg(feature = "network")]
+fn strip_data_uri(payload: &str) -> Result<&str, Box<dyn Error>> {
+    let trimmed = payload.trim();
+    let Some(uri) = trimmed.strip_prefix("data:") else {
+        return Ok(trimmed);
+    };
+    let (media_type, data) = uri.split_once(',').ok_or("Data URI has no ',' separator")?;
+    if !media_type.ends_with(";base64") {
+        return Err(format!("Data URI '{}' is not Base64-encoded", media_type).into());
+    }
+    info!(
+        "Payload is a data URI ({}), using its Base64 data",
+        media_type
+    );
+    Ok(data)
+}
+
+/// Number of attempts made to download an image before giving up.
+#[cfg(feature = "network")]
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Temporary paths used while downloading an image for `encode`.
+#[cfg(feature = "network")]
+struct DownloadPaths {
+    /// Bytes received so far; kept across runs so a later run can resume
+    partial: PathBuf,
+    /// ETag of the partial download, sent as `If-Range` when resuming
+    etag: PathBuf,
+    /// Completed download, named with the URL's extension for format inference
+    complete: PathBuf,
+}
+
+#[cfg(feature = "network")]
+impl DownloadPaths {
+    fn for_url(url: &str) -> Self {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = DefaultHasher::new();
+        url.hash(&mut hasher);
+        let stem = format!("image-base64-{:016x}", hasher.finish());
+        let file_name = url.split(['?', '#']).next().unwrap_or(url);
+        let extension = Path::new(file_name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("img");
+
+        let dir = std::env::temp_dir();
+        DownloadPaths {
+            partial: dir.join(format!("{}.part", stem)),
+            etag: dir.join(format!("{}.etag", stem)),
+            complete: dir.join(format!("{}.{}", stem, extension)),
+        }
+    }
+}
+
+// The partial file is deliberately left behind so a failed download can resume
+#[cfg(feature = "network")]
+impl Drop for DownloadPaths {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.complete);
+    }
+}
+
+/// Downloads an image for `encode`, resuming interrupted transfers.
+///
+/// Received bytes are kept in a temporary `.part` file. Retries, and later
+/// runs for the same URL, request only the missing bytes with a `Range`
+/// header guarded by `If-Range` on the saved ETag. A server that ignores
+/// ranges, or whose ETag changed, answers with the full body, which then
+/// replaces the partial file. The finished download is checked against the
+/// length announced by `Content-Length` or `Content-Range`.
+#[cfg(feature = "network")]
+fn download_image_url(url: &str, timeout: Duration) -> Result<DownloadPaths, Box<dyn Error>> {
+    let paths = DownloadPaths::for_url(url);
+    let agent = ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .redirects(MAX_REDIRECTS)
+        .build();
+
+    let mut last_error = None;
+    for attempt in 1..=DOWNLOAD_ATTEMPTS {
+        match download_attempt(&agent, url, &paths) {
+            Ok(()) => {
+                std::fs::rename(&paths.partial, &paths.complete)?;
+                let _ = std::fs::remove_file(&paths.etag);
+                info!("Downloaded {} to {}", url, paths.complete.display());
+                return Ok(paths);
+            }
+            // A status error will not go away by retrying
+            Err(e @ FetchError::Status { .. }) => {
+                error!("{}", e);
+                return Err(e.into());
+            }
+            Err(e) => {
+                warn!(
+                    "Download attempt {} of {} failed: {}",
+                    attempt, DOWNLOAD_ATTEMPTS, e
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+    let e = last_error.expect("at least one download attempt is made");
+    error!("{}", e);
+    Err(e.into())
+}
+
+/// Makes one request for the bytes of `url` not yet in the partial file.
+#[cfg(feature = "network")]
+fn download_attempt(
+    agent: &ureq::Agent,
+    url: &str,
+    paths: &DownloadPaths,
+) -> Result<(), FetchError> {
+    let body_error = |e: std::io::Error| FetchError::Body {
+        url: url.to_string(),
+        message: e.to_string(),
+    };
+    let offset = std::fs::metadata(&paths.partial).map_or(0, |m| m.len());
+    let etag = std::fs::read_to_string(&paths.etag).ok();
+
+    let mut request = agent.get(url);
+    if offset > 0 {
+        info!("Resuming download of {} at byte {}", url, offset);
+        request = request.set("Range", &format!("bytes={}-", offset));
+        if let Some(etag) = &etag {
+            request = request.set("If-Range", etag);
+        }
+    }
+
+    let response = match request.call() {
+        Ok(response) => response,
+        // The partial file is stale or already complete; start over
+        Err(ureq::Error::Status(416, _)) => {
+            let _ = std::fs::remove_file(&paths.partial);
+            let _ = std::fs::remove_file(&paths.etag);
+            return Err(FetchError::Body {
+                url: url.to_string(),
+                message: "server rejected the resume range".to_string(),
+            });
+        }
+        Err(ureq::Error::Status(code, _)) => {
+            return Err(FetchError::Status {
+                url: url.to_string(),
+                code,
+            })
+        }
+        Err(ureq::Error::Transport(transport)) => {
+            return Err(FetchError::Transport {
+                url: url.to_string(),
+                message: transport.to_string(),
+            })
+        }
+    };
+
+    let resumed = response.status() == 206;
+    let expected = if resumed {
+        response
+            .header("Content-Range")
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse::<u64>().ok())
+    } else {
+        if offset > 0 {
+            info!("Server sent the full body of {}, restarting download", url);
+        }
+        response
+            .header("Content-Length")
+            .and_then(|len| len.parse::<u64>().ok())
+    };
+    if let Some(etag) = response.header("ETag") {
+        std::fs::write(&paths.etag, etag).map_err(body_error)?;
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(&paths.partial)
+        .map_err(body_error)?;
+    std::io::copy(&mut response.into_reader(), &mut file).map_err(body_error)?;
+
+    let received = file.metadata().map_err(body_error)?.len();
+    match expected {
+        Some(expected) if received != expected => Err(FetchError::Incomplete {
+            url: url.to_string(),
+            expected,
+            received,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Resolves the `encode` input to a local path, downloading URLs first.
+///
+/// Returns the path together with the temporary download, if any, which is
+/// deleted when dropped and must be kept alive until the image is encoded.
+#[cfg(feature = "network")]
+fn resolve_encode_input(
+    image_path: &str,
+    matches: &ArgMatches,
+) -> Result<(String, Option<DownloadPaths>), Box<dyn Error>> {
+    if !is_http_url(image_path) {
+        return Ok((image_path.to_string(), None));
+    }
+    let timeout = Duration::from_secs(*matches.get_one::<u64>("timeout").unwrap());
+    let paths = download_image_url(image_path, timeout)?;
+    let local = paths
+        .complete
+        .to_str()
+        .ok_or("Non UTF-8 temporary path")?
+        .to_string();
+    Ok((local, Some(paths)))
+}
+
+#[cfg(not(feature = "network"))]
+fn resolve_encode_input(
+    image_path: &str,
+    _matches: &ArgMatches,
+) -> Result<(String, Option<()>), Box<dyn Error>> {
+    if is_http_url(image_path) {
+        return Err("Encoding from a URL requires the 'network' feature".into());
+    }
+    Ok((image_path.to_string(), None))
+}
+
+/// Stand-in input path for `encode --from-clipboard`, so the output format
+/// defaults to PNG when `--format` is not given.
+const CLIPBOARD_IMAGE_NAME: &str = "clipboard.png";
+
+/// Reads the image on the system clipboard for `encode --from-clipboard`.
+#[cfg(feature = "clipboard")]
+fn read_clipboard_image() -> Result<DynamicImage, Box<dyn Error>> {
+    let mut clipboard =
+        arboard::Clipboard::new().map_err(|e| format!("Cannot access the clipboard: {}", e))?;
+    let data = clipboard.get_image().map_err(|e| match e {
+        arboard::Error::ContentNotAvailable => {
+            "The clipboard does not contain an image".to_string()
+        }
+        e => format!("Cannot read an image from the clipboard: {}", e),
+    })?;
+    info!(
+        "Read {}x{} image from the clipboard",
+        data.width, data.height
+    );
+    let buffer = ImageBuffer::from_raw(
+        u32::try_from(data.width)?,
+        u32::try_from(data.height)?,
+        data.bytes.into_owned(),
+    )
+    .ok_or("Clipboard image data does not match its dimensions")?;
+    Ok(DynamicImage::ImageRgba8(buffer))
+}
+
+#[cfg(not(feature = "clipboard"))]
+fn read_clipboard_image() -> Result<DynamicImage, Box<dyn Error>> {
+    Err("Encoding from the clipboard requires the 'clipboard' feature".into())
+}
+
+/// Whether there is a desktop session a viewer could show up in. On X11
+/// and Wayland systems that needs `DISPLAY` or `WAYLAND_DISPLAY`; macOS and
+/// Windows only lack one in SSH sessions.
+#[cfg(feature = "viewer")]
+fn has_display() -> bool {
+    if cfg!(any(target_os = "macos", windows)) {
+        std::env::var_os("SSH_CONNECTION").is_none()
+    } else {
+        ["DISPLAY", "WAYLAND_DISPLAY"]
+            .into_iter()
+            .any(|var| std::env::var_os(var).is_some_and(|value| !value.is_empty()))
+    }
+}
+
+/// Shows `path` in the system's default image viewer for `decode --open`.
+/// The image is already written, so failing to launch a viewer, or having
+/// no display to show it on, is only a warning.
+#[cfg(feature = "viewer")]
+fn open_in_viewer(path: &str) {
+    if !has_display() {
+        warn!("No display available; not opening {}", path);
+        eprintln!("Warning: no display available; not opening {}", path);
+        return;
+    }
+    match open::that_detached(path) {
+        Ok(()) => info!("Opened {} in the default viewer", path),
+        Err(e) => {
+            warn!("Cannot open {} in the default viewer: {}", path, e);
+            eprintln!("Warning: cannot open {} in the default viewer: {}", path, e);
+        }
+    }
+}
+
+/// Reads the Base64 text for `decode`: fetched over HTTP for a URL, otherwise
+/// taken from the argument or stdin.
+fn read_decode_input(base64_input: &str, matches: &ArgMatches) -> Result<String, Box<dyn Error>> {
+    if !is_http_url(base64_input) {
+        return read_base64_arg(base64_input);
+    }
+
+    #[cfg(feature = "network")]
+    {
+        let timeout = Duration::from_secs(*matches.get_one::<u64>("timeout").unwrap());
+        fetch_base64_url(base64_input, timeout).inspect_err(|e| error!("{}", e))
+    }
+    #[cfg(not(feature = "network"))]
+    {
+        let _ = matches;
+        Err("Decoding from a URL requires the 'network' feature".into())
+    }
+}
+
+fn decode_with_header(
+    payload: &str,
+    output_path: &str,
+    url_safe: bool,
+    options: &DecodeOptions,
+) -> Result<(), Box<dyn Error>> {
+    let (line, base64_str) = payload
+        .split_once('\n')
+        .ok_or("Missing payload header line")?;
+    // Written by `encode --with-header --crlf`
+    let header = PayloadHeader::parse(line.strip_suffix('\r').unwrap_or(line))?;
+    info!("Parsed payload header: {}", header);
+
+    let (width, height, bytes) =
+        decode_base64_to_image(base64_str, output_path, url_safe, options)?;
+    if (width, height) != (header.width, header.height) || bytes != header.bytes {
+        error!("Decoded image does not match payload header");
+        return Err(format!(
+            "Decoded image is {}x{} ({} bytes) but header declares {}",
+            width, height, bytes, header
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Worker threads used by `batch-encode`.
+#[derive(Debug, Clone, Copy)]
+struct WorkerPool {
+    /// Number of images encoded in parallel
+    jobs: usize,
+    /// Stack size of every worker thread in bytes; when unset, Rust's
+    /// default of 2 MiB, or `RUST_MIN_STACK` if that is set
+    stack_size: Option<usize>,
+}
+
+/// Returns a builder for a named thread with `stack_size` bytes of stack,
+/// or the default stack size when unset.
+fn worker_thread(name: String, stack_size: Option<usize>) -> thread::Builder {
+    let builder = thread::Builder::new().name(name);
+    match stack_size {
+        Some(size) => builder.stack_size(size),
+        None => builder,
+    }
+}
+
+/// Runs `job` on a worker thread and waits at most `timeout` for it.
+///
+/// Returns `None` if the job did not finish in time. Threads cannot be
+/// cancelled, so a timed-out worker keeps running in the background until it
+/// finishes or the process exits. Without a timeout the job runs inline.
+fn run_with_timeout<T, F>(
+    timeout: Option<Duration>,
+    stack_size: Option<usize>,
+    job: F,
+) -> Option<Result<T, String>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, String> + Send + 'static,
+{
+    let Some(timeout) = timeout else {
+        return Some(job());
+    };
+
+    let (sender, receiver) = mpsc::sync_channel(1);
+    let spawned = worker_thread("timed-job".into(), stack_size).spawn(move || {
+        // The receiver is gone if we already gave up on this job
+        let _ = sender.send(job());
+    });
+    if let Err(e) = spawned {
+        return Some(Err(format!("failed to start worker thread: {}", e)));
+    }
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => Some(result),
+        Err(mpsc::RecvTimeoutError::Timeout) => None,
+        Err(mpsc::RecvTimeoutError::Disconnected) => Some(Err("worker thread panicked".into())),
+    }
+}
+
+/// Outcome of a batch encode.
+struct BatchEncoded {
+    /// `(path, base64)` pairs of the images that were encoded
+    encoded: Vec<(String, String)>,
+    /// Paths among `encoded` that were not images and hold their raw bytes
+    raw: HashSet<String>,
+    /// Perceptual hashes of the encoded images, with `options.phash`
+    phashes: HashMap<String, String>,
+    /// Paths whose encode exceeded the per-file timeout
+    timed_out: Vec<String>,
+    /// Totals over the encoded images
+    summary: BatchSummary,
+}
+
+/// Totals collected while a batch is encoded, reported by `--summary`.
+#[derive(Debug, Default)]
+struct BatchSummary {
+    images: usize,
+    /// Non-image files encoded as raw bytes; counted in the sizes only
+    raw_files: usize,
+    source_bytes: u64,
+    encoded_bytes: u64,
+    /// Sum of the per-image encoded/source size ratios
+    ratio_sum: f64,
+    /// Smallest and largest encoded dimensions, by pixel count
+    min_dimensions: Option<(u32, u32)>,
+    max_dimensions: Option<(u32, u32)>,
+}
+
+impl BatchSummary {
+    /// Adds one encoded image to the totals.
+    fn record(&mut self, source_bytes: u64, encoded_bytes: usize, dimensions: (u32, u32)) {
+        let pixels = |(w, h): (u32, u32)| w as u64 * h as u64;
+        self.images += 1;
+        self.source_bytes += source_bytes;
+        self.encoded_bytes += encoded_bytes as u64;
+        self.ratio_sum += encoded_bytes as f64 / source_bytes.max(1) as f64;
+        if self
+            .min_dimensions
+            .is_none_or(|min| pixels(dimensions) < pixels(min))
+        {
+            self.min_dimensions = Some(dimensions);
+        }
+        if self
+            .max_dimensions
+            .is_none_or(|max| pixels(dimensions) > pixels(max))
+        {
+            self.max_dimensions = Some(dimensions);
+        }
+    }
+
+    /// Adds one file encoded as raw bytes to the totals.
+    fn record_raw(&mut self, source_bytes: u64, encoded_bytes: usize) {
+        self.raw_files += 1;
+        self.source_bytes += source_bytes;
+        self.encoded_bytes += encoded_bytes as u64;
+    }
+
+    /// Returns the average encoded/source size ratio over the images.
+    fn average_ratio(&self) -> Option<f64> {
+        (self.images > 0).then(|| self.ratio_sum / self.images as f64)
+    }
+
+    /// Returns the totals as the `"summary"` object of `--json` output, with
+    /// sizes in bytes and dimensions as `[width, height]`.
+    fn to_json(&self, elapsed: Duration) -> serde_json::Value {
+        serde_json::json!({
+            "images": self.images,
+            "raw_files": self.raw_files,
+            "source_bytes": self.source_bytes,
+            "encoded_bytes": self.encoded_bytes,
+            "average_ratio": self.average_ratio(),
+            "min_dimensions": self.min_dimensions.map(|(w, h)| [w, h]),
+            "max_dimensions": self.max_dimensions.map(|(w, h)| [w, h]),
+            "elapsed_secs": elapsed.as_secs_f64(),
+        })
+    }
+
+    /// Prints the totals as a table on stderr, keeping stdout for results.
+    fn print(&self, elapsed: Duration) {
+        let dimensions = |dims: Option<(u32, u32)>| {
+            dims.map_or_else(|| "-".to_string(), |(w, h)| format!("{}x{}", w, h))
+        };
+        let average_ratio = self
+            .average_ratio()
+            .map_or_else(|| "-".to_string(), |ratio| format!("{:.3}", ratio));
+        let rows = [
+            ("Images", self.images.to_string()),
+            ("Raw files", self.raw_files.to_string()),
+            ("Source size", format_size(self.source_bytes)),
+            ("Encoded size", format_size(self.encoded_bytes)),
+            ("Average ratio", average_ratio),
+            ("Min dimensions", dimensions(self.min_dimensions)),
+            ("Max dimensions", dimensions(self.max_dimensions)),
+            ("Elapsed", format!("{:.2}s", elapsed.as_secs_f64())),
+        ];
+        eprintln!("Batch summary:");
+        for (label, value) in rows {
+            eprintln!("  {:<16}{:>16}", label, value);
+        }
+    }
+}
+
+/// Inputs a batch has already finished, kept in `--state-file` so that an
+/// interrupted batch resumes instead of starting over.
+///
+/// The file holds one completed key per line. Each key is appended with a
+/// single write once its output is on disk, so a crash leaves at most a
+/// partial last line, which the next run discards.
+struct BatchState {
+    file: Mutex<File>,
+    completed: HashSet<String>,
+}
+
+impl BatchState {
+    /// Opens the state file at `path`, creating it if needed. Keys recorded
+    /// by earlier runs are loaded unless `restart` is set, which empties the
+    /// file instead.
+    fn open(path: &Path, restart: bool) -> Result<Self, Box<dyn Error>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut content = String::new();
+        (&file).read_to_string(&mut content)?;
+        let kept = match restart {
+            true => 0,
+            false => content.rfind('\n').map_or(0, |end| end + 1),
+        };
+        if kept < content.len() {
+            file.set_len(kept as u64)?;
+        }
+        let completed: HashSet<String> = content[..kept].lines().map(str::to_string).collect();
+        if !completed.is_empty() {
+            info!(
+                "Loaded {} completed entries from {}",
+                completed.len(),
+                path.display()
+            );
+        }
+        Ok(BatchState {
+            file: Mutex::new(file),
+            completed,
+        })
+    }
+
+    /// Whether an earlier run already finished `key`.
+    fn is_done(&self, key: &str) -> bool {
+        self.completed.contains(key)
+    }
+
+    /// Records `key` as finished.
+    fn mark(&self, key: &str) -> std::io::Result<()> {
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(format!("{}\n", key).as_bytes())
+    }
+}
+
+/// Progress notifications from a batch encode.
+enum BatchEvent<'a> {
+    /// Worker `worker` started encoding `path`
+    Started { worker: usize, path: &'a str },
+    /// Worker `worker` finished an image; `done` images are complete in total
+    Finished { worker: usize, done: usize },
+}
+
+/// Result of encoding one image of a batch.
+enum BatchOutcome {
+    /// Base64 of the encoded image and, with `options.phash`, its
+    /// perceptual hash
+    Encoded(String, Option<String>),
+    /// Base64 of the file bytes of an input that is not an image
+    Raw(String),
+    TimedOut,
+    Failed(String),
+}
+
+/// Encodes a batch of images on the threads of `pool`, reporting progress
+/// and honouring cancellation.
+///
+/// `progress` receives a [`BatchEvent`] whenever a worker starts or finishes
+/// an image. The `cancel` flag is checked before each image; when it is set,
+/// the images encoded so far are returned without processing the rest.
+/// Images that take longer than `timeout` are recorded as timed out and
+/// skipped. With `options.raw_non_images`, inputs that fail to decode are
+/// encoded as raw bytes. `completed` is called on the worker with the path
+/// and Base64 of each image as soon as it is encoded; an error from it fails
+/// the batch. Results are in input order whatever the number of jobs.
+#[allow(clippy::too_many_arguments)]
+fn encode_multiple_images_with(
+    image_paths: &[String],
+    options: &EncodeOptions,
+    url_safe: bool,
+    timeout: Option<Duration>,
+    pool: WorkerPool,
+    progress: &(dyn Fn(BatchEvent<'_>) + Sync),
+    completed: &(dyn Fn(&str, &str) -> Result<(), String> + Sync),
+    cancel: &AtomicBool,
+) -> Result<BatchEncoded, Box<dyn Error>> {
+    let total = image_paths.len();
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    // One slot per input, left empty for images that were never started
+    let outcomes: Mutex<Vec<Option<BatchOutcome>>> = Mutex::new((0..total).map(|_| None).collect());
+    let summary = Mutex::new(BatchSummary::default());
+
+    thread::scope(|scope| -> std::io::Result<()> {
+        for worker in 0..pool.jobs.clamp(1, total.max(1)) {
+            let (next, done, failed, outcomes, summary) =
+                (&next, &done, &failed, &outcomes, &summary);
+            let thread = worker_thread(format!("encode-{}", worker), pool.stack_size);
+            thread.spawn_scoped(scope, move || loop {
+                // Stop taking new images once cancelled or after a failure
+                if cancel.load(Ordering::Relaxed) || failed.load(Ordering::Relaxed) {
+                    break;
+                }
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = image_paths.get(index) else {
+                    break;
+                };
+
+                progress(BatchEvent::Started { worker, path });
+                info!("Encoding image: {}", path);
+                let job_path = path.clone();
+                let job_options = options.clone();
+                let job = move || {
+                    let _span = phase_span!("image", path = job_path.as_str());
+                    let source_bytes = std::fs::metadata(&job_path)
+                        .map_err(|e| e.to_string())?
+                        .len();
+                    let img = match open_image(&job_path, job_options.auto_orient) {
+                        Ok(img) => img,
+                        Err(e) if job_options.raw_non_images => {
+                            info!("Encoding raw bytes of {}: {}", job_path, e);
+                            let data = std::fs::read(&job_path).map_err(|e| e.to_string())?;
+                            let encoded = base64_engine(url_safe).encode(&data);
+                            return Ok((encoded, source_bytes, None));
+                        }
+                        Err(e) => return Err(e.to_string()),
+                    };
+                    let image = encode_decoded_image(img, &job_path, &job_options)
+                        .map_err(|e| e.to_string())?;
+                    // Hashed as encoded, like `encode --phash`
+                    let phash = if job_options.phash {
+                        let decoded =
+                            image::load_from_memory(&image.data).map_err(|e| e.to_string())?;
+                        Some(perceptual_hash(&decoded))
+                    } else {
+                        None
+                    };
+                    let encoded = base64_engine(url_safe).encode(&image.data);
+                    Ok((
+                        encoded,
+                        source_bytes,
+                        Some((image.width, image.height, phash)),
+                    ))
+                };
+                let outcome = match run_with_timeout(timeout, pool.stack_size, job) {
+                    Some(Ok((encoded, source_bytes, Some((width, height, phash))))) => {
+                        summary.lock().unwrap().record(
+                            source_bytes,
+                            encoded.len(),
+                            (width, height),
+                        );
+                        BatchOutcome::Encoded(encoded, phash)
+                    }
+                    Some(Ok((encoded, source_bytes, None))) => {
+                        summary
+                            .lock()
+                            .unwrap()
+                            .record_raw(source_bytes, encoded.len());
+                        BatchOutcome::Raw(encoded)
+                    }
+                    Some(Err(e)) => {
+                        failed.store(true, Ordering::Relaxed);
+                        BatchOutcome::Failed(e)
+                    }
+                    None => {
+                        warn!("Timed out encoding {}", path);
+                        BatchOutcome::TimedOut
+                    }
+                };
+                let outcome = match &outcome {
+                    BatchOutcome::Encoded(encoded, _) | BatchOutcome::Raw(encoded) => {
+                        match completed(path, encoded) {
+                            Ok(()) => outcome,
+                            Err(e) => {
+                                failed.store(true, Ordering::Relaxed);
+                                BatchOutcome::Failed(e)
+                            }
+                        }
+                    }
+                    _ => outcome,
+                };
+                outcomes.lock().unwrap()[index] = Some(outcome);
+
+                let finished = done.fetch_add(1, Ordering::Relaxed) + 1;
+                progress(BatchEvent::Finished {
+                    worker,
+                    done: finished,
+                });
+            })?;
+        }
+        Ok(())
+    })?;
+
+    if cancel.load(Ordering::Relaxed) {
+        warn!(
+            "Batch encoding cancelled after {} of {} images",
+            done.load(Ordering::Relaxed),
+            total
+        );
+    }
+
+    let mut results = BatchEncoded {
+        encoded: Vec::with_capacity(total),
+        raw: HashSet::new(),
+        phashes: HashMap::new(),
+        timed_out: Vec::new(),
+        summary: summary.into_inner().unwrap(),
+    };
+    for (path, outcome) in image_paths.iter().zip(outcomes.into_inner().unwrap()) {
+        match outcome {
+            Some(BatchOutcome::Encoded(encoded, phash)) => {
+                if let Some(phash) = phash {
+                    results.phashes.insert(path.clone(), phash);
+                }
+                results.encoded.push((path.clone(), encoded));
+            }
+            Some(BatchOutcome::Raw(encoded)) => {
+                results.raw.insert(path.clone());
+                results.encoded.push((path.clone(), encoded));
+            }
+            Some(BatchOutcome::TimedOut) => results.timed_out.push(path.clone()),
+            Some(BatchOutcome::Failed(e)) => return Err(e.into()),
+            // Never started because of cancellation or another image's failure
+            None => {}
+        }
+    }
+
+    Ok(results)
+}
+
+/// Progress display for `batch-encode`: an overall bar, plus one line per
+/// worker showing its current file when several jobs run on a terminal.
+struct BatchProgressBars {
+    overall: ProgressBar,
+    workers: Vec<ProgressBar>,
+    _multi: Option<MultiProgress>,
+}
+
+impl BatchProgressBars {
+    fn new(total: usize, jobs: usize) -> Self {
+        let overall = ProgressBar::new(total as u64);
+        overall.set_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} images ({eta})")
+                .unwrap(),
+        );
+        // Worker lines only make sense when they can be redrawn in place
+        if jobs <= 1 || !std::io::stderr().is_terminal() {
+            return BatchProgressBars {
+                overall,
+                workers: Vec::new(),
+                _multi: None,
+            };
+        }
+
+        let multi = MultiProgress::new();
+        let overall = multi.add(overall);
+        let style = ProgressStyle::default_spinner()
+            .template("  {spinner} worker {prefix}: {wide_msg}")
+            .unwrap();
+        let workers = (0..jobs.min(total))
+            .map(|worker| {
+                let line = multi.add(ProgressBar::new_spinner());
+                line.set_style(style.clone());
+                line.set_prefix((worker + 1).to_string());
+                line.set_message("idle");
+                line.enable_steady_tick(Duration::from_millis(100));
+                line
+            })
+            .collect();
+        BatchProgressBars {
+            overall,
+            workers,
+            _multi: Some(multi),
+        }
+    }
+
+    fn update(&self, event: BatchEvent<'_>) {
+        match event {
+            BatchEvent::Started { worker, path } => {
+                if let Some(line) = self.workers.get(worker) {
+                    line.set_message(path.to_string());
+                }
+            }
+            BatchEvent::Finished { worker, done } => {
+                self.overall.set_position(done as u64);
+                if let Some(line) = self.workers.get(worker) {
+                    line.set_message("idle");
+                }
+            }
+        }
+    }
+
+    fn finish(&self) {
+        for line in &self.workers {
+            line.finish_and_clear();
+        }
+        self.overall.finish_and_clear();
+    }
+}
+
+/// Prints the files that exceeded the per-file timeout, if any.
+fn report_timeouts(timed_out: &[String], timeout: Option<Duration>) {
+    if let (Some(timeout), false) = (timeout, timed_out.is_empty()) {
+        eprintln!(
+            "{} file(s) timed out after {}s:",
+            timed_out.len(),
+            timeout.as_secs()
+        );
+        for path in timed_out {
+            eprintln!("  {}", path);
+        }
+    }
+}
+
+/// What `batch-encode --flatten` does when two inputs share a file stem.
+///
+/// Collisions are only detected between inputs of the same run; an existing
+/// file from an earlier run is always replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CollisionPolicy {
+    /// Fail before encoding anything
+    Error,
+    /// Append `-1`, `-2`, ... to the stem of later inputs
+    Rename,
+    /// Let later inputs replace the output of earlier ones
+    Overwrite,
+}
+
+/// Order in which `batch-encode` processes its images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    /// Byte-wise path order
+    Name,
+    /// File size, smallest first
+    Size,
+    /// Modification time, oldest first
+    Mtime,
+}
+
+/// Returns every file below `dir` whose extension belongs to a registered
+/// image format, or every file at all with `all_files`.
+fn collect_dir_images(dir: &str, all_files: bool) -> Vec<String> {
+    let registry = format_registry().read().unwrap();
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|entry| {
+            entry.file_type().is_file() && (all_files || registry.for_path(entry.path()).is_some())
+        })
+        .map(|entry| entry.path().to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Sorts image paths by `key` so a batch produces the same order on every
+/// run and machine. Equal sizes and times fall back to name order; files
+/// whose metadata cannot be read sort first and fail later when encoded.
+fn sort_image_paths(paths: &mut [String], key: SortKey, reverse: bool) {
+    paths.sort();
+    // Stable sorts, so name order is kept among equal keys
+    match key {
+        SortKey::Name => {}
+        SortKey::Size => {
+            paths.sort_by_cached_key(|path| std::fs::metadata(path).map_or(0, |m| m.len()))
+        }
+        SortKey::Mtime => {
+            paths.sort_by_cached_key(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        }
+    }
+    if reverse {
+        paths.reverse();
+    }
+}
+
+/// Where `batch-encode` writes its results.
+#[derive(Clone, Copy)]
+enum BatchOutput<'a> {
+    /// `path: base64` lines on stdout
+    Stdout,
+    /// `path: base64` lines in a single file
+    File(&'a str),
+    /// A JSON array of `{"path", "base64"}` objects in a file, or on stdout
+    /// when unset; raw non-image entries also carry `"raw": true`, and with
+    /// `--dedup` repeated entries carry `"same_as"` instead of `"base64"`
+    Json(Option<&'a str>),
+    /// One `.b64` file per input under a directory. Input paths are mirrored
+    /// below the directory unless `flatten` is set, in which case every
+    /// output is named after its input's file stem only.
+    Dir {
+        dir: &'a Path,
+        flatten: Option<CollisionPolicy>,
+    },
+}
+
+/// Returns `<output_dir>/<input path>.b64`, dropping any root or `..`
+/// components so the output always stays below `output_dir`.
+fn mirrored_output_path(output_dir: &Path, input: &str) -> PathBuf {
+    let mut relative: PathBuf = Path::new(input)
+        .components()
+        .filter(|c| matches!(c, std::path::Component::Normal(_)))
+        .collect();
+    let mut name = relative.file_name().unwrap_or_default().to_os_string();
+    name.push(".b64");
+    relative.set_file_name(name);
+    output_dir.join(relative)
+}
+
+/// Assigns every input a `<stem>.b64` output directly in `output_dir`,
+/// resolving stem collisions according to `policy`.
+fn flattened_output_paths(
+    image_paths: &[String],
+    output_dir: &Path,
+    policy: CollisionPolicy,
+) -> Result<HashMap<String, PathBuf>, Box<dyn Error>> {
+    let mut outputs = HashMap::new();
+    let mut claimed: HashMap<PathBuf, &str> = HashMap::new();
+
+    for input in image_paths {
+        if outputs.contains_key(input) {
+            continue;
+        }
+        let stem = Path::new(input)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| format!("Cannot derive an output name from '{}'", input))?;
+        let mut output = output_dir.join(format!("{}.b64", stem));
+
+        if let Some(previous) = claimed.get(&output) {
+            match policy {
+                CollisionPolicy::Error => {
+                    error!("Flattened output name collision for {}", output.display());
+                    return Err(format!(
+                        "'{}' and '{}' would both be written to {}",
+                        previous,
+                        input,
+                        output.display()
+                    )
+                    .into());
+                }
+                CollisionPolicy::Rename => {
+                    let mut suffix = 1;
+                    while claimed.contains_key(&output) {
+                        output = output_dir.join(format!("{}-{}.b64", stem, suffix));
+                        suffix += 1;
+                    }
+                    info!("Renamed output of {} to {}", input, output.display());
+                }
+                CollisionPolicy::Overwrite => {
+                    warn!(
+                        "{} overwrites the output of {} at {}",
+                        input,
+                        previous,
+                        output.display()
+                    );
+                }
+            }
+        }
+        claimed.insert(output.clone(), input);
+        outputs.insert(input.clone(), output);
+    }
+
+    Ok(outputs)
+}
+
+/// Encodes a batch of images and writes the results to `output`.
+///
+/// With a `state`, images an earlier run completed are skipped and every
+/// image is written and recorded as soon as it is encoded, which requires
+/// directory output; without one, nothing is written unless the whole batch
+/// succeeds. Setting `cancel`, as the Ctrl-C handler installed by
+/// `main` does, stops the batch after the images in progress.
+#[allow(clippy::too_many_arguments)]
+fn encode_multiple_images(
+    image_paths: Vec<String>,
+    options: &EncodeOptions,
+    url_safe: bool,
+    timeout: Option<Duration>,
+    pool: WorkerPool,
+    output: BatchOutput,
+    summary: bool,
+    state: Option<&BatchState>,
+    dedup: bool,
+    cancel: &AtomicBool,
+) -> Result<(), Box<dyn Error>> {
+    info!("Starting batch encoding of images");
+    let started = Instant::now();
+
+    // Resolve flattened names up front so collisions fail before any work.
+    // A resumed batch resolves them over all inputs, so renamed outputs keep
+    // the names they got in the first run.
+    let flattened = match output {
+        BatchOutput::Dir {
+            dir,
+            flatten: Some(policy),
+        } => Some(flattened_output_paths(&image_paths, dir, policy)?),
+        _ => None,
+    };
+    let dir_output_path = |dir: &Path, path: &str| match &flattened {
+        Some(outputs) => outputs[path].clone(),
+        None => mirrored_output_path(dir, path),
+    };
+
+    let image_paths = match state {
+        Some(state) => {
+            let total = image_paths.len();
+            let remaining: Vec<String> = image_paths
+                .into_iter()
+                .filter(|path| !state.is_done(path))
+                .collect();
+            if remaining.len() < total {
+                eprintln!(
+                    "Resuming: {} of {} images were completed by an earlier run",
+                    total - remaining.len(),
+                    total
+                );
+            }
+            remaining
+        }
+        None => image_paths,
+    };
+
+    // With a state file, outputs are written as each image finishes, so a
+    // crash keeps the finished files along with their record. Otherwise a
+    // failed batch leaves no output, as they are written once all succeed.
+    let write_completed = |path: &str, encoded: &str| -> Result<(), String> {
+        let (BatchOutput::Dir { dir, .. }, Some(state)) = (output, state) else {
+            return Ok(());
+        };
+        let output_path = dir_output_path(dir, path);
+        let write = || -> std::io::Result<()> {
+            if let Some(parent) = output_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&output_path, encoded)?;
+            state.mark(path)
+        };
+        write().map_err(|e| format!("Failed to write {}: {}", output_path.display(), e))
+    };
+
+    let bars = BatchProgressBars::new(image_paths.len(), pool.jobs);
+    let results = encode_multiple_images_with(
+        &image_paths,
+        options,
+        url_safe,
+        timeout,
+        pool,
+        &|event| bars.update(event),
+        &write_completed,
+        cancel,
+    );
+    bars.finish();
+    let results = results?;
+
+    if cancel.load(Ordering::Relaxed) {
+        eprintln!(
+            "Cancelled: encoded {} of {} images",
+            results.encoded.len(),
+            image_paths.len()
+        );
+    }
+    report_timeouts(&results.timed_out, timeout);
+
+    // With --dedup, the earlier input each result repeats exactly
+    let mut first_seen: HashMap<&str, &str> = HashMap::new();
+    let same_as: Vec<Option<&str>> = results
+        .encoded
+        .iter()
+        .map(|(path, encoded)| {
+            if !dedup {
+                return None;
+            }
+            let first = *first_seen.entry(encoded).or_insert(path);
+            (first != path).then_some(first)
+        })
+        .collect();
+    let duplicates = same_as.iter().flatten().count();
+    let line = |path: &str, encoded: &str, same_as: Option<&str>| match same_as {
+        Some(first) => format!("{}: same_as {}", path, first),
+        None => format!("{}: {}", path, encoded),
+    };
+
+    match output {
+        BatchOutput::Stdout => {
+            for ((path, encoded), &same_as) in results.encoded.iter().zip(&same_as) {
+                print_line(&line(path, encoded, same_as));
+            }
+        }
+        BatchOutput::File(output_path) => {
+            info!("Writing encoded results to file: {}", output_path);
+            let mut file = File::create(output_path)?;
+            for ((path, encoded), &same_as) in results.encoded.iter().zip(&same_as) {
+                write!(file, "{}{}", line(path, encoded, same_as), line_ending())?;
+            }
+        }
+        BatchOutput::Json(output_path) => {
+            let entries: Vec<_> = results
+                .encoded
+                .iter()
+                .zip(&same_as)
+                .map(|((path, encoded), &same_as)| {
+                    let mut entry = match same_as {
+                        Some(first) => serde_json::json!({ "path": path, "same_as": first }),
+                        None => serde_json::json!({ "path": path, "base64": encoded }),
+                    };
+                    if results.raw.contains(path) {
+                        entry["raw"] = true.into();
+                    }
+                    if let Some(phash) = results.phashes.get(path) {
+                        entry["phash"] = phash.as_str().into();
+                    }
+                    entry
+                })
+                .collect();
+            // With --summary the array moves under "images" next to the totals
+            let json = if summary {
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "images": entries,
+                    "summary": results.summary.to_json(started.elapsed()),
+                }))?
+            } else {
+                serde_json::to_string_pretty(&entries)?
+            };
+            match output_path {
+                Some(output_path) => {
+                    info!("Writing encoded results to file: {}", output_path);
+                    let json = with_line_endings(&json) + line_ending();
+                    std::fs::write(output_path, json.as_bytes())?;
+                }
+                None => print_line(&json),
+            }
+        }
+        BatchOutput::Dir { dir, .. } => {
+            info!("Encoded results written to directory: {}", dir.display());
+            for (path, encoded) in &results.encoded {
+                let output_path = dir_output_path(dir, path);
+                if state.is_none() {
+                    if let Some(parent) = output_path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    std::fs::write(&output_path, encoded)?;
+                }
+                println!("Encoded {} -> {}", path, output_path.display());
+            }
+        }
+    }
+    if dedup {
+        eprintln!("Collapsed {} duplicate images", duplicates);
+        info!("Collapsed {} duplicate images", duplicates);
+    }
+
+    if summary && !matches!(output, BatchOutput::Json(_)) {
+        results.summary.print(started.elapsed());
+    }
+    info!("Batch encoding completed successfully");
+    Ok(())
+}
+
+/// Decodes one Base64 image per line of `content` into `output_dir`,
+/// skipping lines whose decode exceeds `timeout`. With a `state`, images an
+/// earlier run wrote are skipped and each new one is recorded once written.
+fn decode_multiple_images(
+    content: &str,
+    output_dir: &str,
+    url_safe: bool,
+    timeout: Option<Duration>,
+    options: DecodeOptions,
+    state: Option<&BatchState>,
+) -> Result<(), Box<dyn Error>> {
+    let mut timed_out = Vec::new();
+    let mut resumed = 0;
+    for (i, line) in content.lines().enumerate() {
+        let output_path = format!("{}/image_{}.png", output_dir, i);
+        if state.is_some_and(|state| state.is_done(&output_path)) {
+            resumed += 1;
+            continue;
+        }
+        let job_line = line.to_owned();
+        let job_path = output_path.clone();
+        let job = move || {
+            let _span = phase_span!("image", line = i + 1);
+            decode_base64_to_image(&job_line, &job_path, url_safe, &options)
+                .map_err(|e| e.to_string())
+        };
+        match run_with_timeout(timeout, None, job) {
+            Some(result) => {
+                result?;
+                if let Some(state) = state {
+                    state.mark(&output_path)?;
+                }
+                println!("Decoded image {}", output_path);
+                info!("Decoded image {}", output_path);
+            }
+            None => {
+                warn!("Timed out decoding line {}", i + 1);
+                timed_out.push(format!("line {} ({})", i + 1, output_path));
+            }
+        }
+    }
+    if resumed > 0 {
+        eprintln!("Skipped {} images decoded by an earlier run", resumed);
+    }
+    report_timeouts(&timed_out, timeout);
     Ok(())
 }
 
-fn encode_multiple_images(
-    image_paths: Vec<String>,
-    format: Option<ImageFormat>,
-    quality: Option<u8>,
-    resize: Option<(u32, u32)>,
-    url_safe: bool,
-    output_file: Option<&str>,
-) -> Result<(), Box<dyn Error>> {
-    info!("Starting batch encoding of images");
-    let mut results = Vec::new();
-
-    for path in image_paths {
-        info!("Encoding image: {}", path);
-        let encoded = encode_image_to_base64(&path, format, quality, resize, url_safe)?;
-        results.push((path, encoded));
-    }
+/// Largest share of bytes outside both Base64 alphabets, padding and
+/// whitespace that a `decode-from-file` input may have; data URI prefixes
+/// and stray punctuation stay well below it.
+const MAX_NON_BASE64_RATIO: f64 = 0.1;
 
-    if let Some(output_path) = output_file {
-        info!("Writing encoded results to file: {}", output_path);
-        let mut file = File::create(output_path)?;
-        for (path, encoded) in results {
-            writeln!(file, "{}: {}", path, encoded)?;
-        }
-    } else {
-        for (path, encoded) in results {
-            println!("{}: {}", path, encoded);
-        }
+/// Rejects input that is clearly not Base64 text, such as an image file
+/// passed to `decode-from-file` by mistake, with an actionable message
+/// instead of a UTF-8 or Base64 decode error.
+fn check_base64_text(data: &[u8], path: &str) -> Result<(), Box<dyn Error>> {
+    let non_base64 = data
+        .iter()
+        .filter(|&&b| {
+            !(b.is_ascii_alphanumeric() || b"+/-_=".contains(&b) || b.is_ascii_whitespace())
+        })
+        .count();
+    if (non_base64 as f64) <= data.len() as f64 * MAX_NON_BASE64_RATIO {
+        return Ok(());
     }
 
-    info!("Batch encoding completed successfully");
-    Ok(())
+    error!(
+        "{} has {} of {} bytes outside the Base64 alphabet",
+        path,
+        non_base64,
+        data.len()
+    );
+    let hint = match image::guess_format(data) {
+        Ok(format) => format!(
+            "{} is a {} image; run 'encode {}' to convert it to Base64, or open it directly",
+            path,
+            format.extensions_str()[0].to_uppercase(),
+            path
+        ),
+        Err(_) => format!("check that {} holds Base64 text", path),
+    };
+    Err(format!(
+        "input does not appear to be Base64 text; did you mean to decode a raw image? {}",
+        hint
+    )
+    .into())
 }
 
 fn decode_from_file(
     base64_file: &str,
     output_dir: &str,
     url_safe: bool,
+    options: &DecodeOptions,
 ) -> Result<PathBuf, Box<dyn Error>> {
     info!("Starting to decode Base64 from file: {}", base64_file);
-    let base64_str = read_to_string(base64_file)?;
+    let raw = std::fs::read(base64_file)?;
+    let base64_str = match Algorithm::detect(&raw) {
+        Some(algorithm) => {
+            info!("Detected {}-compressed Base64, inflating", algorithm);
+            let inflated = algorithm::decompress_bytes(algorithm, &raw)?;
+            check_base64_text(&inflated, base64_file)?;
+            String::from_utf8(inflated)?
+        }
+        None => {
+            check_base64_text(&raw, base64_file)?;
+            String::from_utf8(raw)?
+        }
+    };
+    if base64_str.trim().is_empty() {
+        error!("No Base64 data in {}", base64_file);
+        return Err(format!("no Base64 data in {}", base64_file).into());
+    }
     let output_path = Path::new(output_dir).join("decoded_image.png");
 
-    decode_base64_to_image(&base64_str, output_path.to_str().unwrap(), url_safe)?;
+    decode_base64_to_image(
+        &base64_str,
+        output_path.to_str().unwrap(),
+        url_safe,
+        options,
+    )?;
     info!("Decoded image saved to {}", output_path.display());
 
     Ok(output_path)
 }
 
+#[cfg(feature = "watch")]
+fn watch_command() -> Command {
+    Command::new("watch")
+        .about("Re-encode images whenever they change")
+        .arg(
+            Arg::new("input")
+                .help("Image file or directory to watch")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::new("output-dir")
+                .help("Directory where .b64 outputs are written")
+                .required(true)
+                .index(2),
+        )
+        .arg(
+            Arg::new("format")
+                .help("Output format")
+                .long("format")
+                .short('f')
+                .value_parser(format_registry().read().unwrap().names()),
+        )
+        .arg(
+            Arg::new("url-safe")
+                .help("Use URL-safe Base64 encoding")
+                .long("url-safe")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("debounce-ms")
+                .help("Quiet period in milliseconds before re-encoding")
+                .long("debounce-ms")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("300"),
+        )
+}
+
+/// Encodes one watched image and writes `<output-dir>/<file name>.b64`.
+#[cfg(feature = "watch")]
+fn encode_watched(
+    path: &Path,
+    output_dir: &Path,
+    options: &EncodeOptions,
+    url_safe: bool,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let file_name = path.file_name().ok_or("Watched path has no file name")?;
+    let mut output_name = file_name.to_os_string();
+    output_name.push(".b64");
+    let output_path = output_dir.join(output_name);
+
+    let encoded =
+        encode_image_to_base64(path.to_str().ok_or("Non UTF-8 path")?, options, url_safe)?;
+    std::fs::write(&output_path, encoded)?;
+    Ok(output_path)
+}
+
+/// Watches a file or directory and re-encodes images as they change.
+///
+/// Events are debounced: after the first change, further events are collected
+/// until `debounce` passes without any, then every changed image is encoded once.
+#[cfg(feature = "watch")]
+fn watch_and_encode(
+    input: &str,
+    output_dir: &str,
+    options: &EncodeOptions,
+    url_safe: bool,
+    debounce: Duration,
+) -> Result<(), Box<dyn Error>> {
+    use notify::{EventKind, RecursiveMode, Watcher};
+    use std::collections::BTreeSet;
+    use std::sync::mpsc;
+
+    let output_dir = Path::new(output_dir);
+    std::fs::create_dir_all(output_dir)?;
+    let is_image =
+        |path: &Path| path.is_file() && format_registry().read().unwrap().for_path(path).is_some();
+
+    let report = |path: &Path| match encode_watched(path, output_dir, options, url_safe) {
+        Ok(output_path) => println!(
+            "[{}] Encoded {} -> {}",
+            humantime::format_rfc3339_seconds(std::time::SystemTime::now()),
+            path.display(),
+            output_path.display()
+        ),
+        Err(e) => {
+            error!("Failed to encode {}: {}", path.display(), e);
+            eprintln!("Failed to encode {}: {}", path.display(), e);
+        }
+    };
+
+    // Encode everything once so outputs exist before the first change
+    for entry in walkdir::WalkDir::new(input)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        if is_image(entry.path()) {
+            report(entry.path());
+        }
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(Path::new(input), RecursiveMode::Recursive)?;
+    println!("Watching {} for changes (Ctrl+C to stop)", input);
+
+    loop {
+        let mut changed = BTreeSet::new();
+        let mut event = rx.recv()?;
+        loop {
+            let event_result = event?;
+            if matches!(
+                event_result.kind,
+                EventKind::Create(_) | EventKind::Modify(_)
+            ) {
+                changed.extend(event_result.paths);
+            }
+            match rx.recv_timeout(debounce) {
+                Ok(next) => event = next,
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        for path in changed.iter().filter(|path| is_image(path)) {
+            info!("Change detected: {}", path.display());
+            report(path);
+        }
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
     info!("Starting image-base64 utility");
 
-    let matches = Command::new("image-base64")
+    let cli = Command::new("image-base64")
         .version("2.1")
         .author("Max Qian <astro_air@126.com>")
         .about("Advanced image Base64 encoding/decoding tool")
@@ -168,60 +3939,606 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .about("Encode an image to Base64")
                 .arg(
                     Arg::new("image")
-                        .help("Path to the image to encode")
+                        .help("Path to the image to encode, or an http(s) URL to download")
                         .required(true)
                         .index(1),
                 )
                 .arg(
                     Arg::new("format")
-                        .help("Output format (jpeg, png, webp, bmp)")
+                        .help("Output format")
                         .long("format")
                         .short('f')
-                        .value_parser(["jpeg", "png", "webp", "bmp"]),
+                        .value_parser(format_registry().read().unwrap().names()),
                 )
                 .arg(
                     Arg::new("quality")
-                        .help("JPEG quality (1-100)")
+                        .help("JPEG quality, or lossy WebP color quality (1-100)")
                         .long("quality")
                         .short('q')
-                        .value_parser(1..=100),
+                        .value_parser(clap::value_parser!(u8).range(1..=100)),
+                )
+                .arg(
+                    Arg::new("alpha-quality")
+                        .help("Lossy WebP alpha channel quality (1-100), independent of --quality")
+                        .long("alpha-quality")
+                        .value_parser(clap::value_parser!(u8).range(1..=100)),
+                )
+                .arg(
+                    Arg::new("trim")
+                        .help("Crop away a uniform border matching the top-left pixel before any other transform")
+                        .long("trim")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("trim-tolerance")
+                        .help("Largest per-channel difference (0-255) from the border color still trimmed")
+                        .long("trim-tolerance")
+                        .requires("trim")
+                        .value_parser(clap::value_parser!(u8))
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("resize")
+                        .help("Resize image to WxH pixels")
+                        .long("resize")
+                        .short('s')
+                        .value_parser(clap::value_parser!(u32))
+                        .number_of_values(2),
+                )
+                .arg(
+                    Arg::new("max-resize-pixels")
+                        .help("Maximum number of pixels a resize may produce")
+                        .long("max-resize-pixels")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("100000000"),
+                )
+                .arg(
+                    Arg::new("no-auto-orient")
+                        .help("Keep the stored pixel layout instead of applying the EXIF orientation")
+                        .long("no-auto-orient")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("max-dimension")
+                        .help("Downscale so the longest side is at most N pixels (applied after --resize)")
+                        .long("max-dimension")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                )
+                .arg(
+                    Arg::new("normalize")
+                        .help("Stretch contrast so the darkest and brightest samples span the full range")
+                        .long("normalize")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("auto-level")
+                        .help("Like --normalize, but stretch each color channel independently")
+                        .long("auto-level")
+                        .conflicts_with("normalize")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("posterize")
+                        .help("Reduce each color channel to N levels (2-256)")
+                        .long("posterize")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u16).range(2..=256)),
+                )
+                .arg(
+                    Arg::new("dither")
+                        .help("Use Floyd-Steinberg error diffusion when posterizing")
+                        .long("dither")
+                        .requires("posterize")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("raw-pixels")
+                        .help("Treat the input as headerless 8-bit pixel data")
+                        .long("raw-pixels")
+                        .requires("width")
+                        .requires("height")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("width")
+                        .help("Width of the raw pixel data")
+                        .long("width")
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                )
+                .arg(
+                    Arg::new("height")
+                        .help("Height of the raw pixel data")
+                        .long("height")
+                        .value_parser(clap::value_parser!(u32).range(1..)),
+                )
+                .arg(
+                    Arg::new("pixel-format")
+                        .help("Pixel layout of the raw pixel data")
+                        .long("pixel-format")
+                        .value_parser(PixelFormat::NAMES)
+                        .default_value("rgb8"),
+                )
+                .arg(
+                    Arg::new("png-compression")
+                        .help("PNG compression level")
+                        .long("png-compression")
+                        .value_parser(["fast", "default", "best"]),
+                )
+                .arg(
+                    Arg::new("png-filter")
+                        .help("PNG row filter strategy")
+                        .long("png-filter")
+                        .value_parser(["none", "sub", "up", "avg", "paeth", "adaptive"]),
+                )
+                .arg(
+                    Arg::new("bit-depth-reduce")
+                        .help("Write PNG at the smallest lossless bit depth (1, 2, 4 or 8)")
+                        .long_help(
+                            "Write PNG at the smallest lossless bit depth. The pixel histogram \
+                             decides between grayscale, when every pixel is an opaque gray \
+                             level the lower depth can represent, and a palette of up to 256 \
+                             colors (transparency included); whichever needs fewer bits wins, \
+                             grayscale on ties. Images with more colors are written as usual. \
+                             The chosen depth is reported on stderr.",
+                        )
+                        .long("bit-depth-reduce")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("url-safe")
+                        .help("Use URL-safe Base64 encoding")
+                        .long("url-safe")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("crlf")
+                        .help("End output lines with CRLF instead of LF, including header, chunk, JSON and source lines")
+                        .long("crlf")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("output")
+                        .help("Output file for Base64 string")
+                        .long("output")
+                        .short('o'),
+                )
+                .arg(
+                    Arg::new("compress")
+                        .help("Compress the Base64 output (conventionally saved as .b64.gz / .b64.zst)")
+                        .long("compress")
+                        .value_parser(
+                            [Algorithm::Gzip, Algorithm::Zstd]
+                                .into_iter()
+                                .filter(|a| a.is_available())
+                                .map(Algorithm::name)
+                                .collect::<Vec<_>>(),
+                        ),
+                )
+                .arg(
+                    Arg::new("with-header")
+                        .help("Prefix the output with a 'WxH FORMAT BYTES' header line")
+                        .long("with-header")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("chunk")
+                        .help("Split the output into chunks of at most N characters, written to <output>.part0, <output>.part1, ...")
+                        .long("chunk")
+                        .value_name("N")
+                        .conflicts_with("compress")
+                        .value_parser(clap::value_parser!(u64).range(1..)),
+                )
+                .arg(
+                    Arg::new("json")
+                        .help("With --chunk or --split-alpha, write a JSON array instead of separate files")
+                        .long("json")
+                        .requires("json-layout")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("split-alpha")
+                        .help("Encode the RGB and alpha channels as two images, written to <output>.rgb.b64 and <output>.alpha.b64")
+                        .long("split-alpha")
+                        .conflicts_with_all([
+                            "chunk",
+                            "compress",
+                            "svg",
+                            "with-header",
+                            "verify-roundtrip",
+                            "checksum",
+                            "phash",
+                        ])
+                        .action(ArgAction::SetTrue),
+                )
+                .group(ArgGroup::new("json-layout").args(["chunk", "split-alpha"]))
+                .arg(
+                    Arg::new("lqip")
+                        .help("Also build a blurred placeholder at most N pixels wide or high; prints {\"image\": <Base64>, \"lqip\": <data URI>} as JSON")
+                        .long("lqip")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u32).range(1..=256))
+                        .conflicts_with_all([
+                            "chunk",
+                            "compress",
+                            "svg",
+                            "with-header",
+                            "split-alpha",
+                            "as-source",
+                        ]),
+                )
+                .arg(
+                    Arg::new("variants")
+                        .help("Encode one scaled copy per pixel density, e.g. 1x,2x,3x; the largest keeps the image size")
+                        .long_help(
+                            "Encode one scaled copy per pixel density, e.g. 1x,2x,3x, from a single \
+                             decode. The largest density keeps the size of the image after the other \
+                             transforms and the rest scale down in proportion, so 1x,2x of a 1200px \
+                             wide image gives 600px and 1200px. Prints a JSON object mapping each \
+                             variant label (1x, 1.5x, 2x, ...) to its Base64, unless \
+                             --variant-output is given.",
+                        )
+                        .long("variants")
+                        .value_name("DENSITIES")
+                        .value_delimiter(',')
+                        .value_parser(parse_density_variant)
+                        .conflicts_with_all([
+                            "chunk",
+                            "compress",
+                            "svg",
+                            "with-header",
+                            "split-alpha",
+                            "lqip",
+                            "as-source",
+                            "verify-roundtrip",
+                            "checksum",
+                            "phash",
+                        ]),
+                )
+                .arg(
+                    Arg::new("widths")
+                        .help("Encode one scaled copy per width in pixels, e.g. 320,640,1280, keeping the aspect ratio")
+                        .long_help(
+                            "Encode one scaled copy per width in pixels, e.g. 320,640,1280, from a \
+                             single decode, keeping the aspect ratio. Widths beyond the image are \
+                             not upscaled. Prints a JSON object mapping each variant label (320w, \
+                             640w, ...) to its Base64, unless --variant-output is given.",
+                        )
+                        .long("widths")
+                        .value_name("WIDTHS")
+                        .value_delimiter(',')
+                        .value_parser(parse_width_variant)
+                        .conflicts_with("variants")
+                        .conflicts_with_all([
+                            "chunk",
+                            "compress",
+                            "svg",
+                            "with-header",
+                            "split-alpha",
+                            "lqip",
+                            "as-source",
+                            "verify-roundtrip",
+                            "checksum",
+                            "phash",
+                        ]),
+                )
+                .arg(
+                    Arg::new("variant-output")
+                        .help("Write each variant's Base64 to its own file named by PATTERN instead of printing JSON")
+                        .long_help(
+                            "Write each variant's Base64 to its own file instead of printing JSON. \
+                             In PATTERN, {label} is replaced by the variant label (2x, 640w), \
+                             {width} and {height} by the variant's size in pixels, e.g. \
+                             hero-{label}.b64 or hero-{width}x{height}.b64. The pattern must \
+                             contain {label} or {width}.",
+                        )
+                        .long("variant-output")
+                        .value_name("PATTERN")
+                        .value_parser(parse_variant_pattern)
+                        .requires("variant-list")
+                        .conflicts_with("output"),
+                )
+                .group(ArgGroup::new("variant-list").args(["variants", "widths"]))
+                .arg(
+                    Arg::new("as-source")
+                        .help("Output the raw image bytes as a C or Rust array instead of Base64")
+                        .long("as-source")
+                        .value_parser(["c", "rust"])
+                        .conflicts_with_all([
+                            "chunk",
+                            "compress",
+                            "svg",
+                            "with-header",
+                            "url-safe",
+                            "verify-roundtrip",
+                            "split-alpha",
+                        ]),
+                )
+                .arg(
+                    Arg::new("var-name")
+                        .help("Identifier of the array written by --as-source (default: image for C, IMAGE for Rust)")
+                        .long("var-name")
+                        .requires("as-source")
+                        .value_parser(parse_identifier),
+                )
+                .arg(
+                    Arg::new("svg")
+                        .help("Output an SVG document embedding the image as a data URI")
+                        .long("svg")
+                        .conflicts_with_all(["url-safe", "with-header"])
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("max-output-bytes")
+                        .help("Fail before encoding if the Base64 payload, ceil(image bytes / 3) * 4, would exceed N bytes; --svg and --with-header wrapping is not counted")
+                        .long("max-output-bytes")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u64).range(1..)),
+                )
+                .arg(
+                    Arg::new("verify-roundtrip")
+                        .help("Decode the output as written and fail unless it matches the source image; pixels are compared only for lossless formats")
+                        .long("verify-roundtrip")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("checksum")
+                        .help("Print a checksum of the encoded image bytes")
+                        .long("checksum")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("hash")
+                        .help("Hash algorithm used for the checksum")
+                        .long("hash")
+                        .value_parser(HashAlgorithm::NAMES)
+                        .default_value("sha256"),
+                )
+                .arg(
+                    Arg::new("phash")
+                        .help("Print a 64-bit perceptual hash (DCT pHash) of the encoded image")
+                        .long("phash")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("decode")
+                .about("Decode Base64 to image")
+                .arg(
+                    Arg::new("base64")
+                        .help("Base64 string, '-' to read from stdin, or an http(s) URL to fetch")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("output")
+                        .help("Output image path")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::new("url-safe")
+                        .help("Use URL-safe Base64 decoding")
+                        .long("url-safe")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("format")
+                        .help("Write the image in this format instead of PNG; ppm, pgm and pbm are plain text")
+                        .long("format")
+                        .short('f')
+                        .value_parser(format_registry().read().unwrap().names()),
+                )
+                .arg(
+                    Arg::new("sidecar")
+                        .help("Write <output>.json describing the decoded image")
+                        .long("sidecar")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("auto-alphabet")
+                        .help("On failure, retry with the other Base64 alphabet and the unpadded variants")
+                        .long("auto-alphabet")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("with-header")
+                        .help("Expect and validate a 'WxH FORMAT BYTES' header line")
+                        .long("with-header")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("parts")
+                        .help("Treat the input as 'encode --chunk' output: a JSON array file or the prefix of .partN files")
+                        .long("parts")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("input-format")
+                        .help("Decode the data as this format instead of detecting it")
+                        .long("input-format")
+                        .value_parser(format_registry().read().unwrap().names()),
+                )
+                .arg(
+                    Arg::new("as")
+                        .help("Convert the decoded image to this pixel layout; may add or drop an alpha channel")
+                        .long("as")
+                        .value_parser(PixelFormat::NAMES),
+                )
+                .arg(
+                    Arg::new("expect-size")
+                        .help("Fail without writing the output unless the image is WxH pixels")
+                        .long("expect-size")
+                        .value_name("WxH")
+                        .value_parser(parse_size),
+                )
+                .arg(
+                    Arg::new("expect-format")
+                        .help("Fail without writing the output unless the data is detected as this format")
+                        .long("expect-format")
+                        .value_parser(format_registry().read().unwrap().names()),
+                )
+                .arg(
+                    Arg::new("mmap")
+                        .help("Encode PNG, BMP and TIFF output straight into a memory map of the pre-sized file")
+                        .long_help(concat!(
+                            "Encode the output straight into a memory map of the output file.\n\n",
+                            "For PNG, the default, and for BMP and TIFF, the file is pre-sized to the ",
+                            "raw pixel data plus room for headers, mapped, and the encoder writes into ",
+                            "the map, growing it if needed; the file is then cut to the written length. ",
+                            "Other formats are encoded in memory and written normally, as are outputs ",
+                            "that are not regular files and systems where mapping fails.\n\n",
+                            "The pre-sized file is sparse on most Unix filesystems: running out of ",
+                            "disk space, or another process truncating the file, ends the process ",
+                            "with SIGBUS instead of an error. On Windows other processes cannot ",
+                            "resize or delete the file while it is mapped."
+                        ))
+                        .long("mmap")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("info")
+                .about("Show the dimensions of a Base64 image without decoding it")
+                .arg(
+                    Arg::new("base64")
+                        .help("Base64 string or '-' to read from stdin")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("url-safe")
+                        .help("Use URL-safe Base64 decoding")
+                        .long("url-safe")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("phash")
+                        .help("Also print a 64-bit perceptual hash (DCT pHash); requires a full decode")
+                        .long("phash")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("colors")
+                        .help("Also count distinct RGBA colors, up to 1048576; requires a full decode")
+                        .long("colors")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("verbose")
+                        .help("Also print the format and color type in text output")
+                        .short('v')
+                        .long("verbose")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("output-format")
+                        .help("Print plain text or a JSON object (format, width, height, color_type, valid, phash, unique_colors, unique_colors_capped); --verbose only affects text output")
+                        .long("output-format")
+                        .value_parser(["text", "json"])
+                        .default_value("text"),
+                ),
+        )
+        .subcommand(
+            Command::new("bench-encode")
+                .about("Time in-memory encoding of an image in every supported format")
+                .arg(
+                    Arg::new("image")
+                        .help("Image file, decoded once and reused for every run")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("iterations")
+                        .help("Timed runs per format, after one untimed warm-up run")
+                        .long("iterations")
+                        .short('n')
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .help("Print the ranked results as JSON")
+                        .long("json")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("histogram")
+                .about("Print per-channel histograms of an image as JSON")
+                .long_about(
+                    "Print per-channel histograms of an image as JSON: an array of 256 bins per \
+                     channel, where bin i counts the samples whose 8-bit value is i. 16-bit and \
+                     float images are converted to 8 bits first, so each bin covers 1/256 of the \
+                     full range. Color images get red, green, blue and luminance (Rec. 709 \
+                     weights), grayscale images only luminance, and images with transparency \
+                     also alpha.",
+                )
+                .arg(
+                    Arg::new("image")
+                        .help("Image file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("gray")
+                        .help("Only compute the luminance histogram (and alpha, if present)")
+                        .long("gray")
+                        .action(ArgAction::SetTrue),
                 )
                 .arg(
-                    Arg::new("resize")
-                        .help("Resize image to WxH pixels")
-                        .long("resize")
-                        .short('s')
-                        .value_parser(clap::value_parser!(u32))
-                        .number_of_values(2),
+                    Arg::new("render")
+                        .help("Draw the histograms as a 256-pixel-wide chart image instead of printing JSON")
+                        .long("render")
+                        .value_name("PATH"),
+                ),
+        )
+        .subcommand(
+            Command::new("ssim")
+                .about("Compare two image files by structural similarity (SSIM)")
+                .arg(
+                    Arg::new("first")
+                        .help("Reference image")
+                        .required(true)
+                        .index(1),
                 )
                 .arg(
-                    Arg::new("url-safe")
-                        .help("Use URL-safe Base64 encoding")
-                        .long("url-safe")
-                        .action(ArgAction::SetTrue),
+                    Arg::new("second")
+                        .help("Image compared against the reference; resized to match if needed")
+                        .required(true)
+                        .index(2),
                 )
                 .arg(
-                    Arg::new("output")
-                        .help("Output file for Base64 string")
-                        .long("output")
-                        .short('o'),
+                    Arg::new("threshold")
+                        .help("Exit with an error when the SSIM index is below this value (at most 1.0)")
+                        .long("threshold")
+                        .value_parser(clap::value_parser!(f64)),
                 ),
         )
         .subcommand(
-            Command::new("decode")
-                .about("Decode Base64 to image")
+            Command::new("pixel")
+                .about("Print the RGBA color of one pixel of a Base64 image")
                 .arg(
                     Arg::new("base64")
-                        .help("Base64 string or '-' to read from stdin")
+                        .help("Base64 string, a file containing it, or '-' to read from stdin")
                         .required(true)
                         .index(1),
                 )
                 .arg(
-                    Arg::new("output")
-                        .help("Output image path")
+                    Arg::new("x")
+                        .help("Column of the pixel, from 0 at the left")
                         .required(true)
+                        .value_parser(clap::value_parser!(u32))
                         .index(2),
                 )
+                .arg(
+                    Arg::new("y")
+                        .help("Row of the pixel, from 0 at the top")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u32))
+                        .index(3),
+                )
                 .arg(
                     Arg::new("url-safe")
                         .help("Use URL-safe Base64 decoding")
@@ -249,6 +4566,18 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .help("Use URL-safe Base64")
                         .long("url-safe")
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("input-format")
+                        .help("Decode the data as this format instead of detecting it")
+                        .long("input-format")
+                        .value_parser(format_registry().read().unwrap().names()),
+                )
+                .arg(
+                    Arg::new("as")
+                        .help("Convert the decoded image to this pixel layout; may add or drop an alpha channel")
+                        .long("as")
+                        .value_parser(PixelFormat::NAMES),
                 ),
         )
         .subcommand(
@@ -257,26 +4586,103 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .arg(
                     Arg::new("images")
                         .help("Comma-separated list of image paths")
-                        .required(true)
+                        .required_unless_present("dir")
                         .value_delimiter(','),
                 )
+                .arg(
+                    Arg::new("dir")
+                        .help("Also encode every image with a registered extension below DIR")
+                        .long("dir")
+                        .value_name("DIR"),
+                )
+                .arg(
+                    Arg::new("sort")
+                        .help("Order in which images found by --dir are encoded and written, after the listed images")
+                        .long("sort")
+                        .value_parser(["name", "size", "mtime"])
+                        .default_value("name"),
+                )
+                .arg(
+                    Arg::new("reverse")
+                        .help("Reverse the --sort order of images found by --dir")
+                        .long("reverse")
+                        .action(ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("output")
                         .help("Output file for results")
                         .long("output")
-                        .short('o'),
+                        .short('o')
+                        .conflicts_with("output-dir"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .help("Write the results as a JSON array of {\"path\", \"base64\"} objects")
+                        .long("json")
+                        .conflicts_with("output-dir")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("dedup")
+                        .help("Output each distinct encoding once; later identical images refer to the first as 'path: same_as FIRST', or {\"path\", \"same_as\"} with --json")
+                        .long("dedup")
+                        .conflicts_with("output-dir")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("crlf")
+                        .help("End the 'path: base64' and JSON output lines with CRLF instead of LF")
+                        .long("crlf")
+                        .conflicts_with("output-dir")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("phash")
+                        .help("Add a \"phash\" field with the 64-bit perceptual hash (DCT pHash) of each encoded image to the --json entries")
+                        .long("phash")
+                        .requires("json")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("raw-non-images")
+                        .help("Base64 the raw bytes of files that cannot be decoded as images instead of failing; with --dir, include every file")
+                        .long("raw-non-images")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("output-dir")
+                        .help("Write one .b64 file per image, mirroring the input paths below DIR")
+                        .long("output-dir")
+                        .value_name("DIR"),
+                )
+                .arg(
+                    Arg::new("flatten")
+                        .help("Write every output directly into --output-dir, named after the file stem only")
+                        .long("flatten")
+                        .requires("output-dir")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("on-collision")
+                        .help("With --flatten, what to do when two images share a file stem: fail before encoding, append -1, -2, ... to later names, or let later images overwrite earlier ones")
+                        .long("on-collision")
+                        .requires("flatten")
+                        .value_parser(["error", "rename", "overwrite"])
+                        .default_value("error"),
                 )
                 .arg(
                     Arg::new("format")
                         .help("Output format for all images")
                         .long("format")
-                        .short('f'),
+                        .short('f')
+                        .value_parser(format_registry().read().unwrap().names()),
                 )
                 .arg(
                     Arg::new("quality")
                         .help("JPEG quality for all images")
                         .long("quality")
-                        .short('q'),
+                        .short('q')
+                        .value_parser(clap::value_parser!(u8).range(1..=100)),
                 )
                 .arg(
                     Arg::new("resize")
@@ -286,11 +4692,74 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .value_parser(clap::value_parser!(u32))
                         .number_of_values(2),
                 )
+                .arg(
+                    Arg::new("max-resize-pixels")
+                        .help("Maximum number of pixels a resize may produce")
+                        .long("max-resize-pixels")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("100000000"),
+                )
+                .arg(
+                    Arg::new("no-auto-orient")
+                        .help("Keep the stored pixel layout instead of applying the EXIF orientation")
+                        .long("no-auto-orient")
+                        .action(ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("url-safe")
                         .help("Use URL-safe Base64")
                         .long("url-safe")
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("per-file-timeout")
+                        .help("Skip files whose processing takes longer than SECS seconds")
+                        .long("per-file-timeout")
+                        .value_name("SECS")
+                        .value_parser(clap::value_parser!(u64).range(1..)),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .help("Number of images to encode in parallel")
+                        .long("jobs")
+                        .short('j')
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::new("stack-size")
+                        .help("Stack size of each worker thread; defaults to 2 MiB, or RUST_MIN_STACK when set")
+                        .long("stack-size")
+                        .value_name("BYTES")
+                        .value_parser(clap::value_parser!(u64).range(65536..)),
+                )
+                .arg(
+                    Arg::new("summary")
+                        .help("Print totals, average size ratio, dimension range and elapsed time to stderr; with --json, add them as a \"summary\" object and move the results under \"images\"")
+                        .long("summary")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("bytes")
+                        .help("Print summary sizes as plain byte counts instead of KiB/MiB/GiB")
+                        .long("bytes")
+                        .requires("summary")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("state-file")
+                        .help("Record finished images in PATH and skip those it lists, so an interrupted batch resumes; needs --output-dir")
+                        .long("state-file")
+                        .value_name("PATH")
+                        .requires("output-dir"),
+                )
+                .arg(
+                    Arg::new("restart")
+                        .help("Ignore and clear the images recorded in --state-file")
+                        .long("restart")
+                        .requires("state-file")
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
@@ -311,38 +4780,379 @@ fn main() -> Result<(), Box<dyn Error>> {
                         .help("Use URL-safe Base64")
                         .long("url-safe")
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("per-file-timeout")
+                        .help("Skip files whose processing takes longer than SECS seconds")
+                        .long("per-file-timeout")
+                        .value_name("SECS")
+                        .value_parser(clap::value_parser!(u64).range(1..)),
+                )
+                .arg(
+                    Arg::new("sidecar")
+                        .help("Write <image>.json next to each decoded image")
+                        .long("sidecar")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("single")
+                        .help("Join all lines into one Base64 string and decode a single image")
+                        .long_help(concat!(
+                            "Join all lines into one Base64 string and decode a single image.\n\n",
+                            "By default every line is a separate image. Use --single for a file ",
+                            "holding one image whose Base64 is wrapped across lines, as written ",
+                            "by `base64` or MIME encoders."
+                        ))
+                        .long("single")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("state-file")
+                        .help("Record decoded images in PATH and skip those it lists, so an interrupted batch resumes")
+                        .long("state-file")
+                        .value_name("PATH"),
+                )
+                .arg(
+                    Arg::new("restart")
+                        .help("Ignore and clear the images recorded in --state-file")
+                        .long("restart")
+                        .requires("state-file")
+                        .action(ArgAction::SetTrue),
                 ),
+        );
+    #[cfg(feature = "watch")]
+    let cli = cli.subcommand(watch_command());
+    #[cfg(feature = "network")]
+    let cli = cli
+        .mut_subcommand("encode", |encode| {
+            encode.arg(
+                Arg::new("timeout")
+                    .help("Timeout in seconds for each attempt to download the image from a URL")
+                    .long("timeout")
+                    .value_name("SECS")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .default_value("30"),
+            )
+        })
+        .mut_subcommand("decode", |decode| {
+            decode.arg(
+                Arg::new("timeout")
+                    .help("Timeout in seconds when fetching Base64 from a URL")
+                    .long("timeout")
+                    .value_name("SECS")
+                    .value_parser(clap::value_parser!(u64).range(1..))
+                    .default_value("30"),
+            )
+        });
+    #[cfg(feature = "viewer")]
+    let cli = cli.mut_subcommand("decode", |decode| {
+        decode.arg(
+            Arg::new("open")
+                .help("Show the decoded image in the system's default viewer; skipped without a display")
+                .long("open")
+                .action(ArgAction::SetTrue),
+        )
+    });
+    #[cfg(feature = "metadata")]
+    let cli = cli.mut_subcommand("encode", |encode| {
+        encode.arg(
+            Arg::new("embed-thumbnail")
+                .help("Embed an EXIF preview thumbnail at most N pixels on its longest side (JPEG only)")
+                .long("embed-thumbnail")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32).range(1..=512)),
         )
-        .get_matches();
+    });
+    #[cfg(feature = "clipboard")]
+    let cli = cli.mut_subcommand("encode", |encode| {
+        encode
+            .mut_arg("image", |image| {
+                image
+                    .required(false)
+                    .required_unless_present("from-clipboard")
+            })
+            .arg(
+                Arg::new("from-clipboard")
+                    .help("Encode the image on the system clipboard instead of a file")
+                    .long("from-clipboard")
+                    .conflicts_with_all(["image", "raw-pixels"])
+                    .action(ArgAction::SetTrue),
+            )
+    });
+    #[cfg(feature = "tracing")]
+    let cli = cli.arg(
+        Arg::new("trace-json")
+            .help("Write a Chrome-trace-format timeline of pipeline phases to FILE")
+            .long("trace-json")
+            .value_name("FILE")
+            .global(true),
+    );
+    let matches = cli.get_matches();
+
+    // Held until `main` returns so the whole run is flushed to the trace file
+    #[cfg(feature = "tracing")]
+    let _trace_guard = matches
+        .get_one::<String>("trace-json")
+        .map(|path| profiling::init_chrome_trace(path))
+        .transpose()?;
 
     match matches.subcommand() {
         Some(("encode", sub_matches)) => {
-            let image_path = sub_matches.get_one::<String>("image").unwrap();
-            let format = sub_matches
-                .get_one::<String>("format")
-                .map(|f| match f.as_str() {
-                    "jpeg" => ImageFormat::Jpeg,
-                    "png" => ImageFormat::Png,
-                    "webp" => ImageFormat::WebP,
-                    "bmp" => ImageFormat::Bmp,
-                    _ => unreachable!(),
-                });
+            // Only missing with --from-clipboard
+            let image_arg = sub_matches.get_one::<String>("image");
+            let (local_path, _download) = match image_arg {
+                Some(image) => resolve_encode_input(image, sub_matches)?,
+                None => (CLIPBOARD_IMAGE_NAME.to_string(), None),
+            };
+            let image_path = &local_path;
+            let format = format_arg(sub_matches);
             let quality = sub_matches.get_one::<u8>("quality").copied();
             let resize = sub_matches
                 .get_many::<u32>("resize")
                 .map(|mut vals| (*vals.next().unwrap(), *vals.next().unwrap()));
             let url_safe = sub_matches.get_flag("url-safe");
             let output = sub_matches.get_one::<String>("output");
+            let hash: HashAlgorithm = sub_matches.get_one::<String>("hash").unwrap().parse()?;
 
             info!("Encoding image: {}", image_path);
-            let base64_str = encode_image_to_base64(image_path, format, quality, resize, url_safe)?;
+            CRLF.store(sub_matches.get_flag("crlf"), Ordering::Relaxed);
+            let png_compression = sub_matches
+                .get_one::<String>("png-compression")
+                .map(|c| match c.as_str() {
+                    "fast" => CompressionType::Fast,
+                    "default" => CompressionType::Default,
+                    "best" => CompressionType::Best,
+                    _ => unreachable!(),
+                });
+            let png_filter =
+                sub_matches
+                    .get_one::<String>("png-filter")
+                    .map(|f| match f.as_str() {
+                        "none" => PngFilterType::NoFilter,
+                        "sub" => PngFilterType::Sub,
+                        "up" => PngFilterType::Up,
+                        "avg" => PngFilterType::Avg,
+                        "paeth" => PngFilterType::Paeth,
+                        "adaptive" => PngFilterType::Adaptive,
+                        _ => unreachable!(),
+                    });
+            let options = EncodeOptions {
+                format,
+                quality,
+                alpha_quality: sub_matches.get_one::<u8>("alpha-quality").copied(),
+                trim: sub_matches
+                    .get_flag("trim")
+                    .then(|| *sub_matches.get_one::<u8>("trim-tolerance").unwrap()),
+                resize,
+                max_resize_pixels: *sub_matches.get_one::<u64>("max-resize-pixels").unwrap(),
+                max_dimension: sub_matches.get_one::<u32>("max-dimension").copied(),
+                posterize: sub_matches.get_one::<u16>("posterize").copied(),
+                dither: sub_matches.get_flag("dither"),
+                contrast: if sub_matches.get_flag("auto-level") {
+                    Some(ContrastStretch::PerChannel)
+                } else if sub_matches.get_flag("normalize") {
+                    Some(ContrastStretch::Global)
+                } else {
+                    None
+                },
+                png_compression,
+                png_filter,
+                bit_depth_reduce: sub_matches.get_flag("bit-depth-reduce"),
+                #[cfg(feature = "metadata")]
+                embed_thumbnail: sub_matches.get_one::<u32>("embed-thumbnail").copied(),
+                #[cfg(not(feature = "metadata"))]
+                embed_thumbnail: None,
+                auto_orient: !sub_matches.get_flag("no-auto-orient"),
+                raw_non_images: false,
+                phash: false,
+            };
+            let img = if image_arg.is_none() {
+                read_clipboard_image()?
+            } else if sub_matches.get_flag("raw-pixels") {
+                let pixel_format =
+                    PixelFormat::parse(sub_matches.get_one::<String>("pixel-format").unwrap());
+                load_raw_pixels(
+                    image_path,
+                    *sub_matches.get_one::<u32>("width").unwrap(),
+                    *sub_matches.get_one::<u32>("height").unwrap(),
+                    pixel_format,
+                )?
+            } else {
+                open_image(image_path, options.auto_orient)?
+            };
+            let max_output_bytes = sub_matches.get_one::<u64>("max-output-bytes").copied();
+
+            if sub_matches.get_flag("split-alpha") {
+                let (rgb, alpha) =
+                    encode_split_alpha(img, image_path, &options, url_safe, max_output_bytes)?;
+                write_split_alpha_output(
+                    &rgb,
+                    &alpha,
+                    sub_matches.get_flag("json"),
+                    output.map(String::as_str),
+                )?;
+                info!("Encoded RGB and alpha of {}", image_path);
+                return Ok(());
+            }
 
-            if let Some(output_path) = output {
+            let img = transform_image(img, &options)?;
+            let variants: Vec<Variant> = sub_matches
+                .get_many::<Variant>("variants")
+                .or_else(|| sub_matches.get_many::<Variant>("widths"))
+                .map(|values| values.copied().collect())
+                .unwrap_or_default();
+            if !variants.is_empty() {
+                let mut document = serde_json::Map::new();
+                let pattern = sub_matches.get_one::<String>("variant-output");
+                for (label, variant_img) in scale_variants(&img, &variants)? {
+                    let encoded = encode_transformed_image(&variant_img, image_path, &options)?;
+                    check_output_size(encoded.data.len(), max_output_bytes)?;
+                    let payload = base64_engine(url_safe).encode(&encoded.data);
+                    if let Some(pattern) = pattern {
+                        let path = variant_output_path(pattern, &label, &variant_img);
+                        std::fs::write(&path, payload)?;
+                        println!(
+                            "Variant {} ({}x{}) saved to {}",
+                            label, encoded.width, encoded.height, path
+                        );
+                        info!("Variant {} saved to {}", label, path);
+                    } else {
+                        document.insert(label, payload.into());
+                    }
+                }
+                if pattern.is_none() {
+                    let document = serde_json::Value::Object(document).to_string();
+                    if let Some(output_path) = output {
+                        std::fs::write(output_path, document)?;
+                        println!("Encoded variants saved to {}", output_path);
+                        info!("Encoded variants saved to {}", output_path);
+                    } else {
+                        print_line(&document);
+                        info!("Encoded variants output to stdout");
+                    }
+                }
+                return Ok(());
+            }
+            // Built from the transformed image so it previews what is encoded
+            let lqip = sub_matches
+                .get_one::<u32>("lqip")
+                .map(|&size| lqip_data_uri(&img, size))
+                .transpose()?;
+            let encoded_image = encode_transformed_image(&img, image_path, &options)?;
+            check_output_size(encoded_image.data.len(), max_output_bytes)?;
+            let payload = base64_engine(url_safe).encode(&encoded_image.data);
+            if options.bit_depth_reduce {
+                if let Some((depth, color)) = png_layout(&encoded_image.data) {
+                    eprintln!("PNG bit depth: {} ({})", depth, color);
+                }
+            }
+
+            let mut base64_str = if sub_matches.get_flag("svg") {
+                info!(
+                    "Wrapping {}x{} image in SVG",
+                    encoded_image.width, encoded_image.height
+                );
+                svg_wrapper(&encoded_image)
+            } else {
+                payload
+            };
+
+            if sub_matches.get_flag("with-header") {
+                let header = PayloadHeader::for_image(&encoded_image);
+                info!("Prefixing output with header: {}", header);
+                base64_str = format!("{}\n{}", header, base64_str);
+            }
+            // Line breaks of the header and the SVG wrapper follow --crlf
+            let base64_str = with_line_endings(&base64_str).into_owned();
+
+            if sub_matches.get_flag("verify-roundtrip") {
+                let layout = WrittenLayout {
+                    url_safe,
+                    svg: sub_matches.get_flag("svg"),
+                    with_header: sub_matches.get_flag("with-header"),
+                };
+                let lossy = !format_registry()
+                    .read()
+                    .unwrap()
+                    .get(encoded_image.format)
+                    .is_some_and(|entry| entry.lossless)
+                    || options.quality.is_some()
+                    || options.alpha_quality.is_some();
+                verify_roundtrip(&base64_str, layout, &encoded_image, &img, lossy)?;
+                eprintln!("Round-trip verified");
+            }
+
+            if sub_matches.get_flag("checksum") {
+                let digest = hash.digest_reader(&mut encoded_image.data.as_slice())?;
+                eprintln!("{}: {}", hash, digest);
+                info!("Encoded image {} checksum: {}", hash, digest);
+            }
+
+            if sub_matches.get_flag("phash") {
+                // Hash the image as encoded so lossy output is what gets compared
+                let phash = perceptual_hash(&image::load_from_memory(&encoded_image.data)?);
+                eprintln!("phash: {}", phash);
+                info!("Encoded image perceptual hash: {}", phash);
+            }
+
+            if let Some(lqip) = lqip {
+                let document = serde_json::json!({ "image": base64_str, "lqip": lqip });
+                if let Some(output_path) = output {
+                    std::fs::write(output_path, document.to_string())?;
+                    println!("Encoded image and placeholder saved to {}", output_path);
+                    info!("Encoded image and placeholder saved to {}", output_path);
+                } else {
+                    print_line(&document.to_string());
+                    info!("Encoded image and placeholder output to stdout");
+                }
+            } else if let Some(language) = sub_matches.get_one::<String>("as-source") {
+                let language = match language.as_str() {
+                    "c" => SourceLanguage::C,
+                    "rust" => SourceLanguage::Rust,
+                    _ => unreachable!(),
+                };
+                let var_name = sub_matches
+                    .get_one::<String>("var-name")
+                    .map_or(language.default_var_name(), String::as_str);
+                let source = source_array(&encoded_image, language, var_name);
+                let source = with_line_endings(&source);
+                if let Some(output_path) = output {
+                    std::fs::write(output_path, source.as_bytes())?;
+                    println!("Source array saved to {}", output_path);
+                    info!("Source array saved to {}", output_path);
+                } else {
+                    print!("{}", source);
+                    info!("Source array output to stdout");
+                }
+            } else if let Some(compress) = sub_matches.get_one::<String>("compress") {
+                let algorithm: Algorithm = compress.parse()?;
+                info!("Compressing Base64 output with {}", algorithm);
+                let blob = algorithm::compress_bytes(
+                    algorithm,
+                    base64_str.as_bytes(),
+                    BASE64_COMPRESSION_LEVEL,
+                )?;
+                if let Some(output_path) = output {
+                    std::fs::write(output_path, blob)?;
+                    println!("Compressed encoded image saved to {}", output_path);
+                    info!("Compressed encoded image saved to {}", output_path);
+                } else {
+                    std::io::stdout().write_all(&blob)?;
+                    info!("Compressed encoded image output to stdout");
+                }
+            } else if let Some(chunk_size) = sub_matches.get_one::<u64>("chunk") {
+                write_chunked_output(
+                    &base64_str,
+                    *chunk_size as usize,
+                    sub_matches.get_flag("json"),
+                    output.map(String::as_str),
+                )?;
+            } else if let Some(output_path) = output {
                 std::fs::write(output_path, base64_str)?;
                 println!("Encoded image saved to {}", output_path);
                 info!("Encoded image saved to {}", output_path);
             } else {
-                println!("{}", base64_str);
+                print!("{}{}", base64_str, line_ending());
                 info!("Encoded image output to stdout");
             }
         }
@@ -352,18 +5162,224 @@ fn main() -> Result<(), Box<dyn Error>> {
             let output_path = sub_matches.get_one::<String>("output").unwrap();
             let url_safe = sub_matches.get_flag("url-safe");
 
-            let base64_str = if base64_input == "-" {
-                let mut buffer = String::new();
-                std::io::stdin().read_to_string(&mut buffer)?;
-                buffer
+            let options = DecodeOptions {
+                auto_alphabet: sub_matches.get_flag("auto-alphabet"),
+                sidecar: sub_matches.get_flag("sidecar"),
+                output_format: format_arg(sub_matches),
+                expect_size: sub_matches.get_one::<(u32, u32)>("expect-size").copied(),
+                expect_format: registered_format_arg(sub_matches, "expect-format"),
+                mmap: sub_matches.get_flag("mmap"),
+                ..decode_options_arg(sub_matches)
+            };
+
+            let base64_str = if sub_matches.get_flag("parts") {
+                read_chunked_base64(base64_input)?
             } else {
-                base64_input.clone()
+                read_decode_input(base64_input, sub_matches)?
             };
 
             info!("Decoding Base64 string to image: {}", output_path);
-            decode_base64_to_image(&base64_str, output_path, url_safe)?;
+            if sub_matches.get_flag("with-header") {
+                decode_with_header(&base64_str, output_path, url_safe, &options)?;
+            } else {
+                decode_base64_to_image(&base64_str, output_path, url_safe, &options)?;
+            }
             println!("Successfully decoded image to {}", output_path);
             info!("Successfully decoded image to {}", output_path);
+            #[cfg(feature = "viewer")]
+            if sub_matches.get_flag("open") {
+                open_in_viewer(output_path);
+            }
+        }
+
+        Some(("info", sub_matches)) => {
+            let base64_input = sub_matches.get_one::<String>("base64").unwrap();
+            let base64_str = read_base64_arg(base64_input)?;
+
+            let url_safe = sub_matches.get_flag("url-safe");
+            let json = sub_matches.get_one::<String>("output-format").unwrap() == "json";
+
+            let verbose = sub_matches.get_flag("verbose");
+            let phash = sub_matches.get_flag("phash");
+            let colors = sub_matches.get_flag("colors");
+
+            let inspect = || -> Result<InfoReport, Box<dyn Error>> {
+                if !(json || verbose || phash || colors) {
+                    // Plain dimensions never need more than the header
+                    let (width, height) = base64_image_dimensions(&base64_str, url_safe)?;
+                    return Ok(InfoReport::dimensions_only(width, height));
+                }
+                let data = base64_engine(url_safe).decode(base64_str.trim())?;
+                let mut report = InfoReport::from_header(&data)?;
+                if phash || colors {
+                    let img = image::load_from_memory(&data)?;
+                    if phash {
+                        let phash = perceptual_hash(&img);
+                        info!("Image perceptual hash: {}", phash);
+                        report.phash = Some(phash.to_string());
+                    }
+                    if colors {
+                        let (count, capped) = count_unique_colors(&img, UNIQUE_COLOR_LIMIT);
+                        info!("Image unique colors: {} (capped: {})", count, capped);
+                        report.unique_colors = Some(count);
+                        report.unique_colors_capped = Some(capped);
+                    }
+                }
+                Ok(report)
+            };
+            let report = match inspect() {
+                Ok(report) => report,
+                Err(e) if json => {
+                    // Still print a result that pipelines can parse
+                    let invalid = serde_json::json!({ "valid": false, "error": e.to_string() });
+                    println!("{}", invalid);
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+            info!("Image dimensions: {}x{}", report.width, report.height);
+
+            if json {
+                println!("{}", serde_json::to_string(&report)?);
+            } else {
+                report.print_text(verbose);
+            }
+        }
+
+        Some(("bench-encode", sub_matches)) => {
+            let image_path = sub_matches.get_one::<String>("image").unwrap();
+            let iterations = *sub_matches.get_one::<u32>("iterations").unwrap();
+
+            let img = open_image(image_path, true)?;
+            info!(
+                "Benchmarking {}x{} image {} over {} iterations",
+                img.width(),
+                img.height(),
+                image_path,
+                iterations
+            );
+            let (results, failed) = bench_encode(&img, iterations);
+            let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+
+            if sub_matches.get_flag("json") {
+                let ranked: Vec<_> = results
+                    .iter()
+                    .enumerate()
+                    .map(|(rank, result)| {
+                        serde_json::json!({
+                            "rank": rank + 1,
+                            "format": result.format,
+                            "bytes": result.bytes,
+                            "mean_ms": millis(result.mean),
+                            "min_ms": millis(result.min),
+                        })
+                    })
+                    .collect();
+                let failed: Vec<_> = failed
+                    .iter()
+                    .map(|(format, error)| serde_json::json!({ "format": format, "error": error }))
+                    .collect();
+                let document = serde_json::json!({
+                    "image": image_path,
+                    "width": img.width(),
+                    "height": img.height(),
+                    "iterations": iterations,
+                    "results": ranked,
+                    "failed": failed,
+                });
+                println!("{}", serde_json::to_string_pretty(&document)?);
+            } else {
+                println!(
+                    "{}x{} {} over {} iterations",
+                    img.width(),
+                    img.height(),
+                    image_path,
+                    iterations
+                );
+                println!(
+                    "{:<5} {:<8} {:>14} {:>12} {:>12}",
+                    "Rank", "Format", "Size", "Mean", "Min"
+                );
+                for (rank, result) in results.iter().enumerate() {
+                    println!(
+                        "{:<5} {:<8} {:>14} {:>9.2} ms {:>9.2} ms",
+                        rank + 1,
+                        result.format,
+                        format_size(result.bytes as u64),
+                        millis(result.mean),
+                        millis(result.min),
+                    );
+                }
+                for (format, error) in &failed {
+                    println!("-     {:<8} failed: {}", format, error);
+                }
+            }
+        }
+
+        Some(("histogram", sub_matches)) => {
+            let image_path = sub_matches.get_one::<String>("image").unwrap();
+
+            let img = open_image(image_path, true)?;
+            let histograms = channel_histograms(&img, sub_matches.get_flag("gray"));
+            info!(
+                "Computed {} histograms of {}x{} image {}",
+                histograms.len(),
+                img.width(),
+                img.height(),
+                image_path
+            );
+
+            if let Some(render_path) = sub_matches.get_one::<String>("render") {
+                render_histograms(&histograms).save(render_path)?;
+                println!("Histogram rendered to {}", render_path);
+                info!("Histogram rendered to {}", render_path);
+            } else {
+                let channels: serde_json::Map<_, _> = histograms
+                    .into_iter()
+                    .map(|(name, bins)| (name.to_string(), bins.into()))
+                    .collect();
+                let document = serde_json::json!({
+                    "image": image_path,
+                    "width": img.width(),
+                    "height": img.height(),
+                    "bins": HISTOGRAM_BINS,
+                    "histograms": channels,
+                });
+                println!("{}", document);
+            }
+        }
+
+        Some(("ssim", sub_matches)) => {
+            let first = sub_matches.get_one::<String>("first").unwrap();
+            let second = sub_matches.get_one::<String>("second").unwrap();
+
+            let score = ssim(&open_image(first, true)?, &open_image(second, true)?);
+            println!("SSIM: {:.6}", score);
+            info!("SSIM of {} and {}: {}", first, second, score);
+
+            if let Some(threshold) = sub_matches.get_one::<f64>("threshold").copied() {
+                if score < threshold {
+                    error!("SSIM is below the threshold");
+                    return Err(
+                        format!("SSIM {:.6} is below the threshold {}", score, threshold).into(),
+                    );
+                }
+            }
+        }
+
+        Some(("pixel", sub_matches)) => {
+            let base64_input = sub_matches.get_one::<String>("base64").unwrap();
+            let base64_str = read_base64_or_file(base64_input)?;
+            let x = *sub_matches.get_one::<u32>("x").unwrap();
+            let y = *sub_matches.get_one::<u32>("y").unwrap();
+
+            let image::Rgba([r, g, b, a]) =
+                sample_pixel(&base64_str, x, y, sub_matches.get_flag("url-safe"))?;
+            println!(
+                "Pixel ({}, {}): rgba({}, {}, {}, {}) #{:02x}{:02x}{:02x}{:02x}",
+                x, y, r, g, b, a, r, g, b, a
+            );
+            info!("Sampled pixel ({}, {})", x, y);
         }
 
         Some(("decode-from-file", sub_matches)) => {
@@ -372,38 +5388,102 @@ fn main() -> Result<(), Box<dyn Error>> {
             let url_safe = sub_matches.get_flag("url-safe");
 
             info!("Decoding Base64 from file: {}", input_file);
-            let output_path = decode_from_file(input_file, output_dir, url_safe)?;
+            let output_path = decode_from_file(
+                input_file,
+                output_dir,
+                url_safe,
+                &decode_options_arg(sub_matches),
+            )?;
             println!("Decoded image saved to {}", output_path.to_str().unwrap());
             info!("Decoded image saved to {}", output_path.to_str().unwrap());
         }
 
         Some(("batch-encode", sub_matches)) => {
-            let images = sub_matches.get_many::<String>("images").unwrap();
-            let output = sub_matches.get_one::<String>("output");
-            let format = sub_matches
-                .get_one::<String>("format")
-                .map(|f| match f.as_str() {
-                    "jpeg" => ImageFormat::Jpeg,
-                    "tif" | "tiff" => ImageFormat::Tiff,
-                    "png" => ImageFormat::Png,
-                    "webp" => ImageFormat::WebP,
-                    "bmp" => ImageFormat::Bmp,
+            let mut images: Vec<String> = sub_matches
+                .get_many::<String>("images")
+                .map(|vals| vals.cloned().collect())
+                .unwrap_or_default();
+            let raw_non_images = sub_matches.get_flag("raw-non-images");
+            // Listed images keep their order; only the directory walk, whose
+            // order depends on the filesystem, is sorted
+            if let Some(dir) = sub_matches.get_one::<String>("dir") {
+                let mut found = collect_dir_images(dir, raw_non_images);
+                let sort = match sub_matches.get_one::<String>("sort").unwrap().as_str() {
+                    "name" => SortKey::Name,
+                    "size" => SortKey::Size,
+                    "mtime" => SortKey::Mtime,
                     _ => unreachable!(),
-                });
+                };
+                sort_image_paths(&mut found, sort, sub_matches.get_flag("reverse"));
+                images.extend(found);
+            }
+            let output = sub_matches.get_one::<String>("output");
+            let format = format_arg(sub_matches);
             let quality = sub_matches.get_one::<u8>("quality").copied();
             let resize = sub_matches
                 .get_many::<u32>("resize")
                 .map(|mut vals| (*vals.next().unwrap(), *vals.next().unwrap()));
             let url_safe = sub_matches.get_flag("url-safe");
 
-            info!("Batch encoding images");
-            encode_multiple_images(
-                images.cloned().collect(),
+            let options = EncodeOptions {
                 format,
                 quality,
                 resize,
+                max_resize_pixels: *sub_matches.get_one::<u64>("max-resize-pixels").unwrap(),
+                auto_orient: !sub_matches.get_flag("no-auto-orient"),
+                raw_non_images,
+                phash: sub_matches.get_flag("phash"),
+                ..Default::default()
+            };
+            let flatten = sub_matches.get_flag("flatten").then(|| {
+                match sub_matches
+                    .get_one::<String>("on-collision")
+                    .unwrap()
+                    .as_str()
+                {
+                    "error" => CollisionPolicy::Error,
+                    "rename" => CollisionPolicy::Rename,
+                    "overwrite" => CollisionPolicy::Overwrite,
+                    _ => unreachable!(),
+                }
+            });
+            let batch_output = match (sub_matches.get_one::<String>("output-dir"), output) {
+                (Some(dir), _) => BatchOutput::Dir {
+                    dir: Path::new(dir),
+                    flatten,
+                },
+                (None, output_path) if sub_matches.get_flag("json") => {
+                    BatchOutput::Json(output_path.map(String::as_str))
+                }
+                (None, Some(output_path)) => BatchOutput::File(output_path),
+                (None, None) => BatchOutput::Stdout,
+            };
+
+            units::set_raw_bytes(sub_matches.get_flag("bytes"));
+            CRLF.store(sub_matches.get_flag("crlf"), Ordering::Relaxed);
+            let state = batch_state_arg(sub_matches)?;
+            // A process can only install one handler, so it is done here
+            // rather than for each batch
+            let cancel = Arc::new(AtomicBool::new(false));
+            let handler_flag = Arc::clone(&cancel);
+            ctrlc::set_handler(move || handler_flag.store(true, Ordering::Relaxed))?;
+            info!("Batch encoding images");
+            encode_multiple_images(
+                images,
+                &options,
                 url_safe,
-                output.map(String::as_str),
+                per_file_timeout(sub_matches),
+                WorkerPool {
+                    jobs: *sub_matches.get_one::<u64>("jobs").unwrap() as usize,
+                    stack_size: sub_matches
+                        .get_one::<u64>("stack-size")
+                        .map(|&size| size as usize),
+                },
+                batch_output,
+                sub_matches.get_flag("summary"),
+                state.as_ref(),
+                sub_matches.get_flag("dedup"),
+                &cancel,
             )?;
         }
 
@@ -413,13 +5493,44 @@ fn main() -> Result<(), Box<dyn Error>> {
             let url_safe = sub_matches.get_flag("url-safe");
 
             info!("Batch decoding Base64 strings from file: {}", input_file);
-            let content = read_to_string(input_file)?;
-            for (i, line) in content.lines().enumerate() {
-                let output_path = format!("{}/image_{}.png", output_dir, i);
-                decode_base64_to_image(line, &output_path, url_safe)?;
-                println!("Decoded image {}", output_path);
-                info!("Decoded image {}", output_path);
+            let mut content = read_to_string(input_file)?;
+            if sub_matches.get_flag("single") {
+                info!("Joining all lines into a single Base64 image");
+                content = content.lines().map(str::trim).collect();
             }
+            decode_multiple_images(
+                &content,
+                output_dir,
+                url_safe,
+                per_file_timeout(sub_matches),
+                DecodeOptions {
+                    sidecar: sub_matches.get_flag("sidecar"),
+                    ..Default::default()
+                },
+                batch_state_arg(sub_matches)?.as_ref(),
+            )?;
+        }
+
+        #[cfg(feature = "watch")]
+        Some(("watch", sub_matches)) => {
+            let input = sub_matches.get_one::<String>("input").unwrap();
+            let output_dir = sub_matches.get_one::<String>("output-dir").unwrap();
+            let format = format_arg(sub_matches);
+            let options = EncodeOptions {
+                format,
+                ..Default::default()
+            };
+            let debounce =
+                Duration::from_millis(*sub_matches.get_one::<u64>("debounce-ms").unwrap());
+
+            info!("Watching {} for changes", input);
+            watch_and_encode(
+                input,
+                output_dir,
+                &options,
+                sub_matches.get_flag("url-safe"),
+                debounce,
+            )?;
         }
 
         _ => unreachable!(),
@@ -428,3 +5539,260 @@ fn main() -> Result<(), Box<dyn Error>> {
     info!("image-base64 utility finished");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::FormatRegistry;
+    use image::GrayImage;
+
+    /// Writes a 3x2 grayscale PNG whose pixels are 1 to 6 in reading order,
+    /// tagged with the given EXIF orientation, and returns its path.
+    fn oriented_png(orientation: u16) -> String {
+        let img = GrayImage::from_raw(3, 2, vec![1, 2, 3, 4, 5, 6]).unwrap();
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        // Big-endian TIFF header and a single IFD entry: Orientation, SHORT
+        let mut exif =
+            b"MM\x00\x2a\x00\x00\x00\x08\x00\x01\x01\x12\x00\x03\x00\x00\x00\x01".to_vec();
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0; 6]);
+        let mut chunk = (exif.len() as u32).to_be_bytes().to_vec();
+        let mut body = b"eXIf".to_vec();
+        body.extend_from_slice(&exif);
+        chunk.extend_from_slice(&body);
+        chunk.extend_from_slice(&crc32fast::hash(&body).to_be_bytes());
+        // After the signature and the IHDR chunk
+        png.splice(33..33, chunk);
+
+        let path = std::env::temp_dir().join(format!(
+            "image-base64-orientation-{}-{}.png",
+            orientation,
+            std::process::id()
+        ));
+        std::fs::write(&path, png).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Opens `path` and returns its rows of gray values.
+    fn open_rows(path: &str, auto_orient: bool) -> Vec<Vec<u8>> {
+        let img = open_image(path, auto_orient).unwrap().to_luma8();
+        img.rows()
+            .map(|row| row.map(|pixel| pixel.0[0]).collect())
+            .collect()
+    }
+
+    #[test]
+    fn every_exif_orientation_is_applied() {
+        let expected: [&[&[u8]]; 8] = [
+            &[&[1, 2, 3], &[4, 5, 6]],
+            &[&[3, 2, 1], &[6, 5, 4]],
+            &[&[6, 5, 4], &[3, 2, 1]],
+            &[&[4, 5, 6], &[1, 2, 3]],
+            &[&[1, 4], &[2, 5], &[3, 6]],
+            &[&[4, 1], &[5, 2], &[6, 3]],
+            &[&[6, 3], &[5, 2], &[4, 1]],
+            &[&[3, 6], &[2, 5], &[1, 4]],
+        ];
+        for (orientation, rows) in (1..=8).zip(expected) {
+            let path = oriented_png(orientation);
+            assert_eq!(open_rows(&path, true), rows, "orientation {}", orientation);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn no_auto_orient_keeps_stored_pixels() {
+        let path = oriented_png(6);
+        assert_eq!(open_rows(&path, false), [[1, 2, 3], [4, 5, 6]]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn quality_must_be_between_1_and_100() {
+        let registry = FormatRegistry::with_builtin_formats();
+        let jpeg = registry.get("jpeg").unwrap();
+        for quality in [1, 100] {
+            assert!(validate_quality(Some(quality), jpeg).is_ok());
+        }
+        for quality in [0, 101] {
+            assert!(matches!(
+                validate_quality(Some(quality), jpeg),
+                Err(EncodeError::QualityOutOfRange(q)) if q == quality
+            ));
+        }
+    }
+
+    #[test]
+    fn quality_is_rejected_for_formats_without_it() {
+        let registry = FormatRegistry::with_builtin_formats();
+        let png = registry.get("png").unwrap();
+        assert!(validate_quality(None, png).is_ok());
+        assert!(matches!(
+            validate_quality(Some(80), png),
+            Err(EncodeError::QualityNotAllowed("png"))
+        ));
+    }
+
+    #[test]
+    fn resize_rejects_zero_dimensions() {
+        for (width, height) in [(0, 10), (10, 0), (0, 0)] {
+            assert!(matches!(
+                validate_resize(width, height, DEFAULT_MAX_RESIZE_PIXELS),
+                Err(EncodeError::ZeroResize(w, h)) if (w, h) == (width, height)
+            ));
+        }
+    }
+
+    #[test]
+    fn resize_rejects_more_than_max_pixels() {
+        assert!(validate_resize(100, 100, 10_000).is_ok());
+        assert!(matches!(
+            validate_resize(101, 100, 10_000),
+            Err(EncodeError::ResizeTooLarge {
+                max_pixels: 10_000,
+                ..
+            })
+        ));
+        // The product is taken in u64, so it cannot wrap around to pass
+        assert!(matches!(
+            validate_resize(u32::MAX, u32::MAX, DEFAULT_MAX_RESIZE_PIXELS),
+            Err(EncodeError::ResizeTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn verify_roundtrip_checks_written_text_against_source() {
+        let source =
+            DynamicImage::ImageLuma8(GrayImage::from_raw(3, 2, vec![1, 2, 3, 4, 5, 6]).unwrap());
+        let mut data = Vec::new();
+        source
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        let encoded = EncodedImage {
+            data,
+            format: "png",
+            width: 3,
+            height: 2,
+        };
+        let layout = WrittenLayout {
+            url_safe: false,
+            svg: false,
+            with_header: true,
+        };
+        let payload = general_purpose::STANDARD.encode(&encoded.data);
+        let written = format!("{}\r\n{}", PayloadHeader::for_image(&encoded), payload);
+        assert!(verify_roundtrip(&written, layout, &encoded, &source, false).is_ok());
+
+        let wrong_header = format!("3x3 png {}\n{}", encoded.data.len(), payload);
+        assert!(verify_roundtrip(&wrong_header, layout, &encoded, &source, false).is_err());
+
+        let changed =
+            DynamicImage::ImageLuma8(GrayImage::from_raw(3, 2, vec![1, 2, 3, 4, 5, 7]).unwrap());
+        assert!(verify_roundtrip(&written, layout, &encoded, &changed, false).is_err());
+        assert!(verify_roundtrip(&written, layout, &encoded, &changed, true).is_ok());
+    }
+
+    #[test]
+    fn alpha_quality_error_names_the_feature_only_for_webp() {
+        assert_eq!(
+            EncodeError::AlphaQualityNotAllowed("png").to_string(),
+            "Alpha quality is only supported for WebP format, not png"
+        );
+        assert!(EncodeError::AlphaQualityNotAllowed("webp")
+            .to_string()
+            .contains("lossy-webp"));
+    }
+
+    #[test]
+    fn base64_image_dimensions_reads_only_the_header() {
+        let img = GrayImage::new(3, 2);
+        let mut bmp = Vec::new();
+        img.write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+            .unwrap();
+        // Claim gigabytes of pixels that are not there; only the header is read
+        bmp[18..22].copy_from_slice(&20_000i32.to_le_bytes());
+        bmp[22..26].copy_from_slice(&30_000i32.to_le_bytes());
+
+        for url_safe in [false, true] {
+            let text = format!("{}\n", base64_engine(url_safe).encode(&bmp));
+            assert_eq!(
+                base64_image_dimensions(&text, url_safe).unwrap(),
+                (20_000, 30_000)
+            );
+        }
+        assert!(base64_image_dimensions("not base64!", false).is_err());
+    }
+
+    #[test]
+    fn svg_wrapper_is_well_formed_xml() {
+        // "raw" is not registered, so its MIME type falls back to octet-stream
+        for (format, mime) in [
+            ("png", "image/png"),
+            ("ppm", "image/x-portable-anymap"),
+            ("raw", "application/octet-stream"),
+        ] {
+            let image = EncodedImage {
+                data: vec![0xfb, 0xff, 0xbf, 0x00],
+                format,
+                width: 640,
+                height: 480,
+            };
+            let svg = svg_wrapper(&image);
+            let doc = roxmltree::Document::parse(&svg).unwrap();
+            let root = doc.root_element();
+            assert_eq!(root.tag_name().name(), "svg");
+            assert_eq!(root.attribute("width"), Some("640"));
+            assert_eq!(root.attribute("height"), Some("480"));
+            let img = root.first_element_child().unwrap();
+            assert_eq!(img.tag_name().name(), "image");
+            assert_eq!(img.attribute("width"), Some("640"));
+            assert_eq!(img.attribute("height"), Some("480"));
+            assert_eq!(
+                img.attribute("href"),
+                Some(format!("data:{};base64,+/+/AA==", mime).as_str())
+            );
+        }
+
+        let value = r#"a&b<c>d"e'f"#;
+        let xml = format!("<x v=\"{}\"/>", escape_xml_attr(value));
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        assert_eq!(doc.root_element().attribute("v"), Some(value));
+    }
+
+    #[test]
+    fn mapped_writer_grows_and_seeks() {
+        let path = std::env::temp_dir().join(format!("image-base64-mmap-{}", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        file.set_len(4).unwrap();
+        let mut writer = MappedWriter::new(file).map_err(|(_, e)| e).unwrap();
+        writer.write_all(b"0123456789").unwrap();
+        writer.seek(SeekFrom::Start(2)).unwrap();
+        writer.write_all(b"ab").unwrap();
+        writer.seek(SeekFrom::End(-1)).unwrap();
+        writer.write_all(b"z").unwrap();
+        assert!(writer.seek(SeekFrom::Current(-100)).is_err());
+        assert_eq!(writer.finish().unwrap(), 10);
+        assert_eq!(std::fs::read(&path).unwrap(), b"01ab45678z");
+
+        // TIFF goes back to patch offsets it wrote earlier
+        let img = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |x, y| {
+            image::Luma([(x * y) as u8])
+        }));
+        let mut expected = Vec::new();
+        img.write_to(&mut Cursor::new(&mut expected), ImageFormat::Tiff)
+            .unwrap();
+        let written = write_mapped(path.to_str().unwrap(), &img, ImageFormat::Tiff).unwrap();
+        assert_eq!(written, expected.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,39 @@
+/// Enters a span for one phase of the image pipeline (decode, resize,
+/// encode, write). The span closes when the returned guard is dropped.
+#[cfg(feature = "tracing")]
+macro_rules! phase_span {
+    ($name:literal $(, $($fields:tt)*)?) => {
+        tracing::info_span!($name $(, $($fields)*)?).entered()
+    };
+}
+
+/// Without the `tracing` feature, phases are not instrumented.
+#[cfg(not(feature = "tracing"))]
+macro_rules! phase_span {
+    ($($args:tt)*) => {
+        $crate::profiling::NoSpan
+    };
+}
+
+/// Guard returned by `phase_span!` when the `tracing` feature is disabled.
+#[cfg(not(feature = "tracing"))]
+pub struct NoSpan;
+
+/// Keeps the trace file open; the timeline is flushed when this is dropped.
+#[cfg(feature = "tracing")]
+pub type TraceGuard = tracing_chrome::FlushGuard;
+
+/// Installs a subscriber that records all spans to `trace_path` in the
+/// Chrome trace event format, loadable in `chrome://tracing` or Perfetto.
+#[cfg(feature = "tracing")]
+pub fn init_chrome_trace(trace_path: &str) -> Result<TraceGuard, Box<dyn std::error::Error>> {
+    use tracing_subscriber::prelude::*;
+
+    let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new()
+        .file(trace_path)
+        .include_args(true)
+        .build();
+    tracing_subscriber::registry().with(layer).try_init()?;
+    log::info!("Writing Chrome trace to {}", trace_path);
+    Ok(guard)
+}
@@ -0,0 +1,26 @@
+// Shared by the rcomp and image-base64 binaries.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by `--bytes`; sizes are printed as plain byte counts for scripts.
+static RAW_BYTES: AtomicBool = AtomicBool::new(false);
+
+/// Switches every later [`format_size`] call to plain byte counts.
+pub fn set_raw_bytes(raw: bool) {
+    RAW_BYTES.store(raw, Ordering::Relaxed);
+}
+
+/// Returns whether sizes are printed as plain byte counts.
+pub fn raw_bytes() -> bool {
+    RAW_BYTES.load(Ordering::Relaxed)
+}
+
+/// Formats a byte count with binary units (`1.50 MiB`), or as `1572864 bytes`
+/// when `--bytes` was given.
+pub fn format_size(bytes: u64) -> String {
+    if raw_bytes() {
+        format!("{} bytes", bytes)
+    } else {
+        humansize::format_size(bytes, humansize::BINARY)
+    }
+}